@@ -0,0 +1,63 @@
+use std::net::TcpListener;
+
+use shroom_trace::{
+    mock::{CodecFactory, CryptoCodec, Handshake, MockServer, PlainCodec, Script},
+    stream::read_stream_file,
+};
+
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("[{}] {}", record.level(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+fn main() -> anyhow::Result<()> {
+    let (flags, args): (Vec<String>, Vec<String>) =
+        std::env::args().partition(|arg| arg.starts_with("--"));
+    let (session, addr, version) = match &args[..] {
+        [_, session] => (session, "127.0.0.1:8484", None),
+        [_, session, addr] => (session, addr.as_str(), None),
+        [_, session, addr, version] => (session, addr.as_str(), Some(version.parse()?)),
+        _ => anyhow::bail!(
+            "Usage: mock_server <session.jsonl> [addr] [version] [--plain|--no-shanda]"
+        ),
+    };
+    let codec: CodecFactory = match flags.first().map(String::as_str) {
+        None => |hs| Box::new(CryptoCodec::server(hs, true)),
+        // For clients with `disable_shanda`
+        Some("--no-shanda") => |hs| Box::new(CryptoCodec::server(hs, false)),
+        // For the WebSocket gateway or clients without the packet encryption
+        Some("--plain") => |_| Box::new(PlainCodec),
+        Some(flag) => anyhow::bail!("Unknown flag: {flag}"),
+    };
+
+    log::set_logger(&StderrLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Info);
+
+    let script = Script::from_records(read_stream_file(session)?);
+    eprintln!(
+        "Replaying {} steps, {} greeting packets",
+        script.steps.len(),
+        script.greeting.len()
+    );
+
+    let mut handshake = Handshake::default();
+    if let Some(version) = version {
+        handshake.version = version;
+    }
+
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    MockServer::new(script)
+        .with_handshake(handshake)
+        .with_codec(codec)
+        .run(&listener)
+}
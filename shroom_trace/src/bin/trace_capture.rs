@@ -0,0 +1,39 @@
+use shroom_trace::{capture::join_records, packet_struct::read_trace_file, PacketDir};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, dir, send, recv] = &args[..] else {
+        anyhow::bail!("Usage: trace_capture <capture_dir> <send_trace.txt> <recv_trace.txt>");
+    };
+
+    let send = read_trace_file(send)?;
+    let recv = read_trace_file(recv)?;
+    let frames = join_records(dir, &send, &recv)?;
+    for frame in &frames {
+        let record = frame.record.map(|i| match frame.dir {
+            PacketDir::Send => &send[i],
+            PacketDir::Recv => &recv[i],
+        });
+        let desc = match record {
+            Some(record) => format!(
+                "record seq {} opcode {:#06x}",
+                record.seq.unwrap_or_default(),
+                record.opcode().unwrap_or_default()
+            ),
+            None => "no record".to_string(),
+        };
+        println!(
+            "{:>8} conn{} {:?} {}..{}: {desc}",
+            frame.seq, frame.conn, frame.dir, frame.range.start, frame.range.end
+        );
+    }
+
+    let matched = frames.iter().filter(|frame| frame.record.is_some()).count();
+    println!(
+        "{} frames, {} without a record, {} records without a frame",
+        frames.len(),
+        frames.len() - matched,
+        send.len() + recv.len() - matched
+    );
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use shroom_trace::{
+    codegen::gen_rust, merge::merge_records, packet_struct::read_trace_file, symbols::SymbolMap,
+    PacketDir,
+};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let (trace, dir, syms) = match &args[..] {
+        [_, trace, dir] => (trace, dir, SymbolMap::new()),
+        [_, trace, dir, syms] => (trace, dir, SymbolMap::load(syms)?),
+        _ => anyhow::bail!("Usage: trace_codegen <trace.txt> <Send|Recv> [symbols.txt]"),
+    };
+    let dir = match dir.as_str() {
+        "Send" => PacketDir::Send,
+        "Recv" => PacketDir::Recv,
+        _ => anyhow::bail!("Invalid direction: {dir}"),
+    };
+
+    let records = read_trace_file(trace)?;
+    print!("{}", gen_rust(&merge_records(dir, &records), &syms));
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use shroom_trace::{
+    coverage::{Coverage, KnownOpcodes},
+    packet_struct::read_trace_file,
+};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, opcodes, files @ ..] = &args[..] else {
+        anyhow::bail!("Usage: trace_coverage <opcodes.txt> <coverage.json|recv_trace.txt>...");
+    };
+    if files.is_empty() {
+        anyhow::bail!("No coverage or trace files");
+    }
+
+    // Coverage files of the sessions and receive traces are merged
+    let mut coverage = Coverage::new();
+    for file in files {
+        if file.ends_with(".json") {
+            coverage.merge(Coverage::load(file)?);
+        } else {
+            for record in read_trace_file(file)? {
+                coverage.record(&record.strct);
+            }
+        }
+    }
+
+    print!("{}", coverage.report(&KnownOpcodes::load(opcodes)?));
+    Ok(())
+}
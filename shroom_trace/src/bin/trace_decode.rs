@@ -0,0 +1,38 @@
+use anyhow::Context;
+use shroom_trace::{packet_struct::read_trace_file, schema::SchemaFile, PacketDir};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, schema, trace, dir] = &args[..] else {
+        anyhow::bail!("Usage: trace_decode <schema.toml> <trace.txt> <Send|Recv>");
+    };
+    let dir = match dir.as_str() {
+        "Send" => PacketDir::Send,
+        "Recv" => PacketDir::Recv,
+        _ => anyhow::bail!("Invalid direction: {dir}"),
+    };
+
+    let schema = SchemaFile::load(schema)?;
+    let records = read_trace_file(trace)?;
+    for (i, record) in records.iter().enumerate() {
+        let Some(opcode) = record.opcode() else {
+            println!("#{i}: <no opcode>");
+            continue;
+        };
+
+        if schema.get(dir, opcode).is_none() {
+            println!("#{i}: {dir:?} {opcode:#x} <no schema>");
+            continue;
+        }
+
+        let (pkt, mismatches) = schema
+            .decode_record(dir, record)
+            .with_context(|| format!("Decoding record {i}"))?;
+        print!("#{i}: {pkt}");
+        for mismatch in mismatches {
+            println!("  ! {mismatch}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use shroom_trace::{
+    crypto::{PacketCipher, PacketDecoder},
+    mock::Handshake,
+};
+
+/// Decrypts the raw stream of one direction and prints every packet
+fn print_packets(label: &str, mut decoder: PacketDecoder, data: &[u8]) -> anyhow::Result<()> {
+    decoder.push(data);
+    while let Some(pkt) = decoder.next_packet()? {
+        let opcode = pkt
+            .get(..2)
+            .map(|op| u16::from_le_bytes([op[0], op[1]]))
+            .unwrap_or_default();
+        let hex = pkt
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!("{label} {opcode:#06x} ({} bytes): {hex}", pkt.len());
+    }
+
+    if decoder.pending() > 0 {
+        eprintln!(
+            "{label}: {} bytes of an incomplete packet",
+            decoder.pending()
+        );
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let (flags, args): (Vec<String>, Vec<String>) =
+        std::env::args().partition(|arg| arg.starts_with("--"));
+    let (server, client) = match &args[..] {
+        [_, server] => (server, None),
+        [_, server, client] => (server, Some(client)),
+        _ => anyhow::bail!(
+            "Usage: trace_decrypt <server_stream.bin> [client_stream.bin] [--no-shanda]"
+        ),
+    };
+    let shanda = !flags.iter().any(|flag| flag == "--no-shanda");
+
+    // The server stream starts with the unencrypted handshake
+    let server = std::fs::read(server)?;
+    let mut server_data = server.as_slice();
+    let hs = Handshake::read(&mut server_data)?;
+    println!(
+        "Handshake: version {} patch {} locale {}",
+        hs.version, hs.patch, hs.locale
+    );
+
+    let recv = PacketDecoder::new(PacketCipher::server(hs.server_iv, hs.version, shanda));
+    print_packets("Recv", recv, server_data)?;
+    if let Some(client) = client {
+        let send = PacketDecoder::new(PacketCipher::client(hs.client_iv, hs.version, shanda));
+        print_packets("Send", send, &std::fs::read(client)?)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use shroom_trace::{
+    diff::{diff_versions, VersionStructs},
+    merge::merge_records,
+    packet_struct::read_trace_file,
+    symbols::SymbolMap,
+    PacketDir,
+};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let (old, new, dir, old_syms, new_syms) = match &args[..] {
+        [_, old, new, dir] => (old, new, dir, SymbolMap::new(), SymbolMap::new()),
+        [_, old, new, dir, old_syms, new_syms] => (
+            old,
+            new,
+            dir,
+            SymbolMap::load(old_syms)?,
+            SymbolMap::load(new_syms)?,
+        ),
+        _ => anyhow::bail!(
+            "Usage: trace_diff <old.txt> <new.txt> <Send|Recv> [old_symbols.txt new_symbols.txt]"
+        ),
+    };
+    let dir = match dir.as_str() {
+        "Send" => PacketDir::Send,
+        "Recv" => PacketDir::Recv,
+        _ => anyhow::bail!("Invalid direction: {dir}"),
+    };
+
+    let old = merge_records(dir, &read_trace_file(old)?);
+    let new = merge_records(dir, &read_trace_file(new)?);
+    let diffs = diff_versions(
+        VersionStructs {
+            structs: &old,
+            syms: &old_syms,
+        },
+        VersionStructs {
+            structs: &new,
+            syms: &new_syms,
+        },
+    );
+
+    for diff in diffs.iter().filter(|diff| !diff.is_unchanged()) {
+        print!("{diff}");
+    }
+    let unchanged = diffs.iter().filter(|diff| diff.is_unchanged()).count();
+    println!("{unchanged} opcodes unchanged");
+    Ok(())
+}
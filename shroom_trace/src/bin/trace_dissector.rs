@@ -0,0 +1,34 @@
+use shroom_trace::{
+    dissector::{gen_lua_dissector, schema_from_merged},
+    merge::merge_records,
+    packet_struct::read_trace_file,
+    schema::SchemaFile,
+    symbols::SymbolMap,
+    PacketDir,
+};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let schema = match &args[..] {
+        [_, schema] => SchemaFile::load(schema)?,
+        [_, trace, dir, rest @ ..] if rest.len() <= 1 => {
+            let syms = match rest {
+                [syms] => SymbolMap::load(syms)?,
+                _ => SymbolMap::new(),
+            };
+            let dir = match dir.as_str() {
+                "Send" => PacketDir::Send,
+                "Recv" => PacketDir::Recv,
+                _ => anyhow::bail!("Invalid direction: {dir}"),
+            };
+            let records = read_trace_file(trace)?;
+            schema_from_merged(&merge_records(dir, &records), &syms)
+        }
+        _ => anyhow::bail!(
+            "Usage: trace_dissector <schema.toml> | trace_dissector <trace.txt> <Send|Recv> [symbols.txt]"
+        ),
+    };
+
+    print!("{}", gen_lua_dissector(&schema));
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use shroom_trace::{
+    handlers::HandlerMap, packet_struct::read_trace_file, symbols::SymbolMap, PacketDir,
+};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let (trace, dir, syms) = match &args[..] {
+        [_, trace, dir] => (trace, dir, SymbolMap::new()),
+        [_, trace, dir, syms] => (trace, dir, SymbolMap::load(syms)?),
+        _ => anyhow::bail!("Usage: trace_handlers <trace.txt> <Send|Recv> [symbols.txt]"),
+    };
+    let dir = match dir.as_str() {
+        "Send" => PacketDir::Send,
+        "Recv" => PacketDir::Recv,
+        _ => anyhow::bail!("Invalid direction: {dir}"),
+    };
+
+    let mut map = HandlerMap::new();
+    for record in read_trace_file(trace)? {
+        map.record(dir, &record.strct);
+    }
+    map.symbolize(|addr| syms.lookup(addr).map(str::to_string));
+    print!("{}", map.to_csv());
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use std::{fs::File, io::BufWriter};
+
+use shroom_trace::{
+    packet_struct::read_trace_file,
+    pcap::{ordered_packets, PcapWriter},
+};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, out, send, recv] = &args[..] else {
+        anyhow::bail!("Usage: trace_pcap <out.pcap> <send_trace.txt> <recv_trace.txt>");
+    };
+
+    let send = read_trace_file(send)?;
+    let recv = read_trace_file(recv)?;
+    let packets = ordered_packets(&send, &recv);
+    let skipped = send.len() + recv.len() - packets.len();
+    if skipped > 0 {
+        eprintln!("Skipped {skipped} records without data, enable `log_data` to include them");
+    }
+
+    // Traces have no timestamps, so the frames are one millisecond apart
+    let mut w = PcapWriter::new(BufWriter::new(File::create(out)?))?;
+    for (i, (dir, data)) in packets.iter().enumerate() {
+        w.write_packet(i as u64 * 1000, *dir, data)?;
+    }
+    w.into_inner().into_inner()?;
+    println!("Wrote {} packets to {out}", packets.len());
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use shroom_trace::{packet_struct::read_trace_file, symbols::SymbolMap, tree::render_tree};
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let (trace, syms) = match &args[..] {
+        [_, trace] => (trace, SymbolMap::new()),
+        [_, trace, syms] => (trace, SymbolMap::load(syms)?),
+        _ => anyhow::bail!("Usage: trace_tree <trace.txt> [symbols.txt]"),
+    };
+
+    for record in read_trace_file(trace)? {
+        match record.opcode() {
+            Some(opcode) => println!("opcode {opcode:#06x}"),
+            None => println!("opcode unknown"),
+        }
+        print!("{}", render_tree(&record.strct, &syms));
+        if let Some(addr) = record.strct.exception_ret_addr() {
+            match record.strct.exception_offset() {
+                Some(offset) => {
+                    println!("incomplete: exception at {addr:#x}, decoding {offset:#x}")
+                }
+                None => println!("incomplete: exception at {addr:#x}"),
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
@@ -0,0 +1,489 @@
+//! Raw socket capture.
+//!
+//! Every connection writes the bytes of both directions into `conn<N>_send.bin` and
+//! `conn<N>_recv.bin`, so the receive stream starts with the handshake like the streams
+//! `trace_decrypt` expects. `index.jsonl` lists the chunks as they were passed to the socket, the
+//! sequence number is shared with the trace records of the same session. A sent packet is traced
+//! before Its chunk, a received packet after It, chunks without a record bypassed the packet hooks.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto::HEADER_LEN, mock::Handshake, packet_struct::PacketTraceRecord, PacketDir};
+
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// Line of the capture index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum CaptureEvent {
+    /// First data on a socket, which starts the streams of a connection
+    Open {
+        time_ms: u64,
+        conn: u32,
+        socket: u64,
+    },
+    /// Chunk of a stream, starting at `offset`
+    Data {
+        seq: u64,
+        time_ms: u64,
+        conn: u32,
+        dir: PacketDir,
+        offset: u64,
+        len: usize,
+    },
+    Close {
+        time_ms: u64,
+        conn: u32,
+    },
+}
+
+/// Stream file of one connection and direction
+pub fn stream_file(dir: impl AsRef<Path>, conn: u32, pkt_dir: PacketDir) -> PathBuf {
+    let suffix = match pkt_dir {
+        PacketDir::Send => "send",
+        PacketDir::Recv => "recv",
+    };
+    dir.as_ref().join(format!("conn{conn}_{suffix}.bin"))
+}
+
+#[derive(Debug)]
+struct Conn {
+    id: u32,
+    send: BufWriter<File>,
+    recv: BufWriter<File>,
+    send_len: u64,
+    recv_len: u64,
+}
+
+#[derive(Debug)]
+pub struct CaptureWriter {
+    dir: PathBuf,
+    index: BufWriter<File>,
+    conns: HashMap<u64, Conn>,
+    next_conn: u32,
+    start: Instant,
+}
+
+impl CaptureWriter {
+    /// Creates the capture directory, an existing index is replaced
+    pub fn create(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Creating capture dir {}", dir.display()))?;
+        let index = File::create(dir.join(INDEX_FILE))?;
+        Ok(Self {
+            dir,
+            index: BufWriter::new(index),
+            conns: HashMap::new(),
+            next_conn: 0,
+            start: Instant::now(),
+        })
+    }
+
+    fn time_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn write_event(&mut self, ev: &CaptureEvent) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.index, ev)?;
+        self.index.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Appends the chunk to the stream of the socket, the first chunk opens a new connection
+    pub fn data(
+        &mut self,
+        socket: u64,
+        dir: PacketDir,
+        data: &[u8],
+        seq: u64,
+    ) -> anyhow::Result<()> {
+        let time_ms = self.time_ms();
+        if !self.conns.contains_key(&socket) {
+            let id = self.next_conn;
+            self.next_conn += 1;
+            let open = |dir| -> anyhow::Result<_> {
+                Ok(BufWriter::new(File::create(stream_file(
+                    &self.dir, id, dir,
+                ))?))
+            };
+            let conn = Conn {
+                id,
+                send: open(PacketDir::Send)?,
+                recv: open(PacketDir::Recv)?,
+                send_len: 0,
+                recv_len: 0,
+            };
+            self.conns.insert(socket, conn);
+            self.write_event(&CaptureEvent::Open {
+                time_ms,
+                conn: id,
+                socket,
+            })?;
+        }
+
+        let conn = self.conns.get_mut(&socket).unwrap();
+        let (file, len) = match dir {
+            PacketDir::Send => (&mut conn.send, &mut conn.send_len),
+            PacketDir::Recv => (&mut conn.recv, &mut conn.recv_len),
+        };
+        file.write_all(data)?;
+        let ev = CaptureEvent::Data {
+            seq,
+            time_ms,
+            conn: conn.id,
+            dir,
+            offset: *len,
+            len: data.len(),
+        };
+        *len += data.len() as u64;
+        self.write_event(&ev)
+    }
+
+    /// Ends the connection of the socket, as the handle can be reused
+    pub fn close(&mut self, socket: u64) -> anyhow::Result<()> {
+        let Some(mut conn) = self.conns.remove(&socket) else {
+            return Ok(());
+        };
+        conn.send.flush()?;
+        conn.recv.flush()?;
+        let time_ms = self.time_ms();
+        self.write_event(&CaptureEvent::Close {
+            time_ms,
+            conn: conn.id,
+        })
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        for conn in self.conns.values_mut() {
+            conn.send.flush()?;
+            conn.recv.flush()?;
+        }
+        self.index.flush()?;
+        Ok(())
+    }
+}
+
+pub fn read_index(path: impl AsRef<Path>) -> anyhow::Result<Vec<CaptureEvent>> {
+    let file = File::open(path.as_ref())
+        .with_context(|| format!("Opening capture index {}", path.as_ref().display()))?;
+    BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str(&line?).with_context(|| format!("Invalid index line {}", i + 1))
+        })
+        .collect()
+}
+
+/// Frames of an encrypted stream, the length is the XOR of the header halves, so no IV is
+/// required. Stops at an incomplete frame, a receive stream must be passed without the handshake
+pub fn split_frames(stream: &[u8]) -> Vec<Range<usize>> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = stream.get(offset..offset + HEADER_LEN) {
+        let a = u16::from_le_bytes([header[0], header[1]]);
+        let b = u16::from_le_bytes([header[2], header[3]]);
+        let end = offset + HEADER_LEN + (a ^ b) as usize;
+        if end > stream.len() {
+            break;
+        }
+        frames.push(offset..end);
+        offset = end;
+    }
+    frames
+}
+
+/// Frame of a captured stream and the trace record of Its packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    pub conn: u32,
+    pub dir: PacketDir,
+    /// Range in the stream file
+    pub range: Range<u64>,
+    /// Sequence of the chunk, which starts a sent or completes a received frame
+    pub seq: u64,
+    /// Index of the trace record of the direction, None If the packet bypassed the hooks
+    pub record: Option<usize>,
+}
+
+/// Frames of a stream, the receive stream is passed with the handshake
+fn stream_frames(stream: &[u8], dir: PacketDir) -> anyhow::Result<Vec<Range<u64>>> {
+    let start = match dir {
+        PacketDir::Send => 0,
+        PacketDir::Recv if stream.is_empty() => 0,
+        PacketDir::Recv => {
+            let mut rest = stream;
+            Handshake::read(&mut rest).context("Receive stream without a handshake")?;
+            stream.len() - rest.len()
+        }
+    };
+    Ok(split_frames(&stream[start..])
+        .into_iter()
+        .map(|frame| (frame.start + start) as u64..(frame.end + start) as u64)
+        .collect())
+}
+
+/// Joins the frames of the captured streams with the trace records by their sequence.
+///
+/// A sent record is matched with the next sent frame, a received frame with the next received
+/// record. Records with data are skipped If the length differs, as their packet was not passed to
+/// the socket. Fails for records without a sequence, which were traced without the raw capture
+pub fn join_records(
+    dir: impl AsRef<Path>,
+    send: &[PacketTraceRecord],
+    recv: &[PacketTraceRecord],
+) -> anyhow::Result<Vec<CapturedFrame>> {
+    let dir = dir.as_ref();
+    let events = read_index(dir.join(INDEX_FILE))?;
+
+    let mut frames = Vec::new();
+    for ev in &events {
+        let CaptureEvent::Open { conn, .. } = *ev else {
+            continue;
+        };
+        for pkt_dir in [PacketDir::Send, PacketDir::Recv] {
+            let path = stream_file(dir, conn, pkt_dir);
+            let stream = std::fs::read(&path)
+                .with_context(|| format!("Reading stream {}", path.display()))?;
+            let chunks = events
+                .iter()
+                .filter_map(|ev| match *ev {
+                    CaptureEvent::Data {
+                        seq,
+                        conn: c,
+                        dir: d,
+                        offset,
+                        len,
+                        ..
+                    } if c == conn && d == pkt_dir => Some((seq, offset..offset + len as u64)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            for range in stream_frames(&stream, pkt_dir)? {
+                let pos = match pkt_dir {
+                    PacketDir::Send => range.start,
+                    PacketDir::Recv => range.end - 1,
+                };
+                let (seq, _) = chunks
+                    .iter()
+                    .find(|(_, chunk)| chunk.contains(&pos))
+                    .with_context(|| format!("No chunk for the frame {range:?} of conn {conn}"))?;
+                frames.push(CapturedFrame {
+                    conn,
+                    dir: pkt_dir,
+                    range,
+                    seq: *seq,
+                    record: None,
+                });
+            }
+        }
+    }
+
+    let records = |dir| match dir {
+        PacketDir::Send => send,
+        PacketDir::Recv => recv,
+    };
+    let data_len = |dir, i: usize| records(dir)[i].data.as_ref().map(|data| data.len() as u64);
+    let record_seq = |dir, i: usize| {
+        records(dir)[i]
+            .seq
+            .with_context(|| format!("{dir:?} record {i} has no seq, enable the raw capture"))
+    };
+
+    // Frames of a chunk share Its sequence and stay in stream order
+    let mut items = (0..frames.len())
+        .map(|i| Ok((frames[i].seq, Item::Frame(i))))
+        .chain((0..send.len()).map(|i| Ok((record_seq(PacketDir::Send, i)?, Item::Send(i)))))
+        .chain((0..recv.len()).map(|i| Ok((record_seq(PacketDir::Recv, i)?, Item::Recv(i)))))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    items.sort_by_key(|(seq, _)| *seq);
+
+    // Sent records precede their chunk, received records follow It
+    let mut pending_send = VecDeque::new();
+    let mut pending_recv = VecDeque::new();
+    for (_, item) in items {
+        match item {
+            Item::Frame(i) if frames[i].dir == PacketDir::Send => {
+                let len = frame_len(&frames[i]);
+                frames[i].record = take(&mut pending_send, |r| {
+                    data_len(PacketDir::Send, r).is_none_or(|l| l == len)
+                });
+            }
+            Item::Frame(i) => pending_recv.push_back(i),
+            Item::Send(r) => pending_send.push_back(r),
+            Item::Recv(r) => {
+                let len = data_len(PacketDir::Recv, r);
+                let frame = take(&mut pending_recv, |f| {
+                    len.is_none_or(|l| l == frame_len(&frames[f]))
+                });
+                if let Some(f) = frame {
+                    frames[f].record = Some(r);
+                }
+            }
+        }
+    }
+    frames.sort_by_key(|frame| frame.seq);
+    Ok(frames)
+}
+
+enum Item {
+    Frame(usize),
+    Send(usize),
+    Recv(usize),
+}
+
+/// Takes the first queued entry, which matches, the skipped ones are dropped
+fn take(queue: &mut VecDeque<usize>, matches: impl Fn(usize) -> bool) -> Option<usize> {
+    while let Some(front) = queue.pop_front() {
+        if matches(front) {
+            return Some(front);
+        }
+    }
+    None
+}
+
+fn frame_len(frame: &CapturedFrame) -> u64 {
+    frame.range.end - frame.range.start - HEADER_LEN as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{crypto::PacketCipher, mock::Handshake};
+
+    use super::*;
+
+    #[test]
+    fn capture() {
+        let dir = std::env::temp_dir().join(format!("shroom_capture_{}", std::process::id()));
+        let hs = Handshake::default();
+        let mut client = PacketCipher::client(hs.client_iv, hs.version, true);
+        let frames = [client.encrypt(&[1, 0, 1, 2, 3]), client.encrypt(&[2, 0])];
+
+        let mut writer = CaptureWriter::create(&dir).unwrap();
+        writer
+            .data(0x100, PacketDir::Recv, &hs.encode(), 0)
+            .unwrap();
+        // The second frame is split across two sends
+        writer.data(0x100, PacketDir::Send, &frames[0], 1).unwrap();
+        writer
+            .data(0x100, PacketDir::Send, &frames[1][..3], 2)
+            .unwrap();
+        writer
+            .data(0x100, PacketDir::Send, &frames[1][3..], 3)
+            .unwrap();
+        writer.close(0x100).unwrap();
+        // A reused handle starts a new connection
+        writer
+            .data(0x100, PacketDir::Recv, &hs.encode(), 4)
+            .unwrap();
+        writer.flush().unwrap();
+
+        let events = read_index(dir.join(INDEX_FILE)).unwrap();
+        assert_eq!(events.len(), 8);
+        assert!(matches!(
+            events[0],
+            CaptureEvent::Open {
+                conn: 0,
+                socket: 0x100,
+                ..
+            }
+        ));
+        let CaptureEvent::Data {
+            seq, offset, len, ..
+        } = events[4]
+        else {
+            panic!("Expected data: {:?}", events[4]);
+        };
+        assert_eq!((seq, offset, len), (3, 12, 3));
+        assert!(matches!(events[5], CaptureEvent::Close { conn: 0, .. }));
+        assert!(matches!(events[6], CaptureEvent::Open { conn: 1, .. }));
+
+        let send = std::fs::read(stream_file(&dir, 0, PacketDir::Send)).unwrap();
+        assert_eq!(send, frames.concat());
+        let recv = std::fs::read(stream_file(&dir, 0, PacketDir::Recv)).unwrap();
+        assert_eq!(Handshake::read(&mut recv.as_slice()).unwrap(), hs);
+
+        let split = split_frames(&send);
+        assert_eq!(split, [0..9, 9..15]);
+        // Incomplete frames are not returned
+        assert_eq!(split_frames(&send[..12]).len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn join() {
+        let dir = std::env::temp_dir().join(format!("shroom_join_{}", std::process::id()));
+        let hs = Handshake::default();
+        let mut client = PacketCipher::client(hs.client_iv, hs.version, true);
+        let mut server = PacketCipher::server(hs.server_iv, hs.version, true);
+        let sent = [client.encrypt(&[1, 0, 1]), client.encrypt(&[2, 0, 1, 2])];
+        let received = [server.encrypt(&[3, 0]), server.encrypt(&[4, 0, 5])];
+        let record = |data: &[u8], seq| PacketTraceRecord {
+            data: Some(data.to_vec()),
+            seq: Some(seq),
+            ..Default::default()
+        };
+
+        let mut writer = CaptureWriter::create(&dir).unwrap();
+        writer
+            .data(0x100, PacketDir::Recv, &hs.encode(), 0)
+            .unwrap();
+        // Dropped before the socket, so no frame follows
+        let send = vec![
+            record(&[9, 0, 0, 0, 0], 1),
+            record(&[1, 0, 1], 2),
+            record(&[2, 0, 1, 2], 4),
+        ];
+        writer.data(0x100, PacketDir::Send, &sent[0], 3).unwrap();
+        writer
+            .data(0x100, PacketDir::Send, &sent[1][..2], 5)
+            .unwrap();
+        writer
+            .data(0x100, PacketDir::Send, &sent[1][2..], 6)
+            .unwrap();
+        // Both received frames arrive in one chunk, the second bypasses the hooks
+        writer
+            .data(0x100, PacketDir::Recv, &received.concat(), 7)
+            .unwrap();
+        let recv = vec![record(&[3, 0], 8)];
+        writer.flush().unwrap();
+
+        let frames = join_records(&dir, &send, &recv).unwrap();
+        let joined = frames
+            .iter()
+            .map(|frame| (frame.dir, frame.seq, frame.record))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            joined,
+            [
+                (PacketDir::Send, 3, Some(1)),
+                (PacketDir::Send, 5, Some(2)),
+                (PacketDir::Recv, 7, Some(0)),
+                (PacketDir::Recv, 7, None),
+            ]
+        );
+        let hs_len = hs.encode().len() as u64;
+        assert_eq!(frames[2].range, hs_len..hs_len + 6);
+
+        // Traces without the raw capture can't be joined
+        let mut send = send;
+        send[0].seq = None;
+        assert!(join_records(&dir, &send, &recv).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{
+    merge::{MergedField, MergedStruct, MergedTy},
+    symbols::{sym_to_camel, sym_to_snake, SymbolMap},
+    tree::{build_tree, TreeItem, TreeNode},
+};
+
+fn struct_name(strct: &MergedStruct, syms: &SymbolMap) -> String {
+    match strct.handler_addr().and_then(|addr| syms.lookup(addr)) {
+        Some(sym) => format!("{}{:04X}", sym_to_camel(sym), strct.opcode),
+        None => format!("{:?}Op{:04X}", strct.dir, strct.opcode),
+    }
+}
+
+fn field_name(field: &MergedField, syms: &SymbolMap, used: &mut HashSet<String>) -> String {
+    let prefix = match field.ty {
+        MergedTy::Gap(_) | MergedTy::VarGap => "todo_gap".to_string(),
+        MergedTy::VarBuf => "todo_buf".to_string(),
+        _ => syms
+            .lookup(field.ret_address)
+            .map(sym_to_snake)
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| "field".to_string()),
+    };
+
+    unique_name(&prefix, field.offset, used)
+}
+
+fn unique_name(prefix: &str, offset: usize, used: &mut HashSet<String>) -> String {
+    let mut name = format!("{prefix}_{offset:x}");
+    let mut n = 1;
+    while !used.insert(name.clone()) {
+        n += 1;
+        name = format!("{prefix}_{offset:x}_{n}");
+    }
+    name
+}
+
+fn field_ty(ty: &MergedTy) -> String {
+    match ty {
+        MergedTy::I8 => "u8".to_string(),
+        MergedTy::I16 => "u16".to_string(),
+        MergedTy::I32 => "u32".to_string(),
+        MergedTy::I64 => "u64".to_string(),
+        MergedTy::F64 => "f64".to_string(),
+        MergedTy::Time => "u64 /* FILETIME */".to_string(),
+        MergedTy::Pos => "[i16; 2]".to_string(),
+        MergedTy::Str => "String".to_string(),
+        MergedTy::Buf(n) | MergedTy::Gap(n) => format!("[u8; {n}]"),
+        MergedTy::VarBuf | MergedTy::VarGap => "Vec<u8>".to_string(),
+    }
+}
+
+fn todo_comment(ty: &MergedTy) -> Option<&'static str> {
+    match ty {
+        MergedTy::VarBuf => Some("TODO: buffer length differs between observations"),
+        MergedTy::Gap(_) => Some("TODO: data without a hooked encode/decode call"),
+        MergedTy::VarGap => Some("TODO: unhooked data with a varying length"),
+        _ => None,
+    }
+}
+
+/// Sub structs of all generated packets, helpers shared by packets are only emitted once
+#[derive(Default)]
+struct Subs {
+    /// Fields per emitted sub struct name
+    layouts: HashMap<String, String>,
+    /// Definitions, which weren't written yet
+    pending: Vec<String>,
+}
+
+impl Subs {
+    /// Returns the name of the sub struct with the fields.
+    /// If another layout uses the name already, the parent is added as prefix
+    fn insert(&mut self, name: &str, parent: &str, sym: Option<&str>, fields: String) -> String {
+        let mut sub_name = name.to_string();
+        let mut n = 1;
+        while let Some(layout) = self.layouts.get(&sub_name) {
+            if *layout == fields {
+                return sub_name;
+            }
+            n += 1;
+            sub_name = match n {
+                2 => format!("{parent}{name}"),
+                _ => format!("{parent}{name}{n}"),
+            };
+        }
+
+        let mut sub = String::new();
+        if let Some(sym) = sym {
+            writeln!(sub, "/// Read or written by {sym}").unwrap();
+        }
+        writeln!(sub, "#[derive(Debug, Clone)]").unwrap();
+        writeln!(sub, "pub struct {sub_name} {{").unwrap();
+        sub.push_str(&fields);
+        writeln!(sub, "}}").unwrap();
+        self.pending.push(sub);
+        self.layouts.insert(sub_name.clone(), fields);
+        sub_name
+    }
+}
+
+/// Generates a rust struct per merged packet structure
+pub fn gen_rust_struct(strct: &MergedStruct, syms: &SymbolMap) -> String {
+    gen_struct(strct, syms, &mut Subs::default())
+}
+
+fn gen_struct(strct: &MergedStruct, syms: &SymbolMap, subs: &mut Subs) -> String {
+    let mut out = String::new();
+    let name = struct_name(strct, syms);
+
+    writeln!(
+        out,
+        "/// {:?} opcode {:#06x}, observed {} times",
+        strct.dir, strct.opcode, strct.observations
+    )
+    .unwrap();
+    if let Some(addr) = strct.handler_addr() {
+        let sym = syms.lookup(addr).unwrap_or("unknown");
+        writeln!(out, "/// Handler: {sym} ({addr:#x})").unwrap();
+    }
+    if strct.incomplete > 0 {
+        writeln!(
+            out,
+            "/// {} observations were incomplete due to exceptions",
+            strct.incomplete
+        )
+        .unwrap();
+    }
+    if !strct.exception_offsets.is_empty() {
+        let offsets = strct
+            .exception_offsets
+            .iter()
+            .map(|offset| format!("{offset:#x}"))
+            .collect::<Vec<_>>();
+        writeln!(out, "/// Decoding failed at: {}", offsets.join(", ")).unwrap();
+    }
+    let stacks = strct
+        .fields
+        .iter()
+        .map(MergedField::frames)
+        .collect::<Vec<_>>();
+    let tree = build_tree(&stacks);
+
+    writeln!(out, "#[derive(Debug, Clone)]").unwrap();
+    writeln!(out, "pub struct {name} {{").unwrap();
+    write_fields(&mut out, &name, &strct.fields, &tree, syms, subs);
+    if let Some(offset) = strct.diverges_at {
+        writeln!(
+            out,
+            "    // TODO: the layout differs between observations from here on"
+        )
+        .unwrap();
+        writeln!(out, "    pub todo_rest_{offset:x}: Vec<u8>,").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "impl {name} {{").unwrap();
+    writeln!(out, "    pub const OPCODE: u16 = {:#06x};", strct.opcode).unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for sub in subs.pending.drain(..) {
+        writeln!(out).unwrap();
+        out.push_str(&sub);
+    }
+
+    out
+}
+
+/// First field offset inside of a tree node
+fn node_offset(fields: &[MergedField], node: &TreeNode) -> usize {
+    node.items
+        .iter()
+        .map(|item| match item {
+            TreeItem::Elem(i) => fields[*i].offset,
+            TreeItem::Node(node) => node_offset(fields, node),
+        })
+        .next()
+        .unwrap_or_default()
+}
+
+/// Writes the fields of a tree level, nested helper calls become sub structs
+fn write_fields(
+    out: &mut String,
+    parent: &str,
+    fields: &[MergedField],
+    items: &[TreeItem],
+    syms: &SymbolMap,
+    subs: &mut Subs,
+) {
+    let mut used = HashSet::new();
+    for item in items {
+        match item {
+            TreeItem::Elem(i) => write_field(out, &fields[*i], syms, &mut used),
+            TreeItem::Node(node) => {
+                let offset = node_offset(fields, node);
+                let sym = syms.lookup(node.callee_addr);
+                let sub_name = match sym {
+                    Some(sym) => format!("{}{offset:X}", sym_to_camel(sym)),
+                    None => format!("{parent}Sub{offset:X}"),
+                };
+                let prefix = sym
+                    .map(sym_to_snake)
+                    .filter(|s| !s.is_empty())
+                    .unwrap_or_else(|| "sub".to_string());
+                let field_name = unique_name(&prefix, offset, &mut used);
+                let mut sub_fields = String::new();
+                write_fields(&mut sub_fields, &sub_name, fields, &node.items, syms, subs);
+                let sub_name = subs.insert(&sub_name, parent, sym, sub_fields);
+                writeln!(
+                    out,
+                    "    pub {field_name}: {sub_name}, // {offset:#x} called at {:#x}",
+                    node.call_site
+                )
+                .unwrap();
+            }
+        }
+    }
+}
+
+fn write_field(
+    out: &mut String,
+    field: &MergedField,
+    syms: &SymbolMap,
+    used: &mut HashSet<String>,
+) {
+    if let Some(todo) = todo_comment(&field.ty) {
+        writeln!(out, "    // {todo}").unwrap();
+    }
+    let sym = syms
+        .lookup(field.ret_address)
+        .filter(|_| field.ret_address != usize::MAX);
+    let field_name = field_name(field, syms, used);
+    match sym {
+        Some(sym) => writeln!(
+            out,
+            "    pub {field_name}: {}, // {:#x} {sym}",
+            field_ty(&field.ty),
+            field.offset
+        ),
+        None => writeln!(
+            out,
+            "    pub {field_name}: {}, // {:#x}",
+            field_ty(&field.ty),
+            field.offset
+        ),
+    }
+    .unwrap();
+}
+
+pub fn gen_rust(structs: &[MergedStruct], syms: &SymbolMap) -> String {
+    let mut out = String::from("// Generated from packet traces\n\n");
+    let mut subs = Subs::default();
+    for (i, strct) in structs.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(&gen_struct(strct, syms, &mut subs));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{merge::merge_records, packet_struct::parse_trace, PacketDir};
+
+    use super::*;
+
+    // Two observations of the same opcode with a different buffer length
+    const TRACE: &str = r#"{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0},{"ret_address":4352,"ty":"I32","offset":2},{"ret_address":4608,"ty":{"Buf":2},"offset":6}],"send_ret_addr":8192,"exception_ret_addr":null,"last_known_offset":8,"opcode":5},"data":null},
+{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0},{"ret_address":4352,"ty":"I32","offset":2},{"ret_address":4608,"ty":{"Buf":3},"offset":6}],"send_ret_addr":8192,"exception_ret_addr":null,"last_known_offset":9,"opcode":5},"data":null},
+"#;
+
+    #[test]
+    fn gen_struct() {
+        let records = parse_trace(TRACE).unwrap();
+        let merged = merge_records(PacketDir::Send, &records);
+        let mut syms = SymbolMap::new();
+        syms.insert(0x1000, "CField::SendTransferFieldRequest");
+        syms.insert(0x2000, "CField::SendTransferFieldRequest");
+
+        let src = gen_rust(&merged, &syms);
+        assert!(src.contains("pub struct CFieldSendTransferFieldRequest0005 {"));
+        assert!(src.contains("pub send_transfer_field_request_2: u32, // 0x2"));
+        assert!(src.contains("// TODO: buffer length differs between observations"));
+        assert!(src.contains("pub todo_buf_6: Vec<u8>, // 0x6"));
+    }
+
+    // Handler(0x3000) reads a u8 and calls a helper(0x4000) at 0x3010, which reads a u32
+    const NESTED_TRACE: &str = r#"{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0,"call_stack":[20480]},{"ret_address":12288,"ty":"I8","offset":2,"call_stack":[20496]},{"ret_address":16384,"ty":"I32","offset":3,"call_stack":[12304,20496]}],"send_ret_addr":null,"exception_ret_addr":null,"last_known_offset":7,"opcode":7},"data":null},
+"#;
+
+    // A complete observation and one, which threw while decoding the u32
+    const INCOMPLETE_TRACE: &str = r#"{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0},{"ret_address":4352,"ty":"I8","offset":2},{"ret_address":4352,"ty":"I32","offset":3}],"send_ret_addr":null,"exception_ret_addr":null,"last_known_offset":7,"opcode":9},"data":null},
+{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0},{"ret_address":4352,"ty":"I8","offset":2}],"send_ret_addr":null,"exception_ret_addr":30000,"last_known_offset":3,"opcode":9,"exception_offset":3},"data":null},
+"#;
+
+    #[test]
+    fn gen_incomplete() {
+        let records = parse_trace(INCOMPLETE_TRACE).unwrap();
+        let merged = merge_records(PacketDir::Recv, &records);
+        assert_eq!(merged[0].incomplete, 1);
+        assert_eq!(merged[0].exception_offsets, [3]);
+
+        let src = gen_rust(&merged, &SymbolMap::new());
+        assert!(src.contains("/// Decoding failed at: 0x3"));
+        assert!(src.contains("pub field_3: u32, // 0x3"));
+    }
+
+    #[test]
+    fn gen_nested() {
+        let records = parse_trace(NESTED_TRACE).unwrap();
+        let merged = merge_records(PacketDir::Recv, &records);
+        let mut syms = SymbolMap::new();
+        syms.insert(0x3000, "CField::OnFoo");
+        syms.insert(0x4000, "GW_Foo::Decode");
+
+        let src = gen_rust(&merged, &syms);
+        assert!(src.contains("pub on_foo_2: u8, // 0x2 CField::OnFoo"));
+        assert!(src.contains("pub decode_3: GWFooDecode3, // 0x3 called at 0x3010"));
+        assert!(src.contains("pub struct GWFooDecode3 {"));
+        assert!(src.contains("pub decode_3: u32, // 0x3 GW_Foo::Decode"));
+    }
+
+    // Three packets, whose handlers read a u8 and call the same helper(0x4000) at offset 3
+    const SHARED_TRACE: &str = r#"{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0,"call_stack":[20480]},{"ret_address":12288,"ty":"I8","offset":2,"call_stack":[20496]},{"ret_address":16384,"ty":"I32","offset":3,"call_stack":[12304,20496]}],"send_ret_addr":null,"exception_ret_addr":null,"last_known_offset":7,"opcode":7},"data":null},
+{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0,"call_stack":[20480]},{"ret_address":12544,"ty":"I8","offset":2,"call_stack":[20752]},{"ret_address":16384,"ty":"I32","offset":3,"call_stack":[12560,20752]}],"send_ret_addr":null,"exception_ret_addr":null,"last_known_offset":7,"opcode":8},"data":null},
+{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0,"call_stack":[20480]},{"ret_address":12800,"ty":"I8","offset":2,"call_stack":[21008]},{"ret_address":16384,"ty":"I8","offset":3,"call_stack":[12816,21008]}],"send_ret_addr":null,"exception_ret_addr":null,"last_known_offset":4,"opcode":9},"data":null},
+"#;
+
+    #[test]
+    fn gen_shared_helper() {
+        let records = parse_trace(SHARED_TRACE).unwrap();
+        let merged = merge_records(PacketDir::Recv, &records);
+        let mut syms = SymbolMap::new();
+        syms.insert(0x4000, "GW_Foo::Decode");
+
+        let src = gen_rust(&merged, &syms);
+        // The same layout is emitted once, a different one gets the parent as prefix
+        assert_eq!(src.matches("pub struct GWFooDecode3 {").count(), 1);
+        assert_eq!(src.matches(": GWFooDecode3,").count(), 2);
+        assert!(src.contains("pub struct RecvOp0009GWFooDecode3 {"));
+        assert!(src.contains(": RecvOp0009GWFooDecode3,"));
+    }
+}
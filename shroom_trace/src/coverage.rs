@@ -0,0 +1,312 @@
+//! Coverage of the receive handlers.
+//!
+//! Every processed packet is recorded with the set of decode call sites, which were hit while
+//! handling It. Coverage files of multiple sessions can be merged and compared against a list
+//! of the known opcodes.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::packet_struct::PacketStruct;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpcodeCoverage {
+    pub count: u64,
+    /// Observations, which failed to decode
+    pub incomplete: u64,
+    /// Distinct sets of decode call sites, every set is a path through the handler
+    pub paths: BTreeSet<Vec<usize>>,
+}
+
+impl OpcodeCoverage {
+    fn merge(&mut self, other: OpcodeCoverage) {
+        self.count += other.count;
+        self.incomplete += other.incomplete;
+        self.paths.extend(other.paths);
+    }
+}
+
+/// Exercised inbound opcodes of one or more sessions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Coverage {
+    pub opcodes: BTreeMap<u16, OpcodeCoverage>,
+}
+
+/// Sorted decode call sites of the body, the opcode is decoded by the dispatcher
+fn call_sites(strct: &PacketStruct) -> Vec<usize> {
+    strct
+        .elements()
+        .iter()
+        .filter(|elem| elem.offset() >= 2 && !elem.is_gap())
+        .map(|elem| elem.ret_address())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.opcodes.is_empty()
+    }
+
+    /// Records a processed structure, returns false If It has no opcode
+    pub fn record(&mut self, strct: &PacketStruct) -> bool {
+        let Some(opcode) = strct.opcode() else {
+            return false;
+        };
+
+        let cov = self.opcodes.entry(opcode).or_default();
+        cov.count += 1;
+        if strct.is_incomplete() {
+            cov.incomplete += 1;
+        }
+        cov.paths.insert(call_sites(strct));
+        true
+    }
+
+    pub fn merge(&mut self, other: Coverage) {
+        for (opcode, cov) in other.opcodes {
+            self.opcodes.entry(opcode).or_default().merge(cov);
+        }
+    }
+
+    /// Loads a saved coverage file, a missing file is an empty coverage
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading coverage file {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Parsing coverage file {}", path.display()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Writing coverage file {}", path.display()))
+    }
+
+    /// Compares the coverage against the known opcodes
+    pub fn report(&self, known: &KnownOpcodes) -> CoverageReport {
+        let entry = |opcode: u16, cov: Option<&OpcodeCoverage>| CoverageEntry {
+            opcode,
+            name: known.name(opcode).map(str::to_string),
+            count: cov.map_or(0, |cov| cov.count),
+            incomplete: cov.map_or(0, |cov| cov.incomplete),
+            paths: cov.map_or(0, |cov| cov.paths.len()),
+        };
+
+        let mut report = CoverageReport::default();
+        for &opcode in known.opcodes.keys() {
+            let cov = self.opcodes.get(&opcode);
+            let e = entry(opcode, cov);
+            match cov {
+                None => report.never_seen.push(e),
+                Some(cov) if cov.incomplete > 0 => report.incomplete.push(e),
+                Some(_) => report.seen.push(e),
+            }
+        }
+        for (&opcode, cov) in &self.opcodes {
+            if !known.opcodes.contains_key(&opcode) {
+                report.unknown.push(entry(opcode, Some(cov)));
+            }
+        }
+        report
+    }
+}
+
+/// Opcodes, which the server can send
+#[derive(Debug, Clone, Default)]
+pub struct KnownOpcodes {
+    opcodes: BTreeMap<u16, Option<String>>,
+}
+
+impl KnownOpcodes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, opcode: u16, name: Option<String>) {
+        self.opcodes.insert(opcode, name);
+    }
+
+    /// Parses one `<hex opcode> [name]` pair per line, `#` starts a comment
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut known = Self::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (opcode, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let opcode = u16::from_str_radix(opcode.trim_start_matches("0x"), 16)
+                .with_context(|| format!("Invalid opcode in line {}", i + 1))?;
+            let name = name.trim();
+            known.insert(opcode, (!name.is_empty()).then(|| name.to_string()));
+        }
+        Ok(known)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading opcode list {}", path.display()))?;
+        Self::parse(&s)
+    }
+
+    pub fn name(&self, opcode: u16) -> Option<&str> {
+        self.opcodes.get(&opcode)?.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.opcodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.opcodes.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageEntry {
+    pub opcode: u16,
+    pub name: Option<String>,
+    pub count: u64,
+    pub incomplete: u64,
+    /// Number of distinct decode paths
+    pub paths: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageReport {
+    pub seen: Vec<CoverageEntry>,
+    /// Known opcodes, which failed to decode at least once
+    pub incomplete: Vec<CoverageEntry>,
+    pub never_seen: Vec<CoverageEntry>,
+    /// Seen opcodes, which are missing in the list
+    pub unknown: Vec<CoverageEntry>,
+}
+
+impl CoverageReport {
+    /// Share of the known opcodes, which were seen
+    pub fn ratio(&self) -> f64 {
+        let seen = self.seen.len() + self.incomplete.len();
+        let total = seen + self.never_seen.len();
+        if total == 0 {
+            return 0.0;
+        }
+        seen as f64 / total as f64
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Writing coverage report {}", path.display()))
+    }
+}
+
+impl fmt::Display for CoverageEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}", self.opcode)?;
+        if let Some(ref name) = self.name {
+            write!(f, " {name}")?;
+        }
+        if self.count > 0 {
+            write!(f, " - count: {}, paths: {}", self.count, self.paths)?;
+        }
+        if self.incomplete > 0 {
+            write!(f, ", incomplete: {}", self.incomplete)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sections = [
+            ("Seen", &self.seen),
+            ("Incomplete", &self.incomplete),
+            ("Never seen", &self.never_seen),
+            ("Not in the opcode list", &self.unknown),
+        ];
+        for (title, entries) in sections {
+            writeln!(f, "{title} ({}):", entries.len())?;
+            for e in entries {
+                writeln!(f, "  {e}")?;
+            }
+        }
+        writeln!(f, "Coverage: {:.1}%", self.ratio() * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet_struct::{PacketStructElem, PacketStructTy};
+
+    use super::*;
+
+    fn recv(opcode: u16, call_sites: &[usize], incomplete: bool) -> PacketStruct {
+        let mut strct = PacketStruct::new_recv();
+        strct.add_elem(PacketStructElem::new(0, 0x1000, PacketStructTy::I16));
+        for (i, &addr) in call_sites.iter().enumerate() {
+            strct.add_elem(PacketStructElem::new(2 + i, addr, PacketStructTy::I8));
+        }
+        if incomplete {
+            strct.set_exception_ret_addr(0x5000);
+        }
+        strct.set_opcode(opcode);
+        strct
+    }
+
+    #[test]
+    fn report_and_merge() {
+        let known = KnownOpcodes::parse(
+            "# login\n0x10 CheckPasswordResult\n0x11\n0x7a UserChat # chat\n0x7b\n",
+        )
+        .unwrap();
+        assert_eq!(known.len(), 4);
+
+        let mut first = Coverage::new();
+        first.record(&recv(0x10, &[0x2000, 0x2010], false));
+        first.record(&recv(0x10, &[0x2010, 0x2000], false));
+        first.record(&recv(0x7a, &[0x3000], true));
+
+        let mut second = Coverage::new();
+        second.record(&recv(0x10, &[0x2000, 0x2020], false));
+        second.record(&recv(0x99, &[0x4000], false));
+
+        // Sessions are merged through the saved file
+        let json = serde_json::to_string(&second).unwrap();
+        first.merge(serde_json::from_str(&json).unwrap());
+
+        let login = &first.opcodes[&0x10];
+        assert_eq!(login.count, 3);
+        assert_eq!(login.paths.len(), 2);
+
+        let report = first.report(&known);
+        let opcodes =
+            |entries: &[CoverageEntry]| entries.iter().map(|e| e.opcode).collect::<Vec<_>>();
+        assert_eq!(opcodes(&report.seen), [0x10]);
+        assert_eq!(opcodes(&report.incomplete), [0x7a]);
+        assert_eq!(opcodes(&report.never_seen), [0x11, 0x7b]);
+        assert_eq!(opcodes(&report.unknown), [0x99]);
+        assert_eq!(report.seen[0].name.as_deref(), Some("CheckPasswordResult"));
+        assert_eq!(report.ratio(), 0.5);
+    }
+}
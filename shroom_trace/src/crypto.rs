@@ -0,0 +1,503 @@
+//! Packet encryption of the client.
+//!
+//! Every packet has a 4 byte header, which encodes the length with the current IV and the version.
+//! The data is transformed with Shanda(unless It's disabled), then XORed with an AES-OFB
+//! keystream, which restarts with the IV every 1460 bytes of the frame. The IV of a direction is
+//! shuffled after every packet.
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes256,
+};
+
+/// Key of the client, every key byte is the low byte of a little endian dword
+pub const AES_KEY: [u8; 32] = [
+    0x13, 0, 0, 0, 0x08, 0, 0, 0, 0x06, 0, 0, 0, 0xb4, 0, 0, 0, 0x1b, 0, 0, 0, 0x0f, 0, 0, 0, 0x33,
+    0, 0, 0, 0x52, 0, 0, 0,
+];
+
+/// Permutation, which is used by the IV shuffle
+pub const SHUFFLE: [u8; 256] = [
+    0xec, 0x3f, 0x77, 0xa4, 0x45, 0xd0, 0x71, 0xbf, 0xb7, 0x98, 0x20, 0xfc, 0x4b, 0xe9, 0xb3, 0xe1,
+    0x5c, 0x22, 0xf7, 0x0c, 0x44, 0x1b, 0x81, 0xbd, 0x63, 0x8d, 0xd4, 0xc3, 0xf2, 0x10, 0x19, 0xe0,
+    0xfb, 0xa1, 0x6e, 0x66, 0xea, 0xae, 0xd6, 0xce, 0x06, 0x18, 0x4e, 0xeb, 0x78, 0x95, 0xdb, 0xba,
+    0xb6, 0x42, 0x7a, 0x2a, 0x83, 0x0b, 0x54, 0x67, 0x6d, 0xe8, 0x65, 0xe7, 0x2f, 0x07, 0xf3, 0xaa,
+    0x27, 0x7b, 0x85, 0xb0, 0x26, 0xfd, 0x8b, 0xa9, 0xfa, 0xbe, 0xa8, 0xd7, 0xcb, 0xcc, 0x92, 0xda,
+    0xf9, 0x93, 0x60, 0x2d, 0xdd, 0xd2, 0xa2, 0x9b, 0x39, 0x5f, 0x82, 0x21, 0x4c, 0x69, 0xf8, 0x31,
+    0x87, 0xee, 0x8e, 0xad, 0x8c, 0x6a, 0xbc, 0xb5, 0x6b, 0x59, 0x13, 0xf1, 0x04, 0x00, 0xf6, 0x5a,
+    0x35, 0x79, 0x48, 0x8f, 0x15, 0xcd, 0x97, 0x57, 0x12, 0x3e, 0x37, 0xff, 0x9d, 0x4f, 0x51, 0xf5,
+    0xa3, 0x70, 0xbb, 0x14, 0x75, 0xc2, 0xb8, 0x72, 0xc0, 0xed, 0x7d, 0x68, 0xc9, 0x2e, 0x0d, 0x62,
+    0x46, 0x17, 0x11, 0x4d, 0x6c, 0xc4, 0x7e, 0x53, 0xc1, 0x25, 0xc7, 0x9a, 0x1c, 0x88, 0x58, 0x2c,
+    0x89, 0xdc, 0x02, 0x64, 0x40, 0x01, 0x5d, 0x38, 0xa5, 0xe2, 0xaf, 0x55, 0xd5, 0xef, 0x1a, 0x7c,
+    0xa7, 0x5b, 0xa6, 0x6f, 0x86, 0x9f, 0x73, 0xe6, 0x0a, 0xde, 0x2b, 0x99, 0x4a, 0x47, 0x9c, 0xdf,
+    0x09, 0x76, 0x9e, 0x30, 0x0e, 0xe4, 0xb2, 0x94, 0xa0, 0x3b, 0x34, 0x1d, 0x28, 0x0f, 0x36, 0xe3,
+    0x23, 0xb4, 0x03, 0xd8, 0x90, 0xc8, 0x3c, 0xfe, 0x5e, 0x32, 0x24, 0x50, 0x1f, 0x3a, 0x43, 0x8a,
+    0x96, 0x41, 0x74, 0xac, 0x52, 0x33, 0xf0, 0xd9, 0x29, 0x80, 0xb1, 0x16, 0xd3, 0xab, 0x91, 0xb9,
+    0x84, 0x7f, 0x61, 0x1e, 0xcf, 0xc5, 0xd1, 0x56, 0x3d, 0xca, 0xf4, 0x05, 0xc6, 0xe5, 0x08, 0x49,
+];
+
+pub const HEADER_LEN: usize = 4;
+/// Bytes of a frame, after which the AES IV is restarted, the first block includes the header
+const AES_BLOCK_LEN: usize = 1460;
+
+/// Next IV of a direction
+pub fn shuffle_iv(iv: [u8; 4]) -> [u8; 4] {
+    let mut new: [u8; 4] = [0xf2, 0x53, 0x50, 0xc6];
+    for input in iv {
+        let table_input = SHUFFLE[input as usize];
+        new[0] = new[0].wrapping_add(SHUFFLE[new[1] as usize].wrapping_sub(input));
+        new[1] = new[1].wrapping_sub(new[2] ^ table_input);
+        new[2] ^= SHUFFLE[new[3] as usize].wrapping_add(input);
+        new[3] = new[3].wrapping_sub(new[0].wrapping_sub(table_input));
+        new = u32::from_le_bytes(new).rotate_left(3).to_le_bytes();
+    }
+    new
+}
+
+pub fn shanda_encrypt(data: &mut [u8]) {
+    let len = data.len() as u8;
+    for round in 0..6 {
+        let mut remember = 0u8;
+        let mut data_len = len;
+        if round % 2 == 0 {
+            for b in data.iter_mut() {
+                let mut cur = b.rotate_left(3).wrapping_add(data_len);
+                cur ^= remember;
+                remember = cur;
+                cur = (!cur.rotate_right(data_len as u32 % 8)).wrapping_add(0x48);
+                *b = cur;
+                data_len = data_len.wrapping_sub(1);
+            }
+        } else {
+            for b in data.iter_mut().rev() {
+                let mut cur = b.rotate_left(4).wrapping_add(data_len);
+                cur ^= remember;
+                remember = cur;
+                *b = (cur ^ 0x13).rotate_right(3);
+                data_len = data_len.wrapping_sub(1);
+            }
+        }
+    }
+}
+
+pub fn shanda_decrypt(data: &mut [u8]) {
+    let len = data.len() as u8;
+    for _ in 0..3 {
+        let mut remember = 0u8;
+        let mut data_len = len;
+        for b in data.iter_mut().rev() {
+            let cur = b.rotate_left(3) ^ 0x13;
+            let next = cur;
+            *b = (cur ^ remember).wrapping_sub(data_len).rotate_right(4);
+            remember = next;
+            data_len = data_len.wrapping_sub(1);
+        }
+
+        let mut remember = 0u8;
+        let mut data_len = len;
+        for b in data.iter_mut() {
+            let cur = (!b.wrapping_sub(0x48)).rotate_left(data_len as u32 % 8);
+            let next = cur;
+            *b = (cur ^ remember).wrapping_sub(data_len).rotate_right(3);
+            remember = next;
+            data_len = data_len.wrapping_sub(1);
+        }
+    }
+}
+
+/// Cipher of one direction
+#[derive(Clone)]
+pub struct PacketCipher {
+    aes: Aes256,
+    iv: [u8; 4],
+    /// Version, which is encoded into the header
+    version: u16,
+    shanda: bool,
+}
+
+impl std::fmt::Debug for PacketCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketCipher")
+            .field("iv", &self.iv)
+            .field("version", &self.version)
+            .field("shanda", &self.shanda)
+            .finish()
+    }
+}
+
+impl PacketCipher {
+    fn new(iv: [u8; 4], version: u16, shanda: bool) -> Self {
+        Self {
+            aes: Aes256::new(&AES_KEY.into()),
+            iv,
+            version,
+            shanda,
+        }
+    }
+
+    /// Packets sent by the client
+    pub fn client(iv: [u8; 4], version: u16, shanda: bool) -> Self {
+        Self::new(iv, version, shanda)
+    }
+
+    /// Packets sent by the server, which encode the complement of the version
+    pub fn server(iv: [u8; 4], version: u16, shanda: bool) -> Self {
+        Self::new(iv, !version, shanda)
+    }
+
+    pub fn iv(&self) -> [u8; 4] {
+        self.iv
+    }
+
+    /// XORs the data with the AES-OFB keystream of the current IV
+    pub fn aes_crypt(&self, data: &mut [u8]) {
+        let first = (AES_BLOCK_LEN - HEADER_LEN).min(data.len());
+        let (head, tail) = data.split_at_mut(first);
+        for chunk in std::iter::once(head).chain(tail.chunks_mut(AES_BLOCK_LEN)) {
+            let mut block = GenericArray::from([0; 16]);
+            for (dst, src) in block.iter_mut().zip(self.iv.iter().cycle()) {
+                *dst = *src;
+            }
+
+            for part in chunk.chunks_mut(16) {
+                self.aes.encrypt_block(&mut block);
+                for (b, k) in part.iter_mut().zip(block.iter()) {
+                    *b ^= k;
+                }
+            }
+        }
+    }
+
+    pub fn encode_header(&self, len: u16) -> [u8; HEADER_LEN] {
+        let a = u16::from_le_bytes([self.iv[2], self.iv[3]]) ^ self.version;
+        let b = a ^ len;
+        let [a0, a1] = a.to_le_bytes();
+        let [b0, b1] = b.to_le_bytes();
+        [a0, a1, b0, b1]
+    }
+
+    /// Length of the packet, fails If the header does not match the IV and version
+    pub fn decode_header(&self, header: [u8; HEADER_LEN]) -> anyhow::Result<u16> {
+        let a = u16::from_le_bytes([header[0], header[1]]);
+        let b = u16::from_le_bytes([header[2], header[3]]);
+        anyhow::ensure!(
+            a ^ self.version == u16::from_le_bytes([self.iv[2], self.iv[3]]),
+            "Invalid packet header {header:02x?} for IV {:02x?}",
+            self.iv
+        );
+        Ok(a ^ b)
+    }
+
+    /// Encrypts a packet and returns the frame with the header
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut frame = self.encode_header(data.len() as u16).to_vec();
+        let mut data = data.to_vec();
+        if self.shanda {
+            shanda_encrypt(&mut data);
+        }
+        self.aes_crypt(&mut data);
+        frame.extend(data);
+        self.iv = shuffle_iv(self.iv);
+        frame
+    }
+
+    /// Decrypts the data of a packet without the header
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.aes_crypt(data);
+        if self.shanda {
+            shanda_decrypt(data);
+        }
+        self.iv = shuffle_iv(self.iv);
+    }
+}
+
+/// Splits the raw stream of one direction into decrypted packets
+#[derive(Debug)]
+pub struct PacketDecoder {
+    cipher: PacketCipher,
+    buf: Vec<u8>,
+}
+
+impl PacketDecoder {
+    pub fn new(cipher: PacketCipher) -> Self {
+        Self {
+            cipher,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Bytes of an incomplete packet
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Next complete packet, None If more data is required
+    pub fn next_packet(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(header) = self.buf.get(..HEADER_LEN) else {
+            return Ok(None);
+        };
+        let len = self.cipher.decode_header(header.try_into().unwrap())? as usize;
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let mut data = self.buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buf.drain(..HEADER_LEN + len);
+        self.cipher.decrypt(&mut data);
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffle_is_permutation() {
+        let mut seen = [false; 256];
+        for b in SHUFFLE {
+            assert!(!seen[b as usize]);
+            seen[b as usize] = true;
+        }
+    }
+
+    #[test]
+    fn aes_256() {
+        // FIPS-197 C.3, checks the block cipher, which the keystream is built from
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let mut block = GenericArray::from(std::array::from_fn::<u8, 16, _>(|i| (i * 0x11) as u8));
+        Aes256::new(&key.into()).encrypt_block(&mut block);
+        assert_eq!(
+            block.as_slice(),
+            [
+                0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49,
+                0x60, 0x89
+            ]
+        );
+    }
+
+    /// Transcription of the OdinMS reference(`MapleAESOFB`, `MapleCustomEncryption` and
+    /// `BitTools`), which keeps the Java byte and int arithmetic
+    mod odin {
+        use super::*;
+
+        fn roll_left(b: i8, count: i32) -> i8 {
+            let tmp = (b as i32 & 0xff) << (count % 8);
+            ((tmp & 0xff) | (tmp >> 8)) as i8
+        }
+
+        fn roll_right(b: i8, count: i32) -> i8 {
+            let tmp = ((b as i32 & 0xff) << 8) as u32 >> (count % 8);
+            ((tmp & 0xff) | (tmp >> 8)) as i8
+        }
+
+        fn funny_shit(input: i8, iv: &mut [i8; 4]) {
+            let table = |b: i8| SHUFFLE[(b as i32 & 0xff) as usize] as i8;
+            let mut elina = iv[1];
+            let anna = input;
+            let mut moritz = table(elina);
+            moritz = moritz.wrapping_sub(input);
+            iv[0] = iv[0].wrapping_add(moritz);
+            moritz = iv[2];
+            moritz ^= table(anna);
+            elina = (elina as i32 - (moritz as i32 & 0xff)) as i8;
+            iv[1] = elina;
+            moritz = iv[3];
+            elina = moritz;
+            elina = (elina as i32 - (iv[0] as i32 & 0xff)) as i8;
+            moritz = table(moritz);
+            moritz = moritz.wrapping_add(input);
+            moritz ^= iv[2];
+            iv[2] = moritz;
+            elina = (elina as i32 + (table(anna) as i32 & 0xff)) as i8;
+            iv[3] = elina;
+
+            let mut merry = iv[0] as i32 & 0xff;
+            merry |= ((iv[1] as i32) << 8) & 0xff00;
+            merry |= ((iv[2] as i32) << 16) & 0xff0000;
+            merry |= ((iv[3] as i32) << 24) & 0xff000000u32 as i32;
+            let ret = ((merry as u32 >> 0x1d) as i32) | (merry << 3);
+            for (i, b) in iv.iter_mut().enumerate() {
+                *b = ((ret as u32 >> (8 * i)) & 0xff) as i8;
+            }
+        }
+
+        pub fn get_new_iv(old: [u8; 4]) -> [u8; 4] {
+            let mut iv = [0xf2u8 as i8, 0x53, 0x50, 0xc6u8 as i8];
+            for b in old {
+                funny_shit(b as i8, &mut iv);
+            }
+            iv.map(|b| b as u8)
+        }
+
+        pub fn encrypt_data(data: &mut [u8]) {
+            for j in 0..6 {
+                let mut remember = 0i8;
+                let mut data_length = (data.len() & 0xff) as i8;
+                if j % 2 == 0 {
+                    for b in data.iter_mut() {
+                        let mut cur = roll_left(*b as i8, 3);
+                        cur = cur.wrapping_add(data_length);
+                        cur ^= remember;
+                        remember = cur;
+                        cur = roll_right(cur, data_length as i32 & 0xff);
+                        cur = (!cur as i32 & 0xff) as i8;
+                        cur = cur.wrapping_add(0x48);
+                        data_length = data_length.wrapping_sub(1);
+                        *b = cur as u8;
+                    }
+                } else {
+                    for b in data.iter_mut().rev() {
+                        let mut cur = roll_left(*b as i8, 4);
+                        cur = cur.wrapping_add(data_length);
+                        cur ^= remember;
+                        remember = cur;
+                        cur ^= 0x13;
+                        cur = roll_right(cur, 3);
+                        data_length = data_length.wrapping_sub(1);
+                        *b = cur as u8;
+                    }
+                }
+            }
+        }
+
+        pub fn crypt(iv: [u8; 4], data: &mut [u8]) {
+            let aes = Aes256::new(&AES_KEY.into());
+            let mut remaining = data.len();
+            let mut llength = 0x5b0;
+            let mut start = 0;
+            while remaining > 0 {
+                let mut my_iv = GenericArray::from(std::array::from_fn::<u8, 16, _>(|i| iv[i % 4]));
+                if remaining < llength {
+                    llength = remaining;
+                }
+                for x in start..start + llength {
+                    if (x - start) % 16 == 0 {
+                        aes.encrypt_block(&mut my_iv);
+                    }
+                    data[x] ^= my_iv[(x - start) % 16];
+                }
+                start += llength;
+                remaining -= llength;
+                llength = 0x5b4;
+            }
+        }
+
+        pub fn get_packet_header(iv: [u8; 4], version: i16, length: i32) -> [u8; 4] {
+            let version = ((version >> 8) & 0xff) as i32 | ((version as i32) << 8) & 0xff00;
+            let mut iiv = (iv[3] as i32) & 0xff;
+            iiv |= ((iv[2] as i32) << 8) & 0xff00;
+            iiv ^= version;
+            let mlength = ((length << 8) & 0xff00) | (length as u32 >> 8) as i32;
+            let xored_iv = iiv ^ mlength;
+            [
+                ((iiv as u32 >> 8) & 0xff) as u8,
+                (iiv & 0xff) as u8,
+                ((xored_iv as u32 >> 8) & 0xff) as u8,
+                (xored_iv & 0xff) as u8,
+            ]
+        }
+    }
+
+    #[test]
+    fn reference() {
+        let mut rng = 0x2545f491u32;
+        let mut next = || {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            rng
+        };
+
+        for _ in 0..1000 {
+            let iv = next().to_le_bytes();
+            assert_eq!(shuffle_iv(iv), odin::get_new_iv(iv), "iv {iv:02x?}");
+        }
+
+        for len in [
+            0usize, 1, 2, 7, 16, 255, 256, 257, 1455, 1456, 1457, 1460, 3000, 4000,
+        ] {
+            let data = (0..len).map(|_| next() as u8).collect::<Vec<_>>();
+            let iv = next().to_le_bytes();
+
+            let mut expected = data.clone();
+            odin::encrypt_data(&mut expected);
+            let mut shanda = data.clone();
+            shanda_encrypt(&mut shanda);
+            assert_eq!(shanda, expected, "shanda {len}");
+
+            odin::crypt(iv, &mut expected);
+            let mut header = odin::get_packet_header(iv, 95, len as i32).to_vec();
+            header.extend(expected);
+            let mut cipher = PacketCipher::client(iv, 95, true);
+            assert_eq!(cipher.encrypt(&data), header, "frame {len}");
+
+            // The server sends with the version 0xffff - version
+            let cipher = PacketCipher::server(iv, 95, false);
+            let header = odin::get_packet_header(iv, (0xffff - 95) as i16, len as i32);
+            assert_eq!(cipher.encode_header(len as u16), header, "header {len}");
+        }
+    }
+
+    #[test]
+    fn vectors() {
+        // Known answers, which were checked against the reference above
+        assert_eq!(shuffle_iv([0x46, 0x72, 0x7a, 0x52]), SHUFFLED_IV);
+
+        let mut data = *b"\x01\x00shroom";
+        shanda_encrypt(&mut data);
+        assert_eq!(data, SHANDA);
+        shanda_decrypt(&mut data);
+        assert_eq!(&data, b"\x01\x00shroom");
+
+        let mut cipher = PacketCipher::client([0x46, 0x72, 0x7a, 0x52], 95, true);
+        let frame = cipher.encrypt(b"\x01\x00shroom");
+        assert_eq!(frame, FRAME);
+    }
+
+    const SHUFFLED_IV: [u8; 4] = [0x6d, 0xc0, 0x51, 0x75];
+    const SHANDA: [u8; 8] = [0x3d, 0x1d, 0x28, 0x6b, 0xb8, 0xf5, 0x6e, 0xad];
+    const FRAME: [u8; 12] = [
+        0x25, 0x52, 0x2d, 0x52, 0x28, 0xa3, 0xec, 0x06, 0x3b, 0x01, 0xcb, 0x0e,
+    ];
+
+    #[test]
+    fn stream_round_trip() {
+        let iv = [0x52, 0x30, 0x78, 0x73];
+        let packets = [
+            vec![0x11, 0x00],
+            (0..4000).map(|i| i as u8).collect::<Vec<_>>(),
+            b"\x0a\x00hello".to_vec(),
+        ];
+
+        for shanda in [true, false] {
+            let mut enc = PacketCipher::server(iv, 95, shanda);
+            let raw = packets
+                .iter()
+                .flat_map(|p| enc.encrypt(p))
+                .collect::<Vec<_>>();
+
+            // Split at odd positions like TCP segments
+            let mut dec = PacketDecoder::new(PacketCipher::server(iv, 95, shanda));
+            let mut out = Vec::new();
+            for chunk in raw.chunks(777) {
+                dec.push(chunk);
+                while let Some(pkt) = dec.next_packet().unwrap() {
+                    out.push(pkt);
+                }
+            }
+            assert_eq!(out, packets);
+            assert_eq!(dec.pending(), 0);
+        }
+
+        // The client cipher encodes a different version into the header
+        let mut enc = PacketCipher::client(iv, 95, true);
+        let mut dec = PacketDecoder::new(PacketCipher::server(iv, 95, true));
+        dec.push(&enc.encrypt(&packets[0]));
+        assert!(dec.next_packet().is_err());
+    }
+}
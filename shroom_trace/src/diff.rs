@@ -0,0 +1,484 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    merge::{MergedStruct, MergedTy},
+    symbols::SymbolMap,
+};
+
+/// Minimum shape similarity for matching structures with different opcodes and no symbols
+const MIN_SIMILARITY: f64 = 0.6;
+
+/// Structures of one client version
+#[derive(Debug, Clone, Copy)]
+pub struct VersionStructs<'a> {
+    pub structs: &'a [MergedStruct],
+    pub syms: &'a SymbolMap,
+}
+
+impl<'a> VersionStructs<'a> {
+    fn handler_sym(&self, strct: &MergedStruct) -> Option<&'a str> {
+        strct.handler_addr().and_then(|addr| self.syms.lookup(addr))
+    }
+
+    /// Number of structures per handler symbol, dispatchers are shared by several opcodes
+    fn sym_counts(&self) -> HashMap<&'a str, usize> {
+        let mut counts = HashMap::new();
+        for sym in self
+            .structs
+            .iter()
+            .filter_map(|strct| self.handler_sym(strct))
+        {
+            *counts.entry(sym).or_default() += 1;
+        }
+        counts
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchKind {
+    /// Same handler or encode symbol in both versions
+    Symbol(String),
+    Opcode,
+    /// Structure shape with the given similarity(1.0 is identical)
+    Shape(f64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldChange {
+    Added {
+        offset: usize,
+        ty: MergedTy,
+    },
+    Removed {
+        offset: usize,
+        ty: MergedTy,
+    },
+    Changed {
+        old_offset: usize,
+        new_offset: usize,
+        old_ty: MergedTy,
+        new_ty: MergedTy,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StructDiff {
+    Matched {
+        old_opcode: u16,
+        new_opcode: u16,
+        kind: MatchKind,
+        changes: Vec<FieldChange>,
+    },
+    OnlyOld(u16),
+    OnlyNew(u16),
+}
+
+impl StructDiff {
+    pub fn is_unchanged(&self) -> bool {
+        matches!(self, Self::Matched { old_opcode, new_opcode, changes, .. }
+            if old_opcode == new_opcode && changes.is_empty())
+    }
+}
+
+fn shape(strct: &MergedStruct) -> Vec<&MergedTy> {
+    strct.fields.iter().map(|f| &f.ty).collect()
+}
+
+/// Longest common subsequence table of two type sequences
+fn lcs_table(a: &[&MergedTy], b: &[&MergedTy]) -> Vec<Vec<usize>> {
+    let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i][j] = if a[i] == b[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+    table
+}
+
+fn similarity(a: &MergedStruct, b: &MergedStruct) -> f64 {
+    let (a, b) = (shape(a), shape(b));
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let common = lcs_table(&a, &b)[0][0];
+    2.0 * common as f64 / (a.len() + b.len()) as f64
+}
+
+/// Field changes between two structures, removals directly followed by additions are type changes
+pub fn diff_fields(old: &MergedStruct, new: &MergedStruct) -> Vec<FieldChange> {
+    let (a, b) = (shape(old), shape(new));
+    let table = lcs_table(&a, &b);
+
+    let mut changes = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let mut flush = |removed: &mut Vec<usize>, added: &mut Vec<usize>| {
+        for k in 0..removed.len().max(added.len()) {
+            let change = match (removed.get(k), added.get(k)) {
+                (Some(&i), Some(&j)) => FieldChange::Changed {
+                    old_offset: old.fields[i].offset,
+                    new_offset: new.fields[j].offset,
+                    old_ty: old.fields[i].ty.clone(),
+                    new_ty: new.fields[j].ty.clone(),
+                },
+                (Some(&i), None) => FieldChange::Removed {
+                    offset: old.fields[i].offset,
+                    ty: old.fields[i].ty.clone(),
+                },
+                (None, Some(&j)) => FieldChange::Added {
+                    offset: new.fields[j].offset,
+                    ty: new.fields[j].ty.clone(),
+                },
+                (None, None) => unreachable!(),
+            };
+            changes.push(change);
+        }
+        removed.clear();
+        added.clear();
+    };
+
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            flush(&mut removed, &mut added);
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && table[i + 1][j] >= table[i][j + 1]) {
+            removed.push(i);
+            i += 1;
+        } else {
+            added.push(j);
+            j += 1;
+        }
+    }
+    flush(&mut removed, &mut added);
+    changes
+}
+
+/// Matches the structures of two versions by unchanged opcode, unique symbol, shape and opcode, in
+/// that order
+pub fn diff_versions(old: VersionStructs, new: VersionStructs) -> Vec<StructDiff> {
+    let mut old_left = (0..old.structs.len()).map(Some).collect::<Vec<_>>();
+    let mut new_left = (0..new.structs.len()).map(Some).collect::<Vec<_>>();
+    let mut pairs = Vec::new();
+
+    let mut take =
+        |old_left: &mut Vec<Option<usize>>,
+         new_left: &mut Vec<Option<usize>>,
+         pred: &dyn Fn(&MergedStruct, &MergedStruct) -> Option<MatchKind>| {
+            for slot in old_left.iter_mut() {
+                let Some(oi) = *slot else { continue };
+                let found = new_left.iter().enumerate().find_map(|(j, ni)| {
+                    let ni = (*ni)?;
+                    pred(&old.structs[oi], &new.structs[ni]).map(|kind| (j, ni, kind))
+                });
+                if let Some((j, ni, kind)) = found {
+                    *slot = None;
+                    new_left[j] = None;
+                    pairs.push((oi, ni, kind));
+                }
+            }
+        };
+
+    // Unchanged structures keep their opcode, even when other opcodes share the shape or symbol
+    take(&mut old_left, &mut new_left, &|a, b| {
+        (a.opcode == b.opcode && shape(a) == shape(b)).then_some(MatchKind::Opcode)
+    });
+    let (old_counts, new_counts) = (old.sym_counts(), new.sym_counts());
+    take(&mut old_left, &mut new_left, &|a, b| {
+        let sym = old.handler_sym(a)?;
+        let unique = old_counts[sym] == 1 && new_counts.get(sym) == Some(&1);
+        (unique && Some(sym) == new.handler_sym(b)).then(|| MatchKind::Symbol(sym.to_string()))
+    });
+    // Identical shapes are preferred over same opcodes, to detect renumbered opcodes
+    take(&mut old_left, &mut new_left, &|a, b| {
+        (a.opcode != b.opcode && !a.fields.is_empty() && shape(a) == shape(b))
+            .then_some(MatchKind::Shape(1.0))
+    });
+    take(&mut old_left, &mut new_left, &|a, b| {
+        (a.opcode == b.opcode).then_some(MatchKind::Opcode)
+    });
+
+    // Best remaining shape matches
+    let mut candidates = Vec::new();
+    for oi in old_left.iter().flatten() {
+        for ni in new_left.iter().flatten() {
+            let sim = similarity(&old.structs[*oi], &new.structs[*ni]);
+            if sim >= MIN_SIMILARITY {
+                candidates.push((sim, *oi, *ni));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for (sim, oi, ni) in candidates {
+        let old_pos = old_left.iter().position(|i| *i == Some(oi));
+        let new_pos = new_left.iter().position(|i| *i == Some(ni));
+        if let (Some(op), Some(np)) = (old_pos, new_pos) {
+            old_left[op] = None;
+            new_left[np] = None;
+            pairs.push((oi, ni, MatchKind::Shape(sim)));
+        }
+    }
+
+    let mut diffs = pairs
+        .into_iter()
+        .map(|(oi, ni, kind)| {
+            let (a, b) = (&old.structs[oi], &new.structs[ni]);
+            StructDiff::Matched {
+                old_opcode: a.opcode,
+                new_opcode: b.opcode,
+                kind,
+                changes: diff_fields(a, b),
+            }
+        })
+        .collect::<Vec<_>>();
+    diffs.extend(
+        old_left
+            .into_iter()
+            .flatten()
+            .map(|i| StructDiff::OnlyOld(old.structs[i].opcode)),
+    );
+    diffs.extend(
+        new_left
+            .into_iter()
+            .flatten()
+            .map(|i| StructDiff::OnlyNew(new.structs[i].opcode)),
+    );
+    diffs.sort_by_key(|diff| match diff {
+        StructDiff::Matched { old_opcode, .. } | StructDiff::OnlyOld(old_opcode) => {
+            (0, *old_opcode)
+        }
+        StructDiff::OnlyNew(opcode) => (1, *opcode),
+    });
+    diffs
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added { offset, ty } => write!(f, "+ {offset:#x} {ty:?}"),
+            Self::Removed { offset, ty } => write!(f, "- {offset:#x} {ty:?}"),
+            Self::Changed {
+                old_offset,
+                new_offset,
+                old_ty,
+                new_ty,
+            } => write!(
+                f,
+                "~ {old_offset:#x} {old_ty:?} -> {new_offset:#x} {new_ty:?}"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for StructDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Matched {
+                old_opcode,
+                new_opcode,
+                kind,
+                changes,
+            } => {
+                write!(f, "{old_opcode:#06x} -> {new_opcode:#06x}")?;
+                match kind {
+                    MatchKind::Symbol(sym) => write!(f, " (symbol {sym})")?,
+                    MatchKind::Opcode => write!(f, " (opcode)")?,
+                    MatchKind::Shape(sim) => write!(f, " (shape {:.0}%)", sim * 100.0)?,
+                }
+                if old_opcode != new_opcode {
+                    write!(f, " renumbered")?;
+                }
+                if changes.is_empty() {
+                    write!(f, " unchanged")?;
+                }
+                writeln!(f)?;
+                for change in changes {
+                    writeln!(f, "  {change}")?;
+                }
+                Ok(())
+            }
+            Self::OnlyOld(opcode) => writeln!(f, "{opcode:#06x} removed"),
+            Self::OnlyNew(opcode) => writeln!(f, "{opcode:#06x} added"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{merge::MergedField, PacketDir};
+
+    use super::*;
+
+    fn strct(opcode: u16, tys: &[MergedTy], handler: usize) -> MergedStruct {
+        let mut offset = 2;
+        let fields = tys
+            .iter()
+            .map(|ty| {
+                let field = MergedField {
+                    offset,
+                    ret_address: handler,
+                    call_stack: Vec::new(),
+                    ty: ty.clone(),
+                };
+                offset += match ty {
+                    MergedTy::I8 => 1,
+                    MergedTy::I16 => 2,
+                    _ => 4,
+                };
+                field
+            })
+            .collect();
+        MergedStruct {
+            dir: PacketDir::Recv,
+            opcode,
+            observations: 1,
+            fields,
+            diverges_at: None,
+            send_ret_addr: None,
+            incomplete: 0,
+            exception_offsets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn renumbered_and_changed() {
+        use MergedTy::*;
+        let old = [
+            strct(0x10, &[I32, I8, I16, I32], 0x100),
+            strct(0x11, &[I8, I8], 0x200),
+            strct(0x12, &[I16], 0x300),
+        ];
+        let new = [
+            // 0x10 moved to 0x20 and gained a field
+            strct(0x20, &[I32, I8, I8, I16, I32], 0x1100),
+            // 0x11 moved to 0x21 unchanged
+            strct(0x21, &[I8, I8], 0x1200),
+            strct(0x12, &[I32], 0x1300),
+        ];
+        let mut old_syms = SymbolMap::new();
+        old_syms.insert(0x100, "CField::OnFoo");
+        old_syms.insert(0x180, "CField::OnBar");
+        let mut new_syms = SymbolMap::new();
+        new_syms.insert(0x1100, "CField::OnFoo");
+        new_syms.insert(0x1180, "CField::OnBaz");
+
+        let diffs = diff_versions(
+            VersionStructs {
+                structs: &old,
+                syms: &old_syms,
+            },
+            VersionStructs {
+                structs: &new,
+                syms: &new_syms,
+            },
+        );
+
+        assert_eq!(
+            diffs[0],
+            StructDiff::Matched {
+                old_opcode: 0x10,
+                new_opcode: 0x20,
+                kind: MatchKind::Symbol("CField::OnFoo".to_string()),
+                changes: vec![FieldChange::Added { offset: 7, ty: I8 }],
+            }
+        );
+        assert_eq!(
+            diffs[1],
+            StructDiff::Matched {
+                old_opcode: 0x11,
+                new_opcode: 0x21,
+                kind: MatchKind::Shape(1.0),
+                changes: vec![],
+            }
+        );
+        assert_eq!(
+            diffs[2],
+            StructDiff::Matched {
+                old_opcode: 0x12,
+                new_opcode: 0x12,
+                kind: MatchKind::Opcode,
+                changes: vec![FieldChange::Changed {
+                    old_offset: 2,
+                    new_offset: 2,
+                    old_ty: I16,
+                    new_ty: I32,
+                }],
+            }
+        );
+        assert_eq!(diffs.len(), 3);
+    }
+
+    #[test]
+    fn same_shape_keeps_opcode() {
+        use MergedTy::*;
+        let old = [
+            strct(0x30, &[I8, I16], 0x100),
+            strct(0x31, &[I8, I16], 0x200),
+        ];
+        let new = [
+            strct(0x30, &[I8, I16], 0x100),
+            strct(0x31, &[I8, I16], 0x200),
+        ];
+        let syms = SymbolMap::new();
+        let version = |structs| VersionStructs {
+            structs,
+            syms: &syms,
+        };
+
+        let diffs = diff_versions(version(&old), version(&new));
+        let pairs = diffs
+            .iter()
+            .map(|diff| match diff {
+                StructDiff::Matched {
+                    old_opcode,
+                    new_opcode,
+                    kind,
+                    ..
+                } => (*old_opcode, *new_opcode, kind.clone()),
+                diff => panic!("Expected a match: {diff:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pairs,
+            [
+                (0x30, 0x30, MatchKind::Opcode),
+                (0x31, 0x31, MatchKind::Opcode)
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_dispatcher_symbol() {
+        use MergedTy::*;
+        // Both opcodes are decoded by the same dispatcher, the new version lists them swapped
+        let old = [
+            strct(0xa0, &[I32, I8], 0x100),
+            strct(0xa1, &[I32, I16], 0x100),
+        ];
+        let new = [
+            strct(0xa1, &[I32, I16], 0x1100),
+            strct(0xa0, &[I32, I8], 0x1100),
+        ];
+        let mut old_syms = SymbolMap::new();
+        old_syms.insert(0x100, "CField::OnPacket");
+        let mut new_syms = SymbolMap::new();
+        new_syms.insert(0x1100, "CField::OnPacket");
+
+        let diffs = diff_versions(
+            VersionStructs {
+                structs: &old,
+                syms: &old_syms,
+            },
+            VersionStructs {
+                structs: &new,
+                syms: &new_syms,
+            },
+        );
+        assert_eq!(diffs.len(), 2);
+        assert!(diffs.iter().all(StructDiff::is_unchanged), "{diffs:?}");
+    }
+}
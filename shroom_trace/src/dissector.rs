@@ -0,0 +1,338 @@
+//! Wireshark Lua dissector generation.
+//!
+//! The dissector is registered for the `USER0` link type, every frame is a single packet
+//! prefixed with its direction: one byte(0 = Send, 1 = Recv) followed by the opcode and payload.
+//! `pcap` writes traces in this format.
+
+use std::fmt::Write;
+
+use crate::{
+    merge::{MergedStruct, MergedTy},
+    schema::{ArrayLen, FieldSchema, FieldTy, PacketSchema, SchemaFile},
+    symbols::{sym_to_camel, sym_to_snake, SymbolMap},
+    PacketDir,
+};
+
+const HEADER: &str = r#"-- Generated by shroom_trace, do not edit
+-- Frames(link type USER0) contain a direction byte(0 = Send, 1 = Recv), the opcode and the payload
+
+local proto = Proto("shroom", "Shroom packet")
+
+local f_dir = ProtoField.uint8("shroom.dir", "Direction", base.DEC, { [0] = "Send", [1] = "Recv" })
+local f_opcode = ProtoField.uint16("shroom.opcode", "Opcode", base.HEX)
+local f_trailing = ProtoField.bytes("shroom.trailing", "Trailing")
+local f = {}
+"#;
+
+const FOOTER: &str = r#"
+local all_fields = { f_dir, f_opcode, f_trailing }
+for _, field in pairs(f) do
+    all_fields[#all_fields + 1] = field
+end
+proto.fields = all_fields
+
+function proto.dissector(buf, pinfo, tree)
+    if buf:len() < 3 then
+        return 0
+    end
+
+    pinfo.cols.protocol = "Shroom"
+    local dir = buf(0, 1):uint()
+    local opcode = buf(1, 2):le_uint()
+    local sub = tree:add(proto, buf(), "Shroom packet")
+    sub:add(f_dir, buf(0, 1))
+    sub:add_le(f_opcode, buf(1, 2))
+
+    local entry = dissectors[dir] and dissectors[dir][opcode]
+    if entry == nil then
+        pinfo.cols.info = string.format("Unknown 0x%04X", opcode)
+        return buf:len()
+    end
+
+    pinfo.cols.info = entry.name
+    sub:append_text(" " .. entry.name)
+    local off = entry.fn(buf, sub, 3)
+    if off < buf:len() then
+        sub:add(f_trailing, buf(off))
+    end
+    return buf:len()
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, proto)
+"#;
+
+pub(crate) fn dir_id(dir: PacketDir) -> u8 {
+    match dir {
+        PacketDir::Send => 0,
+        PacketDir::Recv => 1,
+    }
+}
+
+fn dir_key(dir: PacketDir) -> &'static str {
+    match dir {
+        PacketDir::Send => "send",
+        PacketDir::Recv => "recv",
+    }
+}
+
+fn lua_str(s: &str) -> String {
+    format!("{s:?}")
+}
+
+fn proto_field(ty: FieldTy) -> Option<(&'static str, &'static str)> {
+    Some(match ty {
+        FieldTy::U8 => ("uint8", ", base.DEC"),
+        FieldTy::U16 => ("uint16", ", base.DEC"),
+        FieldTy::U32 => ("uint32", ", base.DEC"),
+        FieldTy::U64 => ("uint64", ", base.DEC"),
+        FieldTy::Str => ("string", ""),
+        FieldTy::Buf(_) => ("bytes", ""),
+        FieldTy::Array => return None,
+    })
+}
+
+fn write_proto_fields(out: &mut String, prefix: &str, fields: &[FieldSchema]) {
+    for field in fields {
+        let key = format!("{prefix}.{}", sym_to_snake(&field.name));
+        match proto_field(field.ty) {
+            Some((ty, base)) => writeln!(
+                out,
+                "f[{key}] = ProtoField.{ty}({abbrev}, {name}{base})",
+                key = lua_str(&key),
+                abbrev = lua_str(&format!("shroom.{key}")),
+                name = lua_str(&field.name),
+            )
+            .unwrap(),
+            None => write_proto_fields(out, &key, &field.fields),
+        }
+    }
+}
+
+struct FieldWriter<'a> {
+    out: &'a mut String,
+}
+
+impl FieldWriter<'_> {
+    fn line(&mut self, indent: usize, s: &str) {
+        writeln!(self.out, "{}{s}", "    ".repeat(indent)).unwrap();
+    }
+
+    fn fields(&mut self, indent: usize, depth: usize, prefix: &str, fields: &[FieldSchema]) {
+        let v = format!("v{depth}");
+        for field in fields {
+            let key = lua_str(&format!("{prefix}.{}", sym_to_snake(&field.name)));
+            let name = lua_str(&field.name);
+
+            let mut indent = indent;
+            if let Some(cond) = &field.cond {
+                let (op, value) = match (cond.eq, cond.ne) {
+                    (Some(eq), _) => ("==", eq),
+                    (None, Some(ne)) => ("~=", ne),
+                    (None, None) => ("~=", 0),
+                };
+                self.line(
+                    indent,
+                    &format!("if {v}[{}] {op} {value} then", lua_str(&cond.field)),
+                );
+                indent += 1;
+            }
+
+            match field.ty {
+                FieldTy::U8 | FieldTy::U16 | FieldTy::U32 | FieldTy::U64 => {
+                    let (n, read) = match field.ty {
+                        FieldTy::U8 => (1, "le_uint()"),
+                        FieldTy::U16 => (2, "le_uint()"),
+                        FieldTy::U32 => (4, "le_uint()"),
+                        _ => (8, "le_uint64():tonumber()"),
+                    };
+                    self.line(indent, &format!("tree:add_le(f[{key}], buf(off, {n}))"));
+                    self.line(indent, &format!("{v}[{name}] = buf(off, {n}):{read}"));
+                    self.line(indent, &format!("off = off + {n}"));
+                }
+                FieldTy::Str => {
+                    self.line(indent, "do");
+                    self.line(indent + 1, "local len = buf(off, 2):le_uint()");
+                    self.line(indent + 1, "if len > 0 then");
+                    self.line(
+                        indent + 2,
+                        &format!("tree:add(f[{key}], buf(off + 2, len))"),
+                    );
+                    self.line(indent + 1, "else");
+                    self.line(
+                        indent + 2,
+                        &format!("tree:add(f[{key}], buf(off, 2), \"\")"),
+                    );
+                    self.line(indent + 1, "end");
+                    self.line(indent + 1, "off = off + 2 + len");
+                    self.line(indent, "end");
+                }
+                FieldTy::Buf(0) => {}
+                FieldTy::Buf(n) => {
+                    self.line(indent, &format!("tree:add(f[{key}], buf(off, {n}))"));
+                    self.line(indent, &format!("off = off + {n}"));
+                }
+                FieldTy::Array => {
+                    let len = match &field.len {
+                        Some(ArrayLen::Fixed(n)) => n.to_string(),
+                        Some(ArrayLen::Field(len_field)) => format!("{v}[{}]", lua_str(len_field)),
+                        None => "0".to_string(),
+                    };
+                    let i = format!("i{depth}");
+                    let inner = format!("v{}", depth + 1);
+                    let prefix = format!("{prefix}.{}", sym_to_snake(&field.name));
+                    self.line(indent, &format!("for {i} = 1, {len} do"));
+                    self.line(
+                        indent + 1,
+                        &format!(
+                            "local tree = tree:add(proto, {} .. \"[\" .. ({i} - 1) .. \"]\")",
+                            lua_str(&field.name)
+                        ),
+                    );
+                    self.line(
+                        indent + 1,
+                        &format!("local {inner} = setmetatable({{}}, {{ __index = {v} }})"),
+                    );
+                    self.fields(indent + 1, depth + 1, &prefix, &field.fields);
+                    self.line(indent, "end");
+                }
+            }
+
+            if field.cond.is_some() {
+                self.line(indent - 1, "end");
+            }
+        }
+    }
+}
+
+fn packet_prefix(packet: &PacketSchema) -> String {
+    format!("{}.{}", dir_key(packet.dir), sym_to_snake(&packet.name))
+}
+
+/// Generates a dissector for all packets of the schema, the output only depends on the schema
+pub fn gen_lua_dissector(schema: &SchemaFile) -> String {
+    let mut packets = schema.packets.iter().collect::<Vec<_>>();
+    packets.sort_by_key(|p| (dir_id(p.dir), p.opcode));
+
+    let mut out = String::from(HEADER);
+    for packet in &packets {
+        writeln!(out).unwrap();
+        write_proto_fields(&mut out, &packet_prefix(packet), &packet.fields);
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "local dissectors = {{ [0] = {{}}, [1] = {{}} }}").unwrap();
+    for packet in &packets {
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "dissectors[{}][0x{:04X}] = {{ name = {}, fn = function(buf, tree, off)",
+            dir_id(packet.dir),
+            packet.opcode,
+            lua_str(&packet.name)
+        )
+        .unwrap();
+        let mut writer = FieldWriter { out: &mut out };
+        writer.line(1, "local v0 = {}");
+        writer.fields(1, 0, &packet_prefix(packet), &packet.fields);
+        writer.line(1, "return off");
+        writeln!(out, "end }}").unwrap();
+    }
+
+    out.push_str(FOOTER);
+    out
+}
+
+fn merged_field_ty(ty: &MergedTy) -> Option<FieldTy> {
+    Some(match ty {
+        MergedTy::I8 => FieldTy::U8,
+        MergedTy::I16 => FieldTy::U16,
+        MergedTy::I32 | MergedTy::Pos => FieldTy::U32,
+        MergedTy::I64 | MergedTy::Time => FieldTy::U64,
+        MergedTy::F64 => FieldTy::Buf(8),
+        MergedTy::Str => FieldTy::Str,
+        MergedTy::Buf(n) | MergedTy::Gap(n) => FieldTy::Buf(*n as usize),
+        MergedTy::VarBuf | MergedTy::VarGap => return None,
+    })
+}
+
+/// Schema of inferred structures, fields after a variable length buffer are left as trailing data
+pub fn schema_from_merged(structs: &[MergedStruct], syms: &SymbolMap) -> SchemaFile {
+    let packets = structs
+        .iter()
+        .map(|strct| {
+            let name = match strct.handler_addr().and_then(|addr| syms.lookup(addr)) {
+                Some(sym) => format!("{}{:04X}", sym_to_camel(sym), strct.opcode),
+                None => format!("{:?}Op{:04X}", strct.dir, strct.opcode),
+            };
+            let fields = strct
+                .fields
+                .iter()
+                .map_while(|field| {
+                    Some(FieldSchema {
+                        name: format!("field_{:x}", field.offset),
+                        ty: merged_field_ty(&field.ty)?,
+                        cond: None,
+                        len: None,
+                        fields: Vec::new(),
+                    })
+                })
+                .collect();
+
+            PacketSchema {
+                name,
+                dir: strct.dir,
+                opcode: strct.opcode,
+                fields,
+            }
+        })
+        .collect();
+
+    SchemaFile { packets }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+[[packet]]
+name = "UserChat"
+dir = "Recv"
+opcode = 0x7a
+fields = [
+    { name = "char_id", ty = "u32" },
+    { name = "msg", ty = "str" },
+]
+
+[[packet]]
+name = "CheckPassword"
+dir = "Send"
+opcode = 0x1
+fields = [
+    { name = "user", ty = "str" },
+    { name = "machine_id", ty = "buf(16)" },
+    { name = "count", ty = "u8" },
+    { name = "items", ty = "array", len = "count", fields = [{ name = "id", ty = "u32" }] },
+    { name = "extra", ty = "u16", cond = { field = "count", ne = 0 } },
+]
+"#;
+
+    const SNAPSHOT: &str = include_str!("snapshots/dissector.lua");
+
+    #[test]
+    fn dissector_snapshot() {
+        let schema = SchemaFile::from_toml(SCHEMA).unwrap();
+        let lua = gen_lua_dissector(&schema);
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/snapshots/dissector.lua");
+            std::fs::write(path, &lua).unwrap();
+            return;
+        }
+        assert_eq!(lua, SNAPSHOT);
+
+        // The order of the schema must not matter
+        let mut reversed = schema.clone();
+        reversed.packets.reverse();
+        assert_eq!(gen_lua_dissector(&reversed), lua);
+    }
+}
@@ -0,0 +1,422 @@
+//! Deterministic mutation of received packets.
+//!
+//! Every mutated packet gets its own case seed from the session seed, the mutations only depend on
+//! the case seed, the packet data and the known layout of the opcode. So a logged case can be
+//! reproduced with [`gen_mutations`] or replayed with [`apply_mutations`].
+//! The opcode is never mutated.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::packet_struct::{PacketStruct, PacketStructElem, PacketStructTy};
+
+const OPCODE_LEN: usize = 2;
+const MAX_EXTEND: usize = 16;
+
+/// SplitMix64, small and stable across versions, unlike the generators of external crates
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Value in `0..n`, n must not be zero
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Value in `lo..hi`, the range must not be empty
+    pub fn range(&mut self, lo: usize, hi: usize) -> usize {
+        lo + self.below(hi - lo)
+    }
+
+    /// True with the probability p
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64) < p * (1u64 << 53) as f64
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Single change of the packet data, offsets include the opcode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mutation {
+    BitFlip {
+        offset: usize,
+        bit: u8,
+    },
+    /// Little endian boundary integer over a field of `size` bytes
+    Boundary {
+        offset: usize,
+        size: u8,
+        value: u64,
+    },
+    /// Length prefix of a string
+    StrLen {
+        offset: usize,
+        len: u16,
+    },
+    Truncate {
+        len: usize,
+    },
+    Extend {
+        bytes: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Mutation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mutation::BitFlip { offset, bit } => write!(f, "bitflip {offset:#x}:{bit}"),
+            Mutation::Boundary {
+                offset,
+                size,
+                value,
+            } => write!(f, "boundary {offset:#x} = {value:#x}({size} bytes)"),
+            Mutation::StrLen { offset, len } => write!(f, "strlen {offset:#x} = {len:#x}"),
+            Mutation::Truncate { len } => write!(f, "truncate to {len}"),
+            Mutation::Extend { bytes } => write!(f, "extend by {}", bytes.len()),
+        }
+    }
+}
+
+impl Mutation {
+    fn apply(&self, data: &mut Vec<u8>) {
+        match *self {
+            Mutation::BitFlip { offset, bit } => {
+                if let Some(b) = data.get_mut(offset) {
+                    *b ^= 1 << bit;
+                }
+            }
+            Mutation::Boundary {
+                offset,
+                size,
+                value,
+            } => {
+                let size = size as usize;
+                if let Some(field) = data.get_mut(offset..offset + size) {
+                    field.copy_from_slice(&value.to_le_bytes()[..size]);
+                }
+            }
+            Mutation::StrLen { offset, len } => {
+                if let Some(field) = data.get_mut(offset..offset + 2) {
+                    field.copy_from_slice(&len.to_le_bytes());
+                }
+            }
+            Mutation::Truncate { len } => data.truncate(len.max(OPCODE_LEN)),
+            Mutation::Extend { ref bytes } => data.extend_from_slice(bytes),
+        }
+    }
+}
+
+/// Applies logged mutations in order
+pub fn apply_mutations(data: &mut Vec<u8>, mutations: &[Mutation]) {
+    for mutation in mutations {
+        mutation.apply(data);
+    }
+}
+
+fn boundary_values(size: usize) -> [u64; 5] {
+    let bits = size as u32 * 8;
+    let max = u64::MAX >> (64 - bits);
+    let sign = 1u64 << (bits - 1);
+    [0, 1, sign - 1, sign, max]
+}
+
+fn gen_mutation(rng: &mut Rng, data: &[u8], layout: &[PacketStructElem]) -> Option<Mutation> {
+    // Only fields, which are still fully present, are targeted
+    let fields = layout
+        .iter()
+        .filter(|elem| elem.offset() >= OPCODE_LEN && elem.offset() + elem.byte_len() <= data.len())
+        .collect::<Vec<_>>();
+    let ints = fields
+        .iter()
+        .filter(|elem| {
+            matches!(
+                elem.ty(),
+                PacketStructTy::I8
+                    | PacketStructTy::I16
+                    | PacketStructTy::I32
+                    | PacketStructTy::I64
+            )
+        })
+        .collect::<Vec<_>>();
+    let strs = fields
+        .iter()
+        .filter(|elem| matches!(elem.ty(), PacketStructTy::Str(_)))
+        .collect::<Vec<_>>();
+    let has_body = data.len() > OPCODE_LEN;
+
+    Some(match rng.below(5) {
+        0 if !ints.is_empty() => {
+            let elem = rng.pick(&ints);
+            let size = elem.byte_len();
+            Mutation::Boundary {
+                offset: elem.offset(),
+                size: size as u8,
+                value: *rng.pick(&boundary_values(size)),
+            }
+        }
+        1 if !strs.is_empty() => {
+            let elem = rng.pick(&strs);
+            let cur = elem.byte_len() as u16 - 2;
+            Mutation::StrLen {
+                offset: elem.offset(),
+                len: *rng.pick(&[0, cur.wrapping_add(1), 0x7fff, 0x8000, 0xffff]),
+            }
+        }
+        2 if has_body => Mutation::Truncate {
+            len: rng.range(OPCODE_LEN, data.len()),
+        },
+        3 => Mutation::Extend {
+            bytes: (0..rng.range(1, MAX_EXTEND + 1))
+                .map(|_| rng.next_u64() as u8)
+                .collect(),
+        },
+        _ if has_body => Mutation::BitFlip {
+            offset: rng.range(OPCODE_LEN, data.len()),
+            bit: rng.below(8) as u8,
+        },
+        _ => return None,
+    })
+}
+
+/// Generates the mutations of a case, the layout are the traced elements of the opcode
+pub fn gen_mutations(
+    seed: u64,
+    data: &[u8],
+    layout: &[PacketStructElem],
+    max_mutations: usize,
+) -> Vec<Mutation> {
+    let mut rng = Rng::new(seed);
+    let mut data = data.to_vec();
+    let n = rng.range(1, max_mutations.max(1) + 1);
+
+    // Mutations are generated against the already mutated data, so they replay in order
+    let mut mutations = Vec::with_capacity(n);
+    for _ in 0..n {
+        if let Some(mutation) = gen_mutation(&mut rng, &data, layout) {
+            mutation.apply(&mut data);
+            mutations.push(mutation);
+        }
+    }
+    mutations
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FuzzCfg {
+    /// Seed of the session, the same seed and traffic yield the same cases
+    pub seed: u64,
+    /// Receive opcodes, which are mutated, every opcode If It's empty
+    #[serde(default)]
+    pub opcodes: Vec<u16>,
+    /// Share of the selected packets, which are mutated
+    #[serde(default = "FuzzCfg::default_rate")]
+    pub rate: f64,
+    #[serde(default = "FuzzCfg::default_max_mutations")]
+    pub max_mutations: usize,
+}
+
+impl FuzzCfg {
+    fn default_rate() -> f64 {
+        1.0
+    }
+
+    fn default_max_mutations() -> usize {
+        3
+    }
+}
+
+/// Mutated packet, which can be reproduced from the original data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FuzzCase {
+    /// Number of the case in the session
+    pub index: u64,
+    pub seed: u64,
+    pub opcode: u16,
+    pub mutations: Vec<Mutation>,
+}
+
+impl fmt::Display for FuzzCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "case {} opcode {:#06x} seed {:#x}:",
+            self.index, self.opcode, self.seed
+        )?;
+        for (i, mutation) in self.mutations.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{sep}{mutation}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Mutates selected packets, the layouts are learned from the traced structures
+#[derive(Debug, Clone)]
+pub struct Fuzzer {
+    cfg: FuzzCfg,
+    rng: Rng,
+    layouts: BTreeMap<u16, Vec<PacketStructElem>>,
+    cases: u64,
+}
+
+impl Fuzzer {
+    pub fn new(cfg: FuzzCfg) -> Self {
+        Self {
+            rng: Rng::new(cfg.seed),
+            cfg,
+            layouts: BTreeMap::new(),
+            cases: 0,
+        }
+    }
+
+    /// Remembers the layout of a complete structure
+    pub fn learn(&mut self, strct: &PacketStruct) {
+        let Some(opcode) = strct.opcode() else {
+            return;
+        };
+        if strct.is_incomplete() {
+            return;
+        }
+
+        let elems = strct
+            .elements()
+            .iter()
+            .filter(|elem| !elem.is_gap())
+            .cloned()
+            .collect();
+        self.layouts.insert(opcode, elems);
+    }
+
+    pub fn layout(&self, opcode: u16) -> &[PacketStructElem] {
+        self.layouts.get(&opcode).map_or(&[], Vec::as_slice)
+    }
+
+    fn is_selected(&self, opcode: u16) -> bool {
+        self.cfg.opcodes.is_empty() || self.cfg.opcodes.contains(&opcode)
+    }
+
+    /// Mutates the packet data(including the opcode), If the opcode is selected
+    pub fn fuzz(&mut self, data: &mut Vec<u8>) -> Option<FuzzCase> {
+        let opcode = u16::from_le_bytes(data.get(..OPCODE_LEN)?.try_into().unwrap());
+        if !self.is_selected(opcode) || !self.rng.chance(self.cfg.rate) {
+            return None;
+        }
+
+        let seed = self.rng.next_u64();
+        let mutations = gen_mutations(seed, data, self.layout(opcode), self.cfg.max_mutations);
+        apply_mutations(data, &mutations);
+        self.cases += 1;
+        Some(FuzzCase {
+            index: self.cases,
+            seed,
+            opcode,
+            mutations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(seed: u64) -> FuzzCfg {
+        FuzzCfg {
+            seed,
+            opcodes: vec![0x7a],
+            rate: 1.0,
+            max_mutations: 3,
+        }
+    }
+
+    // Opcode 0x7a, char id and a 5 character message
+    fn chat() -> (Vec<u8>, PacketStruct) {
+        let mut data = vec![0x7a, 0x00, 1, 2, 3, 4, 5, 0];
+        data.extend_from_slice(b"hello");
+        let mut strct = PacketStruct::new_recv();
+        strct.add_elem(PacketStructElem::new(0, 0x1000, PacketStructTy::I16));
+        strct.add_elem(PacketStructElem::new(2, 0x2000, PacketStructTy::I32));
+        strct.add_elem(PacketStructElem::new(6, 0x2010, PacketStructTy::Str(5)));
+        strct.set_opcode(0x7a);
+        (data, strct)
+    }
+
+    fn run(seed: u64) -> Vec<(Vec<u8>, FuzzCase)> {
+        let (data, strct) = chat();
+        let mut fuzzer = Fuzzer::new(cfg(seed));
+        fuzzer.learn(&strct);
+        (0..64)
+            .map(|_| {
+                let mut data = data.clone();
+                let case = fuzzer.fuzz(&mut data).unwrap();
+                (data, case)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn reproduce_case() {
+        let (original, strct) = chat();
+        for (data, case) in run(7) {
+            assert_eq!(&data[..2], &original[..2], "opcode mutated: {case}");
+            assert!(!case.mutations.is_empty());
+
+            // From the seed and the layout
+            let mutations = gen_mutations(case.seed, &original, strct.elements(), 3);
+            assert_eq!(mutations, case.mutations);
+
+            // From the logged mutations
+            let mut replayed = original.clone();
+            apply_mutations(&mut replayed, &case.mutations);
+            assert_eq!(replayed, data);
+        }
+    }
+
+    #[test]
+    fn layout_guided() {
+        let cases = run(3);
+        let mutations = cases.iter().flat_map(|(_, case)| &case.mutations);
+        let mut boundary = false;
+        let mut strlen = false;
+        for mutation in mutations {
+            match mutation {
+                Mutation::Boundary { offset, size, .. } => {
+                    assert_eq!((*offset, *size), (2, 4));
+                    boundary = true;
+                }
+                Mutation::StrLen { offset, .. } => {
+                    assert_eq!(*offset, 6);
+                    strlen = true;
+                }
+                _ => (),
+            }
+        }
+        assert!(boundary && strlen);
+
+        // Other opcodes are not touched
+        let mut fuzzer = Fuzzer::new(cfg(3));
+        let mut other = vec![0x7b, 0x00, 1];
+        assert!(fuzzer.fuzz(&mut other).is_none());
+        assert_eq!(other, [0x7b, 0x00, 1]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Opcode to handler map.
+//!
+//! Sent packets are attributed to the return address of the send call, which lies in the encoding
+//! function. Received packets are attributed to the first decode call after the opcode, which lies
+//! in the handler. The map is kept across sessions by loading and merging the previous file.
+
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{packet_struct::PacketStruct, PacketDir};
+
+/// Client function, which encodes or handles an opcode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandlerEntry {
+    pub dir: PacketDir,
+    pub opcode: u16,
+    pub addr: usize,
+    /// Symbol of the address, like `CField::OnPacket+0x1a`
+    #[serde(default)]
+    pub symbol: Option<String>,
+    pub count: u64,
+}
+
+/// Address of the function, which encoded or handled the structure
+pub fn handler_addr(strct: &PacketStruct) -> Option<usize> {
+    strct.send_ret_addr().or_else(|| {
+        strct
+            .elements()
+            .iter()
+            .find(|elem| elem.offset() >= 2 && !elem.is_gap())
+            .map(|elem| elem.ret_address())
+    })
+}
+
+/// Handlers per direction and opcode, an opcode can have multiple handlers
+#[derive(Debug, Clone, Default)]
+pub struct HandlerMap {
+    entries: BTreeMap<(PacketDir, u16, usize), HandlerEntry>,
+}
+
+impl HandlerMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn add(&mut self, entry: HandlerEntry) {
+        match self.entries.get_mut(&(entry.dir, entry.opcode, entry.addr)) {
+            Some(cur) => {
+                cur.count += entry.count;
+                if cur.symbol.is_none() {
+                    cur.symbol = entry.symbol;
+                }
+            }
+            None => {
+                self.entries
+                    .insert((entry.dir, entry.opcode, entry.addr), entry);
+            }
+        }
+    }
+
+    /// Records the handler of a structure, returns false If It has no opcode or handler
+    pub fn record(&mut self, dir: PacketDir, strct: &PacketStruct) -> bool {
+        let (Some(opcode), Some(addr)) = (strct.opcode(), handler_addr(strct)) else {
+            return false;
+        };
+
+        self.add(HandlerEntry {
+            dir,
+            opcode,
+            addr,
+            symbol: None,
+            count: 1,
+        });
+        true
+    }
+
+    /// Adds the entries of another map, like the one of a previous session
+    pub fn merge(&mut self, other: HandlerMap) {
+        for entry in other.entries.into_values() {
+            self.add(entry);
+        }
+    }
+
+    /// Resolves the symbols of all entries without one
+    pub fn symbolize(&mut self, mut lookup: impl FnMut(usize) -> Option<String>) {
+        for entry in self.entries.values_mut() {
+            if entry.symbol.is_none() {
+                entry.symbol = lookup(entry.addr);
+            }
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &HandlerEntry> {
+        self.entries.values()
+    }
+
+    pub fn get(&self, dir: PacketDir, opcode: u16) -> impl Iterator<Item = &HandlerEntry> {
+        self.entries
+            .range((dir, opcode, 0)..=(dir, opcode, usize::MAX))
+            .map(|(_, entry)| entry)
+    }
+
+    /// CSV with the columns `dir,opcode,addr,symbol,count`
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("dir,opcode,addr,symbol,count\n");
+        for e in self.entries() {
+            // Symbols can contain commas, like template arguments
+            let symbol = e
+                .symbol
+                .as_deref()
+                .map(|sym| format!("\"{}\"", sym.replace('"', "\"\"")))
+                .unwrap_or_default();
+            writeln!(
+                out,
+                "{:?},{:#06x},{:#x},{symbol},{}",
+                e.dir, e.opcode, e.addr, e.count
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    pub fn from_json(s: &str) -> anyhow::Result<Self> {
+        let entries: Vec<HandlerEntry> = serde_json::from_str(s)?;
+        let mut map = Self::new();
+        for entry in entries {
+            map.add(entry);
+        }
+        Ok(map)
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(
+            &self.entries().collect::<Vec<_>>(),
+        )?)
+    }
+
+    /// Loads a saved map, a missing file is an empty map
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading handler map {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("Parsing handler map {}", path.display()))
+    }
+
+    pub fn save_json(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_json()?)
+            .with_context(|| format!("Writing handler map {}", path.display()))
+    }
+
+    pub fn save_csv(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_csv())
+            .with_context(|| format!("Writing handler map {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet_struct::{PacketStructElem, PacketStructTy};
+
+    use super::*;
+
+    fn recv(opcode: u16, handler: usize) -> PacketStruct {
+        let mut strct = PacketStruct::new_recv();
+        strct.add_elem(PacketStructElem::new(0, 0x1000, PacketStructTy::I16));
+        strct.add_elem(PacketStructElem::new(2, handler, PacketStructTy::I32));
+        strct.add_elem(PacketStructElem::new(6, handler + 0x10, PacketStructTy::I8));
+        strct.set_opcode(opcode);
+        strct
+    }
+
+    fn send(opcode: u16, encoder: usize) -> PacketStruct {
+        let mut strct = PacketStruct::new_send(encoder);
+        strct.add_elem(PacketStructElem::new(
+            0,
+            encoder - 0x20,
+            PacketStructTy::I16,
+        ));
+        strct.set_opcode(opcode);
+        strct
+    }
+
+    #[test]
+    fn record_and_merge() {
+        let mut map = HandlerMap::new();
+        assert!(map.record(PacketDir::Recv, &recv(0x7a, 0x2000)));
+        assert!(map.record(PacketDir::Recv, &recv(0x7a, 0x2000)));
+        assert!(map.record(PacketDir::Send, &send(0x1, 0x3000)));
+        assert!(!map.record(PacketDir::Recv, &PacketStruct::new_recv()));
+
+        let recv_handlers = map.get(PacketDir::Recv, 0x7a).collect::<Vec<_>>();
+        assert_eq!(recv_handlers.len(), 1);
+        assert_eq!(recv_handlers[0].addr, 0x2000);
+        assert_eq!(recv_handlers[0].count, 2);
+        assert_eq!(map.get(PacketDir::Send, 0x1).next().unwrap().addr, 0x3000);
+
+        map.symbolize(|addr| (addr == 0x2000).then(|| "CField::OnChat<a,b>".to_string()));
+        assert_eq!(
+            map.to_csv(),
+            "dir,opcode,addr,symbol,count\n\
+             Send,0x0001,0x3000,,1\n\
+             Recv,0x007a,0x2000,\"CField::OnChat<a,b>\",2\n"
+        );
+
+        // A later session adds its counts and keeps the resolved symbols
+        let mut next = HandlerMap::new();
+        next.record(PacketDir::Recv, &recv(0x7a, 0x2000));
+        next.record(PacketDir::Recv, &recv(0x7b, 0x2100));
+        next.merge(HandlerMap::from_json(&map.to_json().unwrap()).unwrap());
+
+        assert_eq!(next.len(), 3);
+        let chat = next.get(PacketDir::Recv, 0x7a).next().unwrap();
+        assert_eq!(chat.count, 3);
+        assert_eq!(chat.symbol.as_deref(), Some("CField::OnChat<a,b>"));
+    }
+}
@@ -0,0 +1,31 @@
+//! Pure rust part of the packet tracing, which does not depend on the client.
+//! This allows working with saved traces on the host.
+
+use serde::{Deserialize, Serialize};
+
+pub mod capture;
+pub mod codegen;
+pub mod coverage;
+pub mod crypto;
+pub mod diff;
+pub mod dissector;
+pub mod fuzz;
+pub mod handlers;
+pub mod merge;
+pub mod mock;
+pub mod packet_struct;
+pub mod pcap;
+pub mod redact;
+pub mod schema;
+pub mod stats;
+pub mod stream;
+pub mod symbols;
+pub mod tree;
+pub mod writer;
+
+/// Direction of a packet, seen from the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum PacketDir {
+    Send,
+    Recv,
+}
@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    packet_struct::{PacketStructElem, PacketStructTy, PacketTraceRecord},
+    PacketDir,
+};
+
+/// Type of a field over all observations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergedTy {
+    I8,
+    I16,
+    I32,
+    I64,
+    F64,
+    Time,
+    Pos,
+    Str,
+    Buf(u32),
+    /// Buffer with a different length between the observations
+    VarBuf,
+    /// Data which was not read or written by a hooked function
+    Gap(u32),
+    VarGap,
+}
+
+impl MergedTy {
+    fn from_elem(elem: &PacketStructElem) -> Self {
+        match *elem.ty() {
+            PacketStructTy::I8 => Self::I8,
+            PacketStructTy::I16 => Self::I16,
+            PacketStructTy::I32 => Self::I32,
+            PacketStructTy::I64 => Self::I64,
+            PacketStructTy::F64 => Self::F64,
+            PacketStructTy::Time => Self::Time,
+            PacketStructTy::Pos => Self::Pos,
+            PacketStructTy::Str(_) => Self::Str,
+            PacketStructTy::Buf(n) if elem.is_gap() => Self::Gap(n),
+            PacketStructTy::Buf(n) => Self::Buf(n),
+        }
+    }
+
+    fn merge(&self, other: &Self) -> Option<Self> {
+        Some(match (self, other) {
+            (a, b) if a == b => a.clone(),
+            (Self::Buf(_) | Self::VarBuf, Self::Buf(_) | Self::VarBuf) => Self::VarBuf,
+            (Self::Gap(_) | Self::VarGap, Self::Gap(_) | Self::VarGap) => Self::VarGap,
+            _ => return None,
+        })
+    }
+
+    pub fn is_ambiguous(&self) -> bool {
+        matches!(self, Self::VarBuf | Self::Gap(_) | Self::VarGap)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MergedField {
+    /// Offset in the first observation
+    pub offset: usize,
+    pub ret_address: usize,
+    /// Callers in the first observation
+    pub call_stack: Vec<usize>,
+    pub ty: MergedTy,
+}
+
+impl MergedField {
+    /// Return address followed by the call stack
+    pub fn frames(&self) -> Vec<usize> {
+        std::iter::once(self.ret_address)
+            .chain(self.call_stack.iter().copied())
+            .collect()
+    }
+}
+
+/// Structure of all observations of an opcode
+#[derive(Debug, Clone)]
+pub struct MergedStruct {
+    pub dir: PacketDir,
+    pub opcode: u16,
+    pub observations: usize,
+    pub fields: Vec<MergedField>,
+    /// Offset in the first observation, from which the observations differ
+    pub diverges_at: Option<usize>,
+    pub send_ret_addr: Option<usize>,
+    pub incomplete: usize,
+    /// Offsets, at which incomplete observations failed to decode
+    pub exception_offsets: Vec<usize>,
+}
+
+impl MergedStruct {
+    /// Address of the function, which encoded or handled the packet
+    pub fn handler_addr(&self) -> Option<usize> {
+        self.send_ret_addr
+            .or_else(|| self.fields.first().map(|f| f.ret_address))
+    }
+}
+
+/// Elements after the opcode
+fn body(record: &PacketTraceRecord) -> impl Iterator<Item = &PacketStructElem> {
+    record
+        .strct
+        .elements()
+        .iter()
+        .filter(|elem| elem.offset() >= 2)
+}
+
+fn merge_group(dir: PacketDir, opcode: u16, records: &[&PacketTraceRecord]) -> MergedStruct {
+    let mut iters = records.iter().map(|r| body(r)).collect::<Vec<_>>();
+    let mut fields = Vec::new();
+    let mut diverges_at = None;
+
+    loop {
+        let elems = iters.iter_mut().map(|it| it.next()).collect::<Vec<_>>();
+        if elems.iter().all(Option::is_none) {
+            break;
+        }
+
+        let merged = elems.iter().try_fold(None::<MergedTy>, |acc, elem| {
+            let ty = MergedTy::from_elem((*elem)?);
+            match acc {
+                None => Some(Some(ty)),
+                Some(acc) => acc.merge(&ty).map(Some),
+            }
+        });
+
+        let first = elems[0];
+        match (merged.flatten(), first) {
+            (Some(ty), Some(first)) => fields.push(MergedField {
+                offset: first.offset(),
+                ret_address: first.ret_address(),
+                call_stack: first.call_stack().to_vec(),
+                ty,
+            }),
+            _ => {
+                diverges_at = Some(
+                    first
+                        .map(|e| e.offset())
+                        .unwrap_or_else(|| records[0].strct.last_known_offset()),
+                );
+                break;
+            }
+        }
+    }
+
+    MergedStruct {
+        dir,
+        opcode,
+        observations: records.len(),
+        fields,
+        diverges_at,
+        send_ret_addr: records.iter().find_map(|r| r.strct.send_ret_addr()),
+        incomplete: records.iter().filter(|r| r.strct.is_incomplete()).count(),
+        exception_offsets: Vec::new(),
+    }
+}
+
+/// Merges all complete observations per opcode, records without an opcode are skipped
+pub fn merge_records(dir: PacketDir, records: &[PacketTraceRecord]) -> Vec<MergedStruct> {
+    let mut groups: BTreeMap<u16, Vec<&PacketTraceRecord>> = BTreeMap::new();
+    for record in records {
+        if let Some(opcode) = record.opcode() {
+            groups.entry(opcode).or_default().push(record);
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(opcode, group)| {
+            let complete = group
+                .iter()
+                .copied()
+                .filter(|r| !r.strct.is_incomplete())
+                .collect::<Vec<_>>();
+            let mut merged = if complete.is_empty() {
+                merge_group(dir, opcode, &group)
+            } else {
+                merge_group(dir, opcode, &complete)
+            };
+            merged.observations = group.len();
+            merged.incomplete = group.len() - complete.len();
+            merged.exception_offsets = group
+                .iter()
+                .filter_map(|r| r.strct.exception_offset())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            merged
+        })
+        .collect()
+}
@@ -0,0 +1,363 @@
+//! Mock server, which replays a recorded session.
+//!
+//! The recording is a saved packet stream with logged data. Every packet sent by the client
+//! starts a step, the received packets until the next sent one are Its responses. A client packet
+//! is answered with the responses of the next step with the same opcode, so packets missing in
+//! the recording(like additional pings) are skipped. Connections share the script, so a
+//! migration continues with the next connection.
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::Context;
+
+use crate::{
+    crypto::{PacketCipher, PacketDecoder},
+    stream::StreamRecord,
+    PacketDir,
+};
+
+/// Steps, which are searched for a matching opcode
+const DEFAULT_LOOKAHEAD: usize = 8;
+
+/// Unencrypted first packet of the server, which sets the version and the IVs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u16,
+    pub patch: String,
+    /// IV of the packets sent by the client
+    pub client_iv: [u8; 4],
+    /// IV of the packets sent by the server
+    pub server_iv: [u8; 4],
+    pub locale: u8,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self {
+            version: 95,
+            patch: "1".to_string(),
+            client_iv: [0x46, 0x72, 0x7a, 0x52],
+            server_iv: [0x52, 0x30, 0x78, 0x73],
+            locale: 8,
+        }
+    }
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+impl Handshake {
+    /// Encoded handshake with the length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.version.to_le_bytes());
+        body.extend_from_slice(&(self.patch.len() as u16).to_le_bytes());
+        body.extend_from_slice(self.patch.as_bytes());
+        body.extend_from_slice(&self.client_iv);
+        body.extend_from_slice(&self.server_iv);
+        body.push(self.locale);
+
+        let mut out = (body.len() as u16).to_le_bytes().to_vec();
+        out.extend(body);
+        out
+    }
+
+    pub fn read(r: &mut impl Read) -> anyhow::Result<Self> {
+        let mut body = vec![0; read_u16(r)? as usize];
+        r.read_exact(&mut body)?;
+
+        let mut r = body.as_slice();
+        let version = read_u16(&mut r)?;
+        let mut patch = vec![0; read_u16(&mut r)? as usize];
+        r.read_exact(&mut patch)?;
+        let mut ivs = [0; 9];
+        r.read_exact(&mut ivs).context("Handshake is too short")?;
+
+        Ok(Self {
+            version,
+            patch: String::from_utf8(patch).context("Invalid patch string")?,
+            client_iv: ivs[0..4].try_into().unwrap(),
+            server_iv: ivs[4..8].try_into().unwrap(),
+            locale: ivs[8],
+        })
+    }
+}
+
+/// Framing of the packets after the handshake
+pub trait Codec: Send {
+    fn write_packet(&mut self, w: &mut dyn Write, data: &[u8]) -> io::Result<()>;
+    fn read_packet(&mut self, r: &mut dyn Read) -> io::Result<Vec<u8>>;
+}
+
+/// Unencrypted packets with a 2 byte length, for clients with a disabled packet encryption
+#[derive(Debug, Default)]
+pub struct PlainCodec;
+
+impl Codec for PlainCodec {
+    fn write_packet(&mut self, w: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+        let len = u16::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Packet is too long"))?;
+        let mut buf = len.to_le_bytes().to_vec();
+        buf.extend_from_slice(data);
+        w.write_all(&buf)
+    }
+
+    fn read_packet(&mut self, mut r: &mut dyn Read) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; read_u16(&mut r)? as usize];
+        r.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Encrypted packets like the real server sends them
+#[derive(Debug)]
+pub struct CryptoCodec {
+    send: PacketCipher,
+    recv: PacketDecoder,
+}
+
+impl CryptoCodec {
+    /// Codec of the server side, `shanda` must match `disable_shanda` of the client
+    pub fn server(handshake: &Handshake, shanda: bool) -> Self {
+        Self {
+            send: PacketCipher::server(handshake.server_iv, handshake.version, shanda),
+            recv: PacketDecoder::new(PacketCipher::client(
+                handshake.client_iv,
+                handshake.version,
+                shanda,
+            )),
+        }
+    }
+
+    pub fn client(handshake: &Handshake, shanda: bool) -> Self {
+        Self {
+            send: PacketCipher::client(handshake.client_iv, handshake.version, shanda),
+            recv: PacketDecoder::new(PacketCipher::server(
+                handshake.server_iv,
+                handshake.version,
+                shanda,
+            )),
+        }
+    }
+}
+
+impl Codec for CryptoCodec {
+    fn write_packet(&mut self, w: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+        w.write_all(&self.send.encrypt(data))
+    }
+
+    fn read_packet(&mut self, r: &mut dyn Read) -> io::Result<Vec<u8>> {
+        let mut buf = [0; 4096];
+        loop {
+            let pkt = self
+                .recv
+                .next_packet()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if let Some(pkt) = pkt {
+                return Ok(pkt);
+            }
+
+            let n = r.read(&mut buf)?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.recv.push(&buf[..n]);
+        }
+    }
+}
+
+/// Sent packet of the client with the received responses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub opcode: u16,
+    pub responses: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    /// Packets, which were received before the first sent one
+    pub greeting: Vec<Vec<u8>>,
+    pub steps: Vec<Step>,
+    cursor: usize,
+    lookahead: usize,
+}
+
+impl Script {
+    /// Builds the script from the records, records without data are skipped
+    pub fn from_records(records: impl IntoIterator<Item = StreamRecord>) -> Self {
+        let mut script = Self {
+            lookahead: DEFAULT_LOOKAHEAD,
+            ..Self::default()
+        };
+        for record in records {
+            let Some(data) = record.data.filter(|data| data.len() >= 2) else {
+                continue;
+            };
+
+            match (record.dir, script.steps.last_mut()) {
+                (PacketDir::Send, _) => script.steps.push(Step {
+                    opcode: u16::from_le_bytes([data[0], data[1]]),
+                    responses: Vec::new(),
+                }),
+                (PacketDir::Recv, Some(step)) => step.responses.push(data),
+                (PacketDir::Recv, None) => script.greeting.push(data),
+            }
+        }
+        script
+    }
+
+    pub fn with_lookahead(mut self, lookahead: usize) -> Self {
+        self.lookahead = lookahead.max(1);
+        self
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.cursor >= self.steps.len()
+    }
+
+    /// Responses to a client packet, None If no upcoming step matches the opcode
+    pub fn respond(&mut self, opcode: u16) -> Option<&[Vec<u8>]> {
+        let end = (self.cursor + self.lookahead).min(self.steps.len());
+        let ix = (self.cursor..end).find(|&ix| self.steps[ix].opcode == opcode)?;
+        self.cursor = ix + 1;
+        Some(&self.steps[ix].responses)
+    }
+}
+
+pub type CodecFactory = fn(&Handshake) -> Box<dyn Codec>;
+
+pub struct MockServer {
+    script: Script,
+    handshake: Handshake,
+    codec: CodecFactory,
+    connections: usize,
+}
+
+impl MockServer {
+    pub fn new(script: Script) -> Self {
+        Self {
+            script,
+            handshake: Handshake::default(),
+            codec: |handshake| Box::new(CryptoCodec::server(handshake, true)),
+            connections: 0,
+        }
+    }
+
+    pub fn with_handshake(mut self, handshake: Handshake) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Codec of the connections, which is created from the sent handshake.
+    /// Connections are encrypted like the real server by default
+    pub fn with_codec(mut self, codec: CodecFactory) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn script(&self) -> &Script {
+        &self.script
+    }
+
+    /// Serves one connection, until the client disconnects
+    pub fn serve_conn(&mut self, stream: TcpStream) -> anyhow::Result<()> {
+        let (mut r, mut w) = (&stream, &stream);
+        w.write_all(&self.handshake.encode())?;
+        let mut codec = (self.codec)(&self.handshake);
+
+        self.connections += 1;
+        if self.connections == 1 {
+            for pkt in &self.script.greeting {
+                codec.write_packet(&mut w, pkt)?;
+            }
+        }
+
+        loop {
+            let pkt = match codec.read_packet(&mut r) {
+                Ok(pkt) => pkt,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+                    ) =>
+                {
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if pkt.len() < 2 {
+                log::warn!("Packet without opcode: {pkt:?}");
+                continue;
+            }
+
+            let opcode = u16::from_le_bytes([pkt[0], pkt[1]]);
+            match self.script.respond(opcode) {
+                Some(responses) => {
+                    log::info!("{opcode:#06x}: {} responses", responses.len());
+                    for resp in responses {
+                        codec.write_packet(&mut w, resp)?;
+                    }
+                }
+                None => log::warn!("{opcode:#06x}: not in the upcoming steps"),
+            }
+        }
+    }
+
+    /// Serves the connections one after another, until the script is done
+    pub fn run(&mut self, listener: &TcpListener) -> anyhow::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            log::info!("Client connected from {}", stream.peer_addr()?);
+            if let Err(err) = self.serve_conn(stream) {
+                log::warn!("Connection failed: {err:?}");
+            }
+            log::info!("Client disconnected");
+
+            if self.script.is_done() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake() {
+        let hs = Handshake::default();
+        assert_eq!(Handshake::read(&mut hs.encode().as_slice()).unwrap(), hs);
+    }
+
+    #[test]
+    fn respond() {
+        let steps = [
+            (1, vec![vec![1, 0, 1]]),
+            (2, vec![]),
+            (1, vec![vec![1, 0, 2]]),
+        ];
+        let mut script = Script {
+            steps: steps
+                .into_iter()
+                .map(|(opcode, responses)| Step { opcode, responses })
+                .collect(),
+            ..Script::default()
+        }
+        .with_lookahead(2);
+
+        assert_eq!(script.respond(1).unwrap(), [vec![1, 0, 1]]);
+        // Not in the recording
+        assert!(script.respond(9).is_none());
+        // Skips the missing step 2
+        assert_eq!(script.respond(1).unwrap(), [vec![1, 0, 2]]);
+        assert!(script.is_done());
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    ffi::{c_uchar, c_uint, c_ushort},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PacketStructTy {
+    I8,
+    I16,
+    I32,
+    I64,
+    F64,
+    Buf(u32),
+    Str(u32),
+    /// FILETIME written by a composite helper
+    Time,
+    /// Position(x, y as i16) written by a composite helper
+    Pos,
+}
+
+impl From<c_uchar> for PacketStructTy {
+    fn from(_value: c_uchar) -> Self {
+        Self::I8
+    }
+}
+
+impl From<c_ushort> for PacketStructTy {
+    fn from(_value: c_ushort) -> Self {
+        Self::I16
+    }
+}
+
+impl From<c_uint> for PacketStructTy {
+    fn from(_value: c_uint) -> Self {
+        Self::I32
+    }
+}
+
+impl From<u64> for PacketStructTy {
+    fn from(_value: u64) -> Self {
+        Self::I64
+    }
+}
+
+impl From<f64> for PacketStructTy {
+    fn from(_value: f64) -> Self {
+        Self::F64
+    }
+}
+
+impl From<&[u8]> for PacketStructTy {
+    fn from(value: &[u8]) -> Self {
+        Self::Buf(value.len() as u32)
+    }
+}
+
+impl PacketStructTy {
+    pub fn byte_len(&self) -> usize {
+        match *self {
+            PacketStructTy::I8 => 1,
+            PacketStructTy::I16 => 2,
+            PacketStructTy::I32 | PacketStructTy::Pos => 4,
+            PacketStructTy::I64 | PacketStructTy::F64 | PacketStructTy::Time => 8,
+            PacketStructTy::Buf(ln) => ln as usize,
+            // Strings are prefixed with their length
+            PacketStructTy::Str(ln) => ln as usize + 2,
+        }
+    }
+}
+
+/// Value, which is read or written by a client helper out of primitive calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompositeTy {
+    /// FILETIME as a single 8 byte buffer
+    Time,
+    /// Two consecutive 16 bit values
+    Pos,
+}
+
+/// Address range of a composite helper function, the primitive calls
+/// inside of It are folded into a single element
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompositeHelper {
+    pub start: usize,
+    pub end: usize,
+    pub ty: CompositeTy,
+}
+
+impl CompositeHelper {
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PacketStructElem {
+    ret_address: usize,
+    ty: PacketStructTy,
+    offset: usize,
+    /// Return addresses of the callers, starting with the caller of `ret_address`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    call_stack: Vec<usize>,
+}
+
+impl PacketStructElem {
+    pub fn new<T: Into<PacketStructTy>>(offset: usize, ret_addr: usize, ty: T) -> Self {
+        Self {
+            ret_address: ret_addr,
+            ty: ty.into(),
+            offset,
+            call_stack: Vec::new(),
+        }
+    }
+
+    pub fn with_call_stack(mut self, call_stack: Vec<usize>) -> Self {
+        self.call_stack = call_stack;
+        self
+    }
+
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    /// Return address followed by the call stack
+    pub fn frames(&self) -> impl Iterator<Item = usize> + '_ {
+        std::iter::once(self.ret_address).chain(self.call_stack.iter().copied())
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.ty.byte_len()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn ret_address(&self) -> usize {
+        self.ret_address
+    }
+
+    pub fn ty(&self) -> &PacketStructTy {
+        &self.ty
+    }
+
+    /// Gaps are inserted for data, which was read or written without a hooked function
+    pub fn is_gap(&self) -> bool {
+        self.ret_address == usize::MAX
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PacketStruct {
+    elements: Vec<PacketStructElem>,
+    send_ret_addr: Option<usize>,
+    exception_ret_addr: Option<usize>,
+    last_known_offset: usize,
+    #[serde(default)]
+    opcode: Option<u16>,
+    /// Offset of the element, which failed to decode
+    #[serde(default)]
+    exception_offset: Option<usize>,
+}
+
+impl PacketStruct {
+    pub fn new_send(send_ret_addr: usize) -> Self {
+        Self {
+            send_ret_addr: Some(send_ret_addr),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_recv() -> Self {
+        Self {
+            ..Default::default()
+        }
+    }
+
+    fn handle_gap(&mut self, offset: usize) {
+        if offset > self.last_known_offset {
+            let gap = offset - self.last_known_offset;
+            self.elements.push(PacketStructElem::new(
+                self.last_known_offset,
+                usize::MAX,
+                PacketStructTy::Buf(gap as u32),
+            ));
+            self.last_known_offset = offset;
+        }
+    }
+
+    pub fn add_elem(&mut self, elem: PacketStructElem) {
+        self.handle_gap(elem.offset);
+        self.last_known_offset = elem.offset + elem.byte_len();
+        self.elements.push(elem);
+    }
+
+    /// Adds an element, which was read or written inside of a composite helper
+    pub fn add_composite_elem(&mut self, elem: PacketStructElem, helper: &CompositeHelper) {
+        match (helper.ty, &elem.ty) {
+            (CompositeTy::Time, PacketStructTy::Buf(8)) => {
+                self.add_elem(PacketStructElem {
+                    ty: PacketStructTy::Time,
+                    ..elem
+                });
+            }
+            (CompositeTy::Pos, PacketStructTy::I16) => {
+                let is_x = self.elements.last().is_some_and(|last| {
+                    last.ty == PacketStructTy::I16
+                        && last.offset + 2 == elem.offset
+                        && helper.contains(last.ret_address)
+                });
+
+                if is_x {
+                    let x = self.elements.pop().unwrap();
+                    self.last_known_offset = x.offset + 4;
+                    self.elements.push(PacketStructElem {
+                        ty: PacketStructTy::Pos,
+                        ..x
+                    });
+                } else {
+                    self.add_elem(elem);
+                }
+            }
+            _ => self.add_elem(elem),
+        }
+    }
+
+    pub fn elements(&self) -> &[PacketStructElem] {
+        &self.elements
+    }
+
+    pub fn send_ret_addr(&self) -> Option<usize> {
+        self.send_ret_addr
+    }
+
+    pub fn set_send_ret_addr(&mut self, send_ret_addr: usize) {
+        self.send_ret_addr = Some(send_ret_addr);
+    }
+
+    pub fn exception_ret_addr(&self) -> Option<usize> {
+        self.exception_ret_addr
+    }
+
+    pub fn set_exception_ret_addr(&mut self, exception_ret_addr: usize) {
+        self.exception_ret_addr = Some(exception_ret_addr);
+    }
+
+    pub fn exception_offset(&self) -> Option<usize> {
+        self.exception_offset
+    }
+
+    pub fn set_exception_offset(&mut self, exception_offset: usize) {
+        self.exception_offset = Some(exception_offset);
+    }
+
+    pub fn is_incomplete(&self) -> bool {
+        self.exception_ret_addr.is_some()
+    }
+
+    pub fn last_known_offset(&self) -> usize {
+        self.last_known_offset
+    }
+
+    pub fn opcode(&self) -> Option<u16> {
+        self.opcode
+    }
+
+    pub fn set_opcode(&mut self, opcode: u16) {
+        self.opcode = Some(opcode);
+    }
+}
+
+/// Single record of a trace file, as It's written by the packet logger
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PacketTraceRecord {
+    pub strct: PacketStruct,
+    pub data: Option<Vec<u8>>,
+    /// Sequence number shared with the raw socket capture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl PacketTraceRecord {
+    /// Opcode of the record, which falls back to the logged data for older traces
+    pub fn opcode(&self) -> Option<u16> {
+        self.strct.opcode.or_else(|| {
+            self.data
+                .as_ref()
+                .and_then(|data| data.get(..2))
+                .map(|op| u16::from_le_bytes(op.try_into().unwrap()))
+        })
+    }
+}
+
+/// Parses the content of a trace file, every record is followed by a comma
+pub fn parse_trace(s: &str) -> anyhow::Result<Vec<PacketTraceRecord>> {
+    s.lines()
+        .map(|line| line.trim().trim_end_matches(','))
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).with_context(|| format!("Invalid trace record {i}"))
+        })
+        .collect()
+}
+
+pub fn read_trace_file(path: impl AsRef<Path>) -> anyhow::Result<Vec<PacketTraceRecord>> {
+    let path = path.as_ref();
+    let s = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading trace file {}", path.display()))?;
+    parse_trace(&s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composite_pos() {
+        let helper = CompositeHelper {
+            start: 0x100,
+            end: 0x200,
+            ty: CompositeTy::Pos,
+        };
+        let mut strct = PacketStruct::new_recv();
+        strct.add_elem(PacketStructElem::new(0, 0x50, PacketStructTy::I16));
+        strct.add_composite_elem(
+            PacketStructElem::new(2, 0x110, PacketStructTy::I16),
+            &helper,
+        );
+        strct.add_composite_elem(
+            PacketStructElem::new(4, 0x120, PacketStructTy::I16),
+            &helper,
+        );
+        strct.add_elem(PacketStructElem::new(6, 0x50, PacketStructTy::I8));
+
+        let tys = strct
+            .elements()
+            .iter()
+            .map(|e| e.ty().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tys,
+            [PacketStructTy::I16, PacketStructTy::Pos, PacketStructTy::I8]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Pcap files of traced packets for the generated dissector.
+//!
+//! Frames use the `USER0` link type like the dissector expects, a direction byte followed by the
+//! opcode and payload.
+
+use std::io::{self, Write};
+
+use crate::{dissector::dir_id, packet_struct::PacketTraceRecord, PacketDir};
+
+pub const LINKTYPE_USER0: u32 = 147;
+const MAGIC: u32 = 0xa1b2_c3d4;
+const SNAP_LEN: u32 = 0x4_0000;
+
+#[derive(Debug)]
+pub struct PcapWriter<W> {
+    w: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header
+    pub fn new(mut w: W) -> io::Result<Self> {
+        w.write_all(&MAGIC.to_le_bytes())?;
+        // Version 2.4
+        w.write_all(&2u16.to_le_bytes())?;
+        w.write_all(&4u16.to_le_bytes())?;
+        // Time zone and timestamp accuracy
+        w.write_all(&[0; 8])?;
+        w.write_all(&SNAP_LEN.to_le_bytes())?;
+        w.write_all(&LINKTYPE_USER0.to_le_bytes())?;
+        Ok(Self { w })
+    }
+
+    /// Writes a packet, which starts with the opcode, as frame
+    pub fn write_packet(&mut self, time_us: u64, dir: PacketDir, data: &[u8]) -> io::Result<()> {
+        let len = data.len() as u32 + 1;
+        let secs = (time_us / 1_000_000) as u32;
+        let us = (time_us % 1_000_000) as u32;
+        for v in [secs, us, len, len] {
+            self.w.write_all(&v.to_le_bytes())?;
+        }
+        self.w.write_all(&[dir_id(dir)])?;
+        self.w.write_all(data)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Packets of the send and receive trace in session order, records without data are skipped.
+/// The sequence number orders the records, which requires the raw capture. Without It the sent
+/// packets come first
+pub fn ordered_packets<'a>(
+    send: &'a [PacketTraceRecord],
+    recv: &'a [PacketTraceRecord],
+) -> Vec<(PacketDir, &'a [u8])> {
+    let mut records = send
+        .iter()
+        .map(|record| (PacketDir::Send, record))
+        .chain(recv.iter().map(|record| (PacketDir::Recv, record)))
+        .collect::<Vec<_>>();
+    if records.iter().all(|(_, record)| record.seq.is_some()) {
+        records.sort_by_key(|(_, record)| record.seq);
+    }
+    records
+        .into_iter()
+        .filter_map(|(dir, record)| Some((dir, record.data.as_deref()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(data: &[u8], seq: Option<u64>) -> PacketTraceRecord {
+        PacketTraceRecord {
+            data: Some(data.to_vec()),
+            seq,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let mut w = PcapWriter::new(Vec::new()).unwrap();
+        w.write_packet(1_500_000, PacketDir::Recv, &[0x10, 0, 7])
+            .unwrap();
+        let buf = w.into_inner();
+
+        assert_eq!(buf.len(), 24 + 16 + 4);
+        assert_eq!(buf[20..24], LINKTYPE_USER0.to_le_bytes());
+        // Seconds, microseconds, captured and original length
+        assert_eq!(buf[24..28], 1u32.to_le_bytes());
+        assert_eq!(buf[28..32], 500_000u32.to_le_bytes());
+        assert_eq!(buf[32..36], 4u32.to_le_bytes());
+        assert_eq!(buf[40..], [1, 0x10, 0, 7]);
+    }
+
+    #[test]
+    fn order() {
+        let send = [record(&[1, 0], Some(2)), record(&[2, 0], Some(0))];
+        let recv = [
+            record(&[3, 0], Some(1)),
+            PacketTraceRecord {
+                seq: Some(3),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            ordered_packets(&send, &recv),
+            [
+                (PacketDir::Send, &[2, 0][..]),
+                (PacketDir::Recv, &[3, 0][..]),
+                (PacketDir::Send, &[1, 0][..]),
+            ]
+        );
+
+        // Without a sequence the file order is kept
+        let recv = [record(&[3, 0], None)];
+        assert_eq!(
+            ordered_packets(&send, &recv)
+                .iter()
+                .map(|(_, data)| data[0])
+                .collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    packet_struct::{PacketStructTy, PacketTraceRecord},
+    PacketDir,
+};
+
+const MASK: u8 = b'*';
+
+/// Masks string fields of an opcode in the logged packet data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedactRule {
+    pub dir: PacketDir,
+    pub opcode: u16,
+    /// Offset of the string(including the opcode), every string is masked If It's not set
+    #[serde(default)]
+    pub offset: Option<usize>,
+}
+
+impl RedactRule {
+    fn matches(&self, dir: PacketDir, opcode: u16, offset: usize) -> bool {
+        self.dir == dir && self.opcode == opcode && self.offset.is_none_or(|o| o == offset)
+    }
+}
+
+/// Masks strings in trace records by rule or by known secret values.
+/// Only the string content is masked, so lengths and offsets stay intact
+#[derive(Clone, Default)]
+pub struct Redactor {
+    rules: Vec<RedactRule>,
+    secrets: Vec<Vec<u8>>,
+}
+
+impl fmt::Debug for Redactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Redactor")
+            .field("rules", &self.rules)
+            .field("secrets", &self.secrets.len())
+            .finish()
+    }
+}
+
+impl Redactor {
+    pub fn new(rules: Vec<RedactRule>) -> Self {
+        Self {
+            rules,
+            secrets: Vec::new(),
+        }
+    }
+
+    /// Strings equal to the secret are masked in every packet
+    pub fn with_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        let secret = secret.into();
+        if !secret.is_empty() {
+            self.secrets.push(secret);
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.secrets.is_empty()
+    }
+
+    /// Masks the matching strings in the data of the record, returns the number of masked strings
+    pub fn redact(&self, dir: PacketDir, record: &mut PacketTraceRecord) -> usize {
+        let opcode = record.opcode();
+        let Some(data) = record.data.as_mut() else {
+            return 0;
+        };
+
+        let mut masked = 0;
+        for elem in record.strct.elements() {
+            let PacketStructTy::Str(len) = *elem.ty() else {
+                continue;
+            };
+            // Strings are prefixed with their length
+            let start = elem.offset() + 2;
+            let Some(s) = data.get_mut(start..start + len as usize) else {
+                continue;
+            };
+
+            let by_rule = opcode.is_some_and(|opcode| {
+                self.rules
+                    .iter()
+                    .any(|rule| rule.matches(dir, opcode, elem.offset()))
+            });
+            let by_secret = self.secrets.iter().any(|secret| secret == s);
+            if by_rule || by_secret {
+                s.fill(MASK);
+                masked += 1;
+            }
+        }
+        masked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet_struct::{PacketStruct, PacketStructElem};
+
+    use super::*;
+
+    fn encode_str(data: &mut Vec<u8>, s: &str) {
+        data.extend_from_slice(&(s.len() as u16).to_le_bytes());
+        data.extend_from_slice(s.as_bytes());
+    }
+
+    // Opcode, user and password string
+    fn check_password(user: &str, password: &str) -> PacketTraceRecord {
+        let mut data = 1u16.to_le_bytes().to_vec();
+        let mut strct = PacketStruct::new_send(0x1000);
+        strct.add_elem(PacketStructElem::new(0, 0x1000, PacketStructTy::I16));
+        strct.add_elem(PacketStructElem::new(
+            data.len(),
+            0x1000,
+            PacketStructTy::Str(user.len() as u32),
+        ));
+        encode_str(&mut data, user);
+        strct.add_elem(PacketStructElem::new(
+            data.len(),
+            0x1000,
+            PacketStructTy::Str(password.len() as u32),
+        ));
+        encode_str(&mut data, password);
+        strct.set_opcode(1);
+
+        PacketTraceRecord {
+            strct,
+            data: Some(data),
+            seq: None,
+        }
+    }
+
+    #[test]
+    fn by_offset() {
+        let mut record = check_password("admin", "hunter2");
+        // The password follows the opcode and the 5 character user name
+        let redactor = Redactor::new(vec![RedactRule {
+            dir: PacketDir::Send,
+            opcode: 1,
+            offset: Some(9),
+        }]);
+
+        assert_eq!(redactor.redact(PacketDir::Recv, &mut record), 0);
+        assert_eq!(redactor.redact(PacketDir::Send, &mut record), 1);
+        assert_eq!(&record.data.unwrap()[2..], b"\x05\x00admin\x07\x00*******");
+    }
+
+    #[test]
+    fn by_secret() {
+        let mut record = check_password("admin", "hunter2");
+        let redactor = Redactor::default().with_secret("hunter2");
+
+        assert_eq!(redactor.redact(PacketDir::Send, &mut record), 1);
+        let data = record.data.unwrap();
+        assert!(!data.windows(7).any(|w| w == b"hunter2"));
+        assert!(!format!("{redactor:?}").contains("hunter2"));
+    }
+}
@@ -0,0 +1,577 @@
+use std::{collections::HashMap, fmt, path::Path, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    packet_struct::{PacketStruct, PacketStructTy, PacketTraceRecord},
+    PacketDir,
+};
+
+/// Type of a schema field, written as `u8`, `u16`, `u32`, `u64`, `str`, `buf(n)` or `array`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldTy {
+    U8,
+    U16,
+    U32,
+    U64,
+    Str,
+    Buf(usize),
+    Array,
+}
+
+impl FromStr for FieldTy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "u8" => Self::U8,
+            "u16" => Self::U16,
+            "u32" => Self::U32,
+            "u64" => Self::U64,
+            "str" => Self::Str,
+            "array" => Self::Array,
+            _ => {
+                let n = s
+                    .strip_prefix("buf(")
+                    .and_then(|s| s.strip_suffix(')'))
+                    .ok_or_else(|| anyhow!("Unknown field type: {s}"))?;
+                Self::Buf(n.trim().parse()?)
+            }
+        })
+    }
+}
+
+impl fmt::Display for FieldTy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::U8 => write!(f, "u8"),
+            Self::U16 => write!(f, "u16"),
+            Self::U32 => write!(f, "u32"),
+            Self::U64 => write!(f, "u64"),
+            Self::Str => write!(f, "str"),
+            Self::Buf(n) => write!(f, "buf({n})"),
+            Self::Array => write!(f, "array"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FieldTy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for FieldTy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+/// Length of an array, either fixed or taken from an earlier field
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ArrayLen {
+    Fixed(usize),
+    Field(String),
+}
+
+/// Field is only present, If the earlier field is equal(`eq`) or not equal(`ne`) to the value
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FieldCond {
+    pub field: String,
+    pub eq: Option<u64>,
+    pub ne: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FieldSchema {
+    pub name: String,
+    pub ty: FieldTy,
+    pub cond: Option<FieldCond>,
+    /// Only used by arrays
+    pub len: Option<ArrayLen>,
+    /// Only used by arrays
+    #[serde(default)]
+    pub fields: Vec<FieldSchema>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PacketSchema {
+    pub name: String,
+    pub dir: PacketDir,
+    pub opcode: u16,
+    #[serde(default)]
+    pub fields: Vec<FieldSchema>,
+}
+
+/// Schema file with packet layouts, the fields exclude the opcode
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SchemaFile {
+    #[serde(rename = "packet", default)]
+    pub packets: Vec<PacketSchema>,
+}
+
+impl SchemaFile {
+    pub fn from_toml(s: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading schema file {}", path.display()))?;
+        Self::from_toml(&s)
+    }
+
+    pub fn get(&self, dir: PacketDir, opcode: u16) -> Option<&PacketSchema> {
+        self.packets
+            .iter()
+            .find(|p| p.dir == dir && p.opcode == opcode)
+    }
+
+    /// Decodes the packet data, which starts with the opcode
+    pub fn decode(&self, dir: PacketDir, data: &[u8]) -> anyhow::Result<DecodedPacket> {
+        let opcode = data
+            .get(..2)
+            .map(|op| u16::from_le_bytes(op.try_into().unwrap()))
+            .context("Packet without opcode")?;
+        let schema = self
+            .get(dir, opcode)
+            .ok_or_else(|| anyhow!("No schema for {dir:?} opcode {opcode:#x}"))?;
+
+        let mut reader = Reader { data, offset: 2 };
+        let mut scopes = Vec::new();
+        let fields = decode_fields(&schema.fields, &mut reader, &mut scopes)?;
+
+        Ok(DecodedPacket {
+            name: schema.name.clone(),
+            dir,
+            opcode,
+            fields,
+            trailing: data.len() - reader.offset,
+        })
+    }
+
+    /// Decodes a trace record and checks the schema against the observed structure
+    pub fn decode_record(
+        &self,
+        dir: PacketDir,
+        record: &PacketTraceRecord,
+    ) -> anyhow::Result<(DecodedPacket, Vec<SchemaMismatch>)> {
+        let data = record
+            .data
+            .as_ref()
+            .context("Trace record without data, enable log_data")?;
+        let decoded = self.decode(dir, data)?;
+        let mismatches = decoded.check_struct(&record.strct);
+        Ok((decoded, mismatches))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Value {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    Str(String),
+    Buf(Vec<u8>),
+    Array(Vec<Vec<DecodedField>>),
+}
+
+impl Value {
+    fn as_u64(&self) -> Option<u64> {
+        Some(match *self {
+            Self::U8(v) => v as u64,
+            Self::U16(v) => v as u64,
+            Self::U32(v) => v as u64,
+            Self::U64(v) => v,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DecodedField {
+    pub name: String,
+    pub offset: usize,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DecodedPacket {
+    pub name: String,
+    pub dir: PacketDir,
+    pub opcode: u16,
+    pub fields: Vec<DecodedField>,
+    /// Bytes which were not covered by the schema
+    pub trailing: usize,
+}
+
+/// Difference between the schema and the elements, which were recorded by the hooks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum SchemaMismatch {
+    TypeMismatch {
+        offset: usize,
+        field: String,
+        expected: FieldTy,
+        observed: PacketStructTy,
+    },
+    NotObserved {
+        offset: usize,
+        field: String,
+    },
+    NotInSchema {
+        offset: usize,
+        observed: PacketStructTy,
+    },
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TypeMismatch {
+                offset,
+                field,
+                expected,
+                observed,
+            } => write!(
+                f,
+                "{offset:#x}: field {field} expected {expected} but observed {observed:?}"
+            ),
+            Self::NotObserved { offset, field } => {
+                write!(f, "{offset:#x}: field {field} was not observed")
+            }
+            Self::NotInSchema { offset, observed } => {
+                write!(f, "{offset:#x}: observed {observed:?} is not in the schema")
+            }
+        }
+    }
+}
+
+fn ty_matches(expected: FieldTy, observed: &PacketStructTy) -> bool {
+    match (expected, observed) {
+        (FieldTy::U8, PacketStructTy::I8)
+        | (FieldTy::U16, PacketStructTy::I16)
+        | (FieldTy::U32, PacketStructTy::I32)
+        | (FieldTy::Str, PacketStructTy::Str(_)) => true,
+        // Without the 64 bit hooks the values show up as gaps
+        (FieldTy::U64, PacketStructTy::I64 | PacketStructTy::Time | PacketStructTy::Buf(8)) => true,
+        (FieldTy::U32, PacketStructTy::Pos) => true,
+        (FieldTy::Buf(8), PacketStructTy::Time | PacketStructTy::F64) => true,
+        (FieldTy::Buf(n), PacketStructTy::Buf(m)) => n == *m as usize,
+        _ => false,
+    }
+}
+
+impl DecodedPacket {
+    /// Flattened primitive fields with their type, arrays are expanded
+    pub fn flat_fields(&self) -> Vec<(usize, String, FieldTy)> {
+        fn flatten(
+            prefix: &str,
+            schema_fields: &[DecodedField],
+            out: &mut Vec<(usize, String, FieldTy)>,
+        ) {
+            for field in schema_fields {
+                let name = format!("{prefix}{}", field.name);
+                let ty = match field.value {
+                    Value::U8(_) => FieldTy::U8,
+                    Value::U16(_) => FieldTy::U16,
+                    Value::U32(_) => FieldTy::U32,
+                    Value::U64(_) => FieldTy::U64,
+                    Value::Str(_) => FieldTy::Str,
+                    Value::Buf(ref b) => FieldTy::Buf(b.len()),
+                    Value::Array(ref items) => {
+                        for (i, item) in items.iter().enumerate() {
+                            flatten(&format!("{name}[{i}]."), item, out);
+                        }
+                        continue;
+                    }
+                };
+                out.push((field.offset, name, ty));
+            }
+        }
+
+        let mut out = Vec::new();
+        flatten("", &self.fields, &mut out);
+        out
+    }
+
+    /// Compares the decoded fields with the observed element sequence,
+    /// elements before the first field(the opcode) are ignored
+    pub fn check_struct(&self, strct: &PacketStruct) -> Vec<SchemaMismatch> {
+        let expected = self.flat_fields();
+        let mut observed = strct
+            .elements()
+            .iter()
+            .filter(|elem| elem.offset() >= 2)
+            .peekable();
+        let mut expected = expected.into_iter().peekable();
+        let mut mismatches = Vec::new();
+
+        loop {
+            match (expected.peek(), observed.peek()) {
+                (Some((exp_off, _, _)), Some(obs)) if *exp_off == obs.offset() => {
+                    let (offset, field, ty) = expected.next().unwrap();
+                    let obs = observed.next().unwrap();
+                    if !ty_matches(ty, obs.ty()) {
+                        mismatches.push(SchemaMismatch::TypeMismatch {
+                            offset,
+                            field,
+                            expected: ty,
+                            observed: obs.ty().clone(),
+                        });
+                    }
+                }
+                (Some((exp_off, _, _)), obs)
+                    if obs.map(|obs| *exp_off < obs.offset()).unwrap_or(true) =>
+                {
+                    let (offset, field, _) = expected.next().unwrap();
+                    mismatches.push(SchemaMismatch::NotObserved { offset, field });
+                }
+                (_, Some(_)) => {
+                    let obs = observed.next().unwrap();
+                    mismatches.push(SchemaMismatch::NotInSchema {
+                        offset: obs.offset(),
+                        observed: obs.ty().clone(),
+                    });
+                }
+                (None, None) => break,
+                _ => unreachable!(),
+            }
+        }
+
+        mismatches
+    }
+}
+
+fn fmt_fields(f: &mut fmt::Formatter<'_>, fields: &[DecodedField], indent: usize) -> fmt::Result {
+    for field in fields {
+        write!(f, "{:indent$}{}: ", "", field.name, indent = indent)?;
+        match field.value {
+            Value::U8(v) => writeln!(f, "{v} ({v:#x})")?,
+            Value::U16(v) => writeln!(f, "{v} ({v:#x})")?,
+            Value::U32(v) => writeln!(f, "{v} ({v:#x})")?,
+            Value::U64(v) => writeln!(f, "{v} ({v:#x})")?,
+            Value::Str(ref s) => writeln!(f, "{s:?}")?,
+            Value::Buf(ref b) => writeln!(f, "{}", b.escape_ascii())?,
+            Value::Array(ref items) => {
+                writeln!(f, "[{}]", items.len())?;
+                for (i, item) in items.iter().enumerate() {
+                    writeln!(f, "{:indent$}[{i}]", "", indent = indent + 2)?;
+                    fmt_fields(f, item, indent + 4)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for DecodedPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:?} {} ({:#x})", self.dir, self.name, self.opcode)?;
+        fmt_fields(f, &self.fields, 2)?;
+        if self.trailing > 0 {
+            writeln!(f, "  <{} trailing bytes>", self.trailing)?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let data = self.data.get(self.offset..self.offset + n).ok_or_else(|| {
+            anyhow!(
+                "Unexpected end of packet at {:#x}, required {n} bytes",
+                self.offset
+            )
+        })?;
+        self.offset += n;
+        Ok(data)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.read(N)?.try_into().unwrap())
+    }
+}
+
+type Scope = HashMap<String, u64>;
+
+fn lookup(scopes: &[Scope], name: &str) -> anyhow::Result<u64> {
+    scopes
+        .iter()
+        .rev()
+        .find_map(|scope| scope.get(name).copied())
+        .ok_or_else(|| anyhow!("Unknown or non-integer field reference: {name}"))
+}
+
+/// Minimum bytes an item of the fields consumes, conditional fields may be skipped
+fn min_len(fields: &[FieldSchema]) -> usize {
+    fields
+        .iter()
+        .filter(|field| field.cond.is_none())
+        .map(|field| match field.ty {
+            FieldTy::U8 => 1,
+            FieldTy::U16 | FieldTy::Str => 2,
+            FieldTy::U32 => 4,
+            FieldTy::U64 => 8,
+            FieldTy::Buf(n) => n,
+            FieldTy::Array => match field.len {
+                Some(ArrayLen::Fixed(n)) => n.saturating_mul(min_len(&field.fields)),
+                _ => 0,
+            },
+        })
+        .fold(0, usize::saturating_add)
+}
+
+fn decode_fields(
+    fields: &[FieldSchema],
+    r: &mut Reader,
+    scopes: &mut Vec<Scope>,
+) -> anyhow::Result<Vec<DecodedField>> {
+    scopes.push(Scope::new());
+    let mut out = Vec::with_capacity(fields.len());
+
+    for field in fields {
+        if let Some(ref cond) = field.cond {
+            let v = lookup(scopes, &cond.field)?;
+            if cond.eq.is_some_and(|eq| eq != v) || cond.ne.is_some_and(|ne| ne == v) {
+                continue;
+            }
+        }
+
+        let offset = r.offset;
+        let value = match field.ty {
+            FieldTy::U8 => Value::U8(u8::from_le_bytes(r.read_array()?)),
+            FieldTy::U16 => Value::U16(u16::from_le_bytes(r.read_array()?)),
+            FieldTy::U32 => Value::U32(u32::from_le_bytes(r.read_array()?)),
+            FieldTy::U64 => Value::U64(u64::from_le_bytes(r.read_array()?)),
+            FieldTy::Str => {
+                let n = u16::from_le_bytes(r.read_array()?) as usize;
+                Value::Str(String::from_utf8_lossy(r.read(n)?).into_owned())
+            }
+            FieldTy::Buf(n) => Value::Buf(r.read(n)?.to_vec()),
+            FieldTy::Array => {
+                let n = match field.len {
+                    Some(ArrayLen::Fixed(n)) => n,
+                    Some(ArrayLen::Field(ref name)) => lookup(scopes, name)? as usize,
+                    None => bail!("Array {} without len", field.name),
+                };
+                // The length is read from the packet, so It's bounded by the remaining data
+                let remaining = r.data.len() - r.offset;
+                if n > remaining / min_len(&field.fields).max(1) {
+                    bail!(
+                        "Array {} has {n} items, but only {remaining} bytes are left",
+                        field.name
+                    );
+                }
+                let items = (0..n)
+                    .map(|_| decode_fields(&field.fields, r, scopes))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .with_context(|| format!("Decoding array {}", field.name))?;
+                Value::Array(items)
+            }
+        };
+
+        if let Some(v) = value.as_u64() {
+            scopes.last_mut().unwrap().insert(field.name.clone(), v);
+        }
+        out.push(DecodedField {
+            name: field.name.clone(),
+            offset,
+            value,
+        });
+    }
+
+    scopes.pop();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet_struct::parse_trace;
+
+    use super::*;
+
+    const SCHEMA: &str = r#"
+[[packet]]
+name = "CheckPassword"
+dir = "Send"
+opcode = 1
+fields = [
+    { name = "user", ty = "str" },
+    { name = "pw", ty = "str" },
+    { name = "machine_id", ty = "buf(4)" },
+    { name = "count", ty = "u8" },
+    { name = "items", ty = "array", len = "count", fields = [
+        { name = "id", ty = "u32" },
+    ] },
+    { name = "extra", ty = "u16", cond = { field = "count", ne = 0 } },
+]
+"#;
+
+    // Captured with log_data, the trace lacks the last element
+    const CAPTURE: &str = r#"{"strct":{"elements":[{"ret_address":4096,"ty":"I16","offset":0},{"ret_address":4097,"ty":{"Str":1},"offset":2},{"ret_address":4098,"ty":{"Str":2},"offset":5},{"ret_address":4099,"ty":{"Buf":4},"offset":9},{"ret_address":4100,"ty":"I8","offset":13},{"ret_address":4101,"ty":"I32","offset":14},{"ret_address":4102,"ty":"I16","offset":18}],"send_ret_addr":8192,"exception_ret_addr":null,"last_known_offset":20},"data":[1,0,1,0,97,2,0,98,99,1,2,3,4,1,7,0,0,0,9,0,255]},
+"#;
+
+    #[test]
+    fn decode_capture() {
+        let schema = SchemaFile::from_toml(SCHEMA).unwrap();
+        let records = parse_trace(CAPTURE).unwrap();
+        let (pkt, mismatches) = schema.decode_record(PacketDir::Send, &records[0]).unwrap();
+
+        assert_eq!(pkt.name, "CheckPassword");
+        assert_eq!(pkt.trailing, 1);
+        assert_eq!(pkt.fields[0].value, Value::Str("a".to_string()));
+        assert_eq!(pkt.fields[5].value, Value::U16(9));
+        let Value::Array(ref items) = pkt.fields[4].value else {
+            panic!("array expected");
+        };
+        assert_eq!(items[0][0].value, Value::U32(7));
+        assert!(mismatches.is_empty(), "{mismatches:?}");
+        assert!(pkt.to_string().contains("items: [1]"));
+    }
+
+    #[test]
+    fn mismatch() {
+        let schema = SchemaFile::from_toml(SCHEMA).unwrap();
+        let mut records = parse_trace(CAPTURE).unwrap();
+        // Without items the extra field is skipped
+        records[0].data.as_mut().unwrap()[13] = 0;
+        let (pkt, mismatches) = schema.decode_record(PacketDir::Send, &records[0]).unwrap();
+        assert_eq!(pkt.fields.len(), 5);
+        assert_eq!(
+            mismatches[0],
+            SchemaMismatch::NotInSchema {
+                offset: 14,
+                observed: PacketStructTy::I32
+            }
+        );
+    }
+
+    #[test]
+    fn array_len_beyond_data() {
+        let schema = SchemaFile::from_toml(SCHEMA).unwrap();
+        let mut records = parse_trace(CAPTURE).unwrap();
+        // The count is bogus, so decoding must fail before allocating the items
+        records[0].data.as_mut().unwrap()[13] = 0xff;
+        let err = schema
+            .decode_record(PacketDir::Send, &records[0])
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("255 items"), "{err:#}");
+    }
+}
@@ -0,0 +1,103 @@
+-- Generated by shroom_trace, do not edit
+-- Frames(link type USER0) contain a direction byte(0 = Send, 1 = Recv), the opcode and the payload
+
+local proto = Proto("shroom", "Shroom packet")
+
+local f_dir = ProtoField.uint8("shroom.dir", "Direction", base.DEC, { [0] = "Send", [1] = "Recv" })
+local f_opcode = ProtoField.uint16("shroom.opcode", "Opcode", base.HEX)
+local f_trailing = ProtoField.bytes("shroom.trailing", "Trailing")
+local f = {}
+
+f["send.check_password.user"] = ProtoField.string("shroom.send.check_password.user", "user")
+f["send.check_password.machine_id"] = ProtoField.bytes("shroom.send.check_password.machine_id", "machine_id")
+f["send.check_password.count"] = ProtoField.uint8("shroom.send.check_password.count", "count", base.DEC)
+f["send.check_password.items.id"] = ProtoField.uint32("shroom.send.check_password.items.id", "id", base.DEC)
+f["send.check_password.extra"] = ProtoField.uint16("shroom.send.check_password.extra", "extra", base.DEC)
+
+f["recv.user_chat.char_id"] = ProtoField.uint32("shroom.recv.user_chat.char_id", "char_id", base.DEC)
+f["recv.user_chat.msg"] = ProtoField.string("shroom.recv.user_chat.msg", "msg")
+
+local dissectors = { [0] = {}, [1] = {} }
+
+dissectors[0][0x0001] = { name = "CheckPassword", fn = function(buf, tree, off)
+    local v0 = {}
+    do
+        local len = buf(off, 2):le_uint()
+        if len > 0 then
+            tree:add(f["send.check_password.user"], buf(off + 2, len))
+        else
+            tree:add(f["send.check_password.user"], buf(off, 2), "")
+        end
+        off = off + 2 + len
+    end
+    tree:add(f["send.check_password.machine_id"], buf(off, 16))
+    off = off + 16
+    tree:add_le(f["send.check_password.count"], buf(off, 1))
+    v0["count"] = buf(off, 1):le_uint()
+    off = off + 1
+    for i0 = 1, v0["count"] do
+        local tree = tree:add(proto, "items" .. "[" .. (i0 - 1) .. "]")
+        local v1 = setmetatable({}, { __index = v0 })
+        tree:add_le(f["send.check_password.items.id"], buf(off, 4))
+        v1["id"] = buf(off, 4):le_uint()
+        off = off + 4
+    end
+    if v0["count"] ~= 0 then
+        tree:add_le(f["send.check_password.extra"], buf(off, 2))
+        v0["extra"] = buf(off, 2):le_uint()
+        off = off + 2
+    end
+    return off
+end }
+
+dissectors[1][0x007A] = { name = "UserChat", fn = function(buf, tree, off)
+    local v0 = {}
+    tree:add_le(f["recv.user_chat.char_id"], buf(off, 4))
+    v0["char_id"] = buf(off, 4):le_uint()
+    off = off + 4
+    do
+        local len = buf(off, 2):le_uint()
+        if len > 0 then
+            tree:add(f["recv.user_chat.msg"], buf(off + 2, len))
+        else
+            tree:add(f["recv.user_chat.msg"], buf(off, 2), "")
+        end
+        off = off + 2 + len
+    end
+    return off
+end }
+
+local all_fields = { f_dir, f_opcode, f_trailing }
+for _, field in pairs(f) do
+    all_fields[#all_fields + 1] = field
+end
+proto.fields = all_fields
+
+function proto.dissector(buf, pinfo, tree)
+    if buf:len() < 3 then
+        return 0
+    end
+
+    pinfo.cols.protocol = "Shroom"
+    local dir = buf(0, 1):uint()
+    local opcode = buf(1, 2):le_uint()
+    local sub = tree:add(proto, buf(), "Shroom packet")
+    sub:add(f_dir, buf(0, 1))
+    sub:add_le(f_opcode, buf(1, 2))
+
+    local entry = dissectors[dir] and dissectors[dir][opcode]
+    if entry == nil then
+        pinfo.cols.info = string.format("Unknown 0x%04X", opcode)
+        return buf:len()
+    end
+
+    pinfo.cols.info = entry.name
+    sub:append_text(" " .. entry.name)
+    local off = entry.fn(buf, sub, 3)
+    if off < buf:len() then
+        sub:add(f_trailing, buf(off))
+    end
+    return buf:len()
+end
+
+DissectorTable.get("wtap_encap"):add(wtap.USER0, proto)
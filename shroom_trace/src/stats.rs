@@ -0,0 +1,224 @@
+use std::{collections::BTreeMap, fmt::Write, path::Path, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::PacketDir;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpcodeStats {
+    pub count: u64,
+    pub bytes: u64,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl OpcodeStats {
+    fn new(len: usize) -> Self {
+        Self {
+            count: 1,
+            bytes: len as u64,
+            min: len,
+            max: len,
+        }
+    }
+
+    fn add(&mut self, len: usize) {
+        self.count += 1;
+        self.bytes += len as u64;
+        self.min = self.min.min(len);
+        self.max = self.max.max(len);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.bytes += other.bytes;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.bytes as f64 / self.count as f64
+    }
+}
+
+/// Traffic per direction and opcode
+#[derive(Debug, Clone, Default)]
+pub struct TrafficStats {
+    opcodes: BTreeMap<(PacketDir, u16), OpcodeStats>,
+}
+
+/// Row of a stats report, rates are per second
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsEntry {
+    pub dir: PacketDir,
+    pub opcode: u16,
+    #[serde(flatten)]
+    pub stats: OpcodeStats,
+    pub avg: f64,
+    pub packets_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+impl StatsEntry {
+    fn new(dir: PacketDir, opcode: u16, stats: OpcodeStats, secs: f64) -> Self {
+        Self {
+            dir,
+            opcode,
+            stats,
+            avg: stats.avg(),
+            packets_per_sec: stats.count as f64 / secs,
+            bytes_per_sec: stats.bytes as f64 / secs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub duration_secs: f64,
+    pub opcodes: Vec<StatsEntry>,
+    /// Total per direction
+    pub totals: Vec<StatsEntry>,
+}
+
+impl TrafficStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a packet, the length includes the opcode
+    pub fn record(&mut self, dir: PacketDir, opcode: u16, len: usize) {
+        self.opcodes
+            .entry((dir, opcode))
+            .and_modify(|stats| stats.add(len))
+            .or_insert_with(|| OpcodeStats::new(len));
+    }
+
+    pub fn get(&self, dir: PacketDir, opcode: u16) -> Option<&OpcodeStats> {
+        self.opcodes.get(&(dir, opcode))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.opcodes.is_empty()
+    }
+
+    fn total(&self, dir: PacketDir) -> Option<OpcodeStats> {
+        self.opcodes
+            .iter()
+            .filter(|((d, _), _)| *d == dir)
+            .map(|(_, stats)| *stats)
+            .reduce(|mut acc, stats| {
+                acc.merge(&stats);
+                acc
+            })
+    }
+
+    /// Report over the given duration, opcodes are sorted by their byte total
+    pub fn report(&self, duration: Duration) -> StatsReport {
+        // Avoids infinite rates for very short sessions
+        let secs = duration.as_secs_f64().max(0.001);
+        let mut opcodes = self
+            .opcodes
+            .iter()
+            .map(|(&(dir, opcode), &stats)| StatsEntry::new(dir, opcode, stats, secs))
+            .collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| {
+            b.stats
+                .bytes
+                .cmp(&a.stats.bytes)
+                .then((a.dir, a.opcode).cmp(&(b.dir, b.opcode)))
+        });
+
+        let totals = [PacketDir::Send, PacketDir::Recv]
+            .into_iter()
+            .filter_map(|dir| {
+                self.total(dir)
+                    .map(|stats| StatsEntry::new(dir, 0, stats, secs))
+            })
+            .collect();
+
+        StatsReport {
+            duration_secs: duration.as_secs_f64(),
+            opcodes,
+            totals,
+        }
+    }
+}
+
+fn write_row(out: &mut String, name: &str, e: &StatsEntry) {
+    writeln!(
+        out,
+        "{:<5} {:<8} {:>8} {:>10} {:>6} {:>6} {:>8.1} {:>8.2} {:>10.1}",
+        format!("{:?}", e.dir),
+        name,
+        e.stats.count,
+        e.stats.bytes,
+        e.stats.min,
+        e.stats.max,
+        e.avg,
+        e.packets_per_sec,
+        e.bytes_per_sec
+    )
+    .unwrap();
+}
+
+impl StatsReport {
+    /// Text table with the top opcodes and the totals
+    pub fn table(&self, top: usize) -> String {
+        let mut out = String::new();
+        writeln!(out, "Traffic over {:.1}s", self.duration_secs).unwrap();
+        writeln!(
+            out,
+            "{:<5} {:<8} {:>8} {:>10} {:>6} {:>6} {:>8} {:>8} {:>10}",
+            "Dir", "Opcode", "Count", "Bytes", "Min", "Max", "Avg", "Pkt/s", "B/s"
+        )
+        .unwrap();
+        for e in self.opcodes.iter().take(top) {
+            write_row(&mut out, &format!("{:#06x}", e.opcode), e);
+        }
+        for e in &self.totals {
+            write_row(&mut out, "total", e);
+        }
+        out
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json).with_context(|| format!("Writing stats file {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate() {
+        let mut stats = TrafficStats::new();
+        stats.record(PacketDir::Recv, 0x10, 10);
+        stats.record(PacketDir::Recv, 0x10, 30);
+        stats.record(PacketDir::Recv, 0x20, 100);
+        stats.record(PacketDir::Send, 0x10, 4);
+
+        let op = stats.get(PacketDir::Recv, 0x10).unwrap();
+        assert_eq!((op.count, op.bytes, op.min, op.max), (2, 40, 10, 30));
+        assert_eq!(op.avg(), 20.0);
+
+        let report = stats.report(Duration::from_secs(2));
+        assert_eq!(report.opcodes[0].opcode, 0x20);
+        assert_eq!(report.opcodes[1].packets_per_sec, 1.0);
+        assert_eq!(report.opcodes[1].bytes_per_sec, 20.0);
+
+        let send = &report.totals[0];
+        assert_eq!((send.dir, send.stats.bytes), (PacketDir::Send, 4));
+        let recv = &report.totals[1];
+        assert_eq!((recv.stats.count, recv.stats.bytes), (3, 140));
+        assert_eq!((recv.stats.min, recv.stats.max), (10, 100));
+
+        let json = serde_json::to_string(&report).unwrap();
+        let parsed: StatsReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, report);
+        assert!(report.table(10).contains("0x0020"));
+    }
+}
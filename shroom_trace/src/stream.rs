@@ -0,0 +1,279 @@
+//! Live packet stream over a localhost TCP socket.
+//!
+//! Every connected client receives one JSON encoded [`StreamRecord`] per line(JSONL),
+//! starting with the first packet after It connected. Slow clients miss records
+//! instead of stalling the game, see [`StreamServer::dropped`].
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    packet_struct::{PacketStruct, PacketTraceRecord},
+    PacketDir,
+};
+
+/// Record of the stream, a trace record with its direction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamRecord {
+    pub dir: PacketDir,
+    pub strct: PacketStruct,
+    pub data: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl StreamRecord {
+    pub fn new(dir: PacketDir, record: PacketTraceRecord) -> Self {
+        Self {
+            dir,
+            strct: record.strct,
+            data: record.data,
+            seq: record.seq,
+        }
+    }
+
+    pub fn into_trace_record(self) -> PacketTraceRecord {
+        PacketTraceRecord {
+            strct: self.strct,
+            data: self.data,
+            seq: self.seq,
+        }
+    }
+}
+
+type Clients = Arc<Mutex<Vec<SyncSender<Arc<str>>>>>;
+
+/// Streams records to all connected clients. The server runs for the lifetime of the process
+#[derive(Debug)]
+pub struct StreamServer {
+    tx: SyncSender<StreamRecord>,
+    addr: SocketAddr,
+    clients: Clients,
+    dropped: Arc<AtomicU64>,
+}
+
+fn run_client(mut stream: TcpStream, rx: Receiver<Arc<str>>) {
+    while let Ok(line) = rx.recv() {
+        if stream.write_all(line.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+fn run_accept(listener: TcpListener, clients: Clients, capacity: usize) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("Failed to accept stream client: {err:?}");
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        log::info!("Packet stream client connected: {:?}", stream.peer_addr());
+
+        let (tx, rx) = sync_channel(capacity);
+        std::thread::spawn(move || run_client(stream, rx));
+        clients.lock().unwrap().push(tx);
+    }
+}
+
+fn run_broadcast(rx: Receiver<StreamRecord>, clients: Clients, dropped: Arc<AtomicU64>) {
+    while let Ok(record) = rx.recv() {
+        let line = match serde_json::to_string(&record) {
+            Ok(json) => Arc::<str>::from(json + "\n"),
+            Err(err) => {
+                log::error!("Failed to serialize stream record: {err:?}");
+                continue;
+            }
+        };
+
+        // Disconnected clients are removed, full ones miss the record
+        clients
+            .lock()
+            .unwrap()
+            .retain(|client| match client.try_send(line.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+impl StreamServer {
+    /// Binds to the port on localhost, port 0 picks a free one.
+    /// `capacity` is the number of queued records per client
+    pub fn bind(port: u16, capacity: usize) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .with_context(|| format!("Binding packet stream to port {port}"))?;
+        let addr = listener.local_addr()?;
+        let clients = Clients::default();
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        std::thread::Builder::new()
+            .name("stream_accept".to_string())
+            .spawn({
+                let clients = clients.clone();
+                move || run_accept(listener, clients, capacity)
+            })?;
+
+        let (tx, rx) = sync_channel(capacity);
+        std::thread::Builder::new()
+            .name("stream_broadcast".to_string())
+            .spawn({
+                let clients = clients.clone();
+                let dropped = dropped.clone();
+                move || run_broadcast(rx, clients, dropped)
+            })?;
+
+        Ok(Self {
+            tx,
+            addr,
+            clients,
+            dropped,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Queues a record without blocking, It's dropped If the broadcast thread is behind
+    pub fn publish(&self, record: StreamRecord) {
+        if self.client_count() == 0 {
+            return;
+        }
+
+        if self.tx.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of records, which were not delivered to a client
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Client of the packet stream
+#[derive(Debug)]
+pub struct StreamReader {
+    stream: BufReader<TcpStream>,
+    line: String,
+}
+
+impl StreamReader {
+    pub fn connect(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr).context("Connecting to packet stream")?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+            line: String::new(),
+        })
+    }
+
+    /// Reads the next record, `None` once the server closed the stream
+    pub fn next_record(&mut self) -> anyhow::Result<Option<StreamRecord>> {
+        self.line.clear();
+        if self.stream.read_line(&mut self.line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            serde_json::from_str(&self.line).context("Invalid stream record")?,
+        ))
+    }
+}
+
+/// Parses a saved stream, like one recorded with `nc 127.0.0.1 <port> > session.jsonl`
+pub fn parse_stream(s: &str) -> anyhow::Result<Vec<StreamRecord>> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).with_context(|| format!("Invalid stream record {i}"))
+        })
+        .collect()
+}
+
+pub fn read_stream_file(path: impl AsRef<Path>) -> anyhow::Result<Vec<StreamRecord>> {
+    let path = path.as_ref();
+    let s = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading stream file {}", path.display()))?;
+    parse_stream(&s)
+}
+
+impl Iterator for StreamReader {
+    type Item = anyhow::Result<StreamRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::packet_struct::{PacketStructElem, PacketStructTy};
+
+    use super::*;
+
+    fn record(opcode: u16) -> StreamRecord {
+        let mut strct = PacketStruct::new_recv();
+        strct.add_elem(PacketStructElem::new(0, 0x1000, PacketStructTy::I16));
+        strct.set_opcode(opcode);
+        StreamRecord {
+            dir: PacketDir::Recv,
+            strct,
+            data: Some(opcode.to_le_bytes().to_vec()),
+            seq: None,
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        let server = StreamServer::bind(0, 64).unwrap();
+        let mut readers = (0..2)
+            .map(|_| StreamReader::connect(server.local_addr()).unwrap())
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        while server.client_count() < 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "clients not accepted"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        for op in 0..10 {
+            server.publish(record(op));
+        }
+
+        for reader in &mut readers {
+            for op in 0..10 {
+                let rec = reader.next().unwrap().unwrap();
+                assert_eq!(rec.dir, PacketDir::Recv);
+                assert_eq!(rec.into_trace_record().opcode(), Some(op));
+            }
+        }
+        assert_eq!(server.dropped(), 0);
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, Context};
+
+/// Maps addresses to the symbol of the function, which contains them.
+/// The text format has one `<hex address> <name>` pair per line.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolMap {
+    syms: BTreeMap<usize, String>,
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, addr: usize, name: impl Into<String>) {
+        self.syms.insert(addr, name.into());
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut map = Self::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (addr, name) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("Invalid symbol line {i}: {line}"))?;
+            let addr = usize::from_str_radix(addr.trim_start_matches("0x"), 16)
+                .with_context(|| format!("Invalid symbol address in line {i}"))?;
+            map.insert(addr, name.trim());
+        }
+        Ok(map)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading symbol file {}", path.display()))?;
+        Self::parse(&s)
+    }
+
+    /// Symbol of the closest function start before the address
+    pub fn lookup(&self, addr: usize) -> Option<&str> {
+        self.syms
+            .range(..=addr)
+            .next_back()
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+/// Converts a symbol like `CLogin::SendCheckPasswordPacket` to `send_check_password_packet`
+pub fn sym_to_snake(sym: &str) -> String {
+    let name = sym.rsplit("::").next().unwrap_or(sym);
+    let mut out = String::with_capacity(name.len() + 4);
+    let mut prev_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            if prev_lower {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+            prev_lower = false;
+        } else if c.is_ascii_alphanumeric() {
+            out.push(c);
+            prev_lower = true;
+        } else {
+            if !out.is_empty() && !out.ends_with('_') {
+                out.push('_');
+            }
+            prev_lower = false;
+        }
+    }
+    out.trim_matches('_').to_string()
+}
+
+/// Converts a symbol like `CLogin::SendCheckPasswordPacket` to `CLoginSendCheckPasswordPacket`
+pub fn sym_to_camel(sym: &str) -> String {
+    sym.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            let first = chars.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(chars).collect::<String>()
+        })
+        .collect()
+}
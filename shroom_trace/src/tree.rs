@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+use crate::{packet_struct::PacketStruct, symbols::SymbolMap};
+
+/// Item of a structure tree, elements are indices into the flat element list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeItem {
+    Elem(usize),
+    Node(TreeNode),
+}
+
+/// Sub structure, which was read or written by a nested helper call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeNode {
+    /// Return address of the call to the helper
+    pub call_site: usize,
+    /// Address inside of the helper, used to symbolize It
+    pub callee_addr: usize,
+    pub items: Vec<TreeItem>,
+}
+
+/// Innermost caller frame, which is shared by all stacks with callers
+fn find_anchor(stacks: &[Vec<usize>]) -> Option<usize> {
+    let with_callers = stacks.iter().filter(|s| s.len() > 1).collect::<Vec<_>>();
+    let first = with_callers.first()?;
+    first
+        .iter()
+        .skip(1)
+        .copied()
+        .find(|addr| with_callers.iter().all(|s| s[1..].contains(addr)))
+}
+
+fn container_at(root: &mut Vec<TreeItem>, depth: usize) -> &mut Vec<TreeItem> {
+    let mut items = root;
+    for _ in 0..depth {
+        items = match items.last_mut() {
+            Some(TreeItem::Node(node)) => &mut node.items,
+            _ => unreachable!("open path without a node"),
+        };
+    }
+    items
+}
+
+/// Builds a tree out of the frames(return address followed by the callers) of each element.
+/// Consecutive elements with the same helper call site are grouped into a node,
+/// frames above the innermost common caller are ignored. Elements without callers(gaps or
+/// traces without call stacks) stay in the current node.
+pub fn build_tree(stacks: &[Vec<usize>]) -> Vec<TreeItem> {
+    let anchor = find_anchor(stacks);
+    let mut root = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+
+    for (i, frames) in stacks.iter().enumerate() {
+        let callers = frames.get(1..).unwrap_or_default();
+        if callers.is_empty() {
+            container_at(&mut root, open.len()).push(TreeItem::Elem(i));
+            continue;
+        }
+
+        let end = anchor
+            .and_then(|anchor| callers.iter().position(|&addr| addr == anchor))
+            .unwrap_or(callers.len());
+        // Path from the outermost to the innermost call, paired with an address inside the callee
+        let path = (0..end)
+            .rev()
+            .map(|k| (callers[k], frames[k]))
+            .collect::<Vec<_>>();
+
+        let common = open
+            .iter()
+            .zip(&path)
+            .take_while(|(site, (path_site, _))| *site == path_site)
+            .count();
+        open.truncate(common);
+
+        for &(call_site, callee_addr) in &path[common..] {
+            container_at(&mut root, open.len()).push(TreeItem::Node(TreeNode {
+                call_site,
+                callee_addr,
+                items: Vec::new(),
+            }));
+            open.push(call_site);
+        }
+        container_at(&mut root, open.len()).push(TreeItem::Elem(i));
+    }
+
+    root
+}
+
+/// Tree of the elements of a traced structure
+pub fn struct_tree(strct: &PacketStruct) -> Vec<TreeItem> {
+    let stacks = strct
+        .elements()
+        .iter()
+        .map(|elem| elem.frames().collect())
+        .collect::<Vec<_>>();
+    build_tree(&stacks)
+}
+
+fn render_items(
+    out: &mut String,
+    strct: &PacketStruct,
+    items: &[TreeItem],
+    syms: &SymbolMap,
+    depth: usize,
+) {
+    let indent = "  ".repeat(depth);
+    for item in items {
+        match item {
+            TreeItem::Elem(i) => {
+                let elem = &strct.elements()[*i];
+                let sym = if elem.is_gap() {
+                    "gap"
+                } else {
+                    syms.lookup(elem.ret_address()).unwrap_or("")
+                };
+                writeln!(out, "{indent}{:#x} {:?} {sym}", elem.offset(), elem.ty()).unwrap();
+            }
+            TreeItem::Node(node) => {
+                let sym = syms.lookup(node.callee_addr).unwrap_or("unknown");
+                writeln!(out, "{indent}{sym} (called at {:#x})", node.call_site).unwrap();
+                render_items(out, strct, &node.items, syms, depth + 1);
+            }
+        }
+    }
+}
+
+/// Renders the nested elements of a structure, one line per element or sub structure
+pub fn render_tree(strct: &PacketStruct, syms: &SymbolMap) -> String {
+    let mut out = String::new();
+    render_items(&mut out, strct, &struct_tree(strct), syms, 0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_helpers() {
+        // Handler reads at 0x10, calls A at 0x12 which reads at 0x20 and calls B at 0x22
+        let stacks = vec![
+            vec![0x10, 0x1],
+            vec![0x20, 0x12, 0x1],
+            vec![0x30, 0x22, 0x12, 0x1],
+            vec![0x31, 0x22, 0x12, 0x1],
+            vec![0x11, 0x1],
+        ];
+        let tree = build_tree(&stacks);
+        let b = TreeNode {
+            call_site: 0x22,
+            callee_addr: 0x30,
+            items: vec![TreeItem::Elem(2), TreeItem::Elem(3)],
+        };
+        let a = TreeNode {
+            call_site: 0x12,
+            callee_addr: 0x20,
+            items: vec![TreeItem::Elem(1), TreeItem::Node(b)],
+        };
+        assert_eq!(
+            tree,
+            vec![TreeItem::Elem(0), TreeItem::Node(a), TreeItem::Elem(4)]
+        );
+    }
+
+    #[test]
+    fn no_call_stack() {
+        let stacks = vec![vec![0x10], vec![0x11]];
+        assert_eq!(
+            build_tree(&stacks),
+            vec![TreeItem::Elem(0), TreeItem::Elem(1)]
+        );
+    }
+
+    #[test]
+    fn anchor_shared_by_all() {
+        // 0x50 is only a caller of the first and the last stack
+        let stacks = vec![
+            vec![0x100, 0xa, 0x50, 0x1],
+            vec![0x101, 0xb, 0x1],
+            vec![0x102, 0xc, 0x50, 0x1],
+        ];
+        assert_eq!(find_anchor(&stacks), Some(0x1));
+    }
+}
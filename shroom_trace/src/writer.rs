@@ -0,0 +1,293 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::packet_struct::PacketTraceRecord;
+
+/// Behaviour, when the queue of the writer thread is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriterPolicy {
+    /// Waits for the writer, every record is written
+    #[default]
+    Block,
+    /// Drops the record and counts It
+    Drop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriterCfg {
+    #[serde(default = "WriterCfg::default_capacity")]
+    pub capacity: usize,
+    #[serde(default)]
+    pub policy: WriterPolicy,
+}
+
+impl WriterCfg {
+    fn default_capacity() -> usize {
+        4096
+    }
+}
+
+impl Default for WriterCfg {
+    fn default() -> Self {
+        Self {
+            capacity: Self::default_capacity(),
+            policy: WriterPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriterStats {
+    pub written: u64,
+    pub dropped: u64,
+}
+
+enum Msg {
+    Record(PacketTraceRecord),
+    /// Acknowledged, after the records before It were written and flushed
+    Flush(SyncSender<()>),
+}
+
+/// Serializes trace records on a background thread, in the same format as `parse_trace` reads them
+#[derive(Debug)]
+pub struct TraceWriter {
+    tx: Option<SyncSender<Msg>>,
+    thread: Option<JoinHandle<()>>,
+    policy: WriterPolicy,
+    counters: Arc<Counters>,
+}
+
+fn write_record(out: &mut impl Write, record: &PacketTraceRecord) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    writeln!(out, ",")?;
+    Ok(())
+}
+
+fn handle_record(out: &mut impl Write, record: PacketTraceRecord, counters: &Counters) {
+    match write_record(out, &record) {
+        Ok(()) => {
+            counters.written.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(err) => log::error!("Failed to write trace record: {err:?}"),
+    }
+}
+
+fn handle_msg(out: &mut impl Write, msg: Msg, counters: &Counters, acks: &mut Vec<SyncSender<()>>) {
+    match msg {
+        Msg::Record(record) => handle_record(out, record, counters),
+        Msg::Flush(ack) => acks.push(ack),
+    }
+}
+
+fn run_writer(mut out: impl Write, rx: Receiver<Msg>, counters: Arc<Counters>) {
+    let mut acks = Vec::new();
+    while let Ok(msg) = rx.recv() {
+        handle_msg(&mut out, msg, &counters, &mut acks);
+        // Drain the queue before flushing, so busy periods don't flush per record
+        while let Ok(msg) = rx.try_recv() {
+            handle_msg(&mut out, msg, &counters, &mut acks);
+        }
+        if let Err(err) = out.flush() {
+            log::error!("Failed to flush trace: {err:?}");
+        }
+        for ack in acks.drain(..) {
+            let _ = ack.send(());
+        }
+    }
+}
+
+impl TraceWriter {
+    pub fn new(out: impl Write + Send + 'static, cfg: &WriterCfg) -> Self {
+        let (tx, rx) = sync_channel(cfg.capacity);
+        let counters = Arc::new(Counters::default());
+        let thread = std::thread::Builder::new()
+            .name("trace_writer".to_string())
+            .spawn({
+                let counters = counters.clone();
+                move || run_writer(out, rx, counters)
+            })
+            .expect("trace writer thread");
+
+        Self {
+            tx: Some(tx),
+            thread: Some(thread),
+            policy: cfg.policy,
+            counters,
+        }
+    }
+
+    pub fn create(path: impl AsRef<Path>, cfg: &WriterCfg) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Creating trace file {}", path.display()))?;
+        Ok(Self::new(BufWriter::new(file), cfg))
+    }
+
+    /// Queues a record, returns the number of dropped records If It was dropped
+    pub fn send(&self, record: PacketTraceRecord) -> Option<u64> {
+        let tx = self.tx.as_ref().unwrap();
+        let record = Msg::Record(record);
+        let res = match self.policy {
+            WriterPolicy::Block => tx
+                .send(record)
+                .map_err(|err| TrySendError::Disconnected(err.0)),
+            WriterPolicy::Drop => tx.try_send(record),
+        };
+
+        match res {
+            Ok(()) => None,
+            Err(_) => Some(self.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1),
+        }
+    }
+
+    /// Waits until the queued records are written and flushed, used before the process exits.
+    /// Blocks regardless of the policy
+    pub fn flush(&self) {
+        let (ack, done) = sync_channel(1);
+        let tx = self.tx.as_ref().unwrap();
+        if tx.send(Msg::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+
+    pub fn stats(&self) -> WriterStats {
+        WriterStats {
+            written: self.counters.written.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread write the remaining records
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Mutex};
+
+    use crate::packet_struct::{parse_trace, PacketStruct, PacketStructElem, PacketStructTy};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Blocks every write until the gate is opened
+    struct GatedBuf(mpsc::Receiver<()>, bool);
+
+    impl Write for GatedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if !self.1 {
+                self.1 = self.0.recv().is_ok();
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(opcode: u16) -> PacketTraceRecord {
+        let mut strct = PacketStruct::new_recv();
+        strct.add_elem(PacketStructElem::new(0, 0x1000, PacketStructTy::I16));
+        strct.add_elem(PacketStructElem::new(2, 0x1010, PacketStructTy::I32));
+        strct.set_opcode(opcode);
+        PacketTraceRecord {
+            strct,
+            data: Some(vec![opcode as u8, 0, 1, 2, 3, 4]),
+            seq: None,
+        }
+    }
+
+    #[test]
+    fn write_in_order() {
+        let buf = SharedBuf::default();
+        let writer = TraceWriter::new(buf.clone(), &WriterCfg::default());
+        for op in 0..100 {
+            assert_eq!(writer.send(record(op)), None);
+        }
+        drop(writer);
+
+        let s = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let records = parse_trace(&s).unwrap();
+        assert_eq!(records.len(), 100);
+        for (op, rec) in records.iter().enumerate() {
+            assert_eq!(rec.opcode(), Some(op as u16));
+            assert_eq!(rec.data, record(op as u16).data);
+        }
+    }
+
+    #[test]
+    fn flush_written() {
+        let buf = SharedBuf::default();
+        let writer = TraceWriter::new(buf.clone(), &WriterCfg::default());
+        for op in 0..10 {
+            writer.send(record(op));
+        }
+        // The writer stays alive like the static loggers of the client
+        writer.flush();
+
+        let s = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(parse_trace(&s).unwrap().len(), 10);
+        assert_eq!(writer.stats().written, 10);
+    }
+
+    #[test]
+    fn drop_when_full() {
+        let (gate, rx) = mpsc::channel();
+        let cfg = WriterCfg {
+            capacity: 1,
+            policy: WriterPolicy::Drop,
+        };
+        let writer = TraceWriter::new(GatedBuf(rx, false), &cfg);
+        // The thread holds at most one record and the queue one more
+        for op in 0..4 {
+            writer.send(record(op));
+        }
+        gate.send(()).unwrap();
+
+        let counters = writer.counters.clone();
+        drop(writer);
+        let written = counters.written.load(Ordering::Relaxed);
+        let dropped = counters.dropped.load(Ordering::Relaxed);
+        assert!(dropped >= 2);
+        assert_eq!(written + dropped, 4);
+    }
+}
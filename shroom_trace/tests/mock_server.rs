@@ -0,0 +1,101 @@
+//! Plays a recorded login and a migration against the mock server over TCP
+
+use std::{
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use shroom_trace::{
+    mock::{Codec, CryptoCodec, Handshake, MockServer, Script},
+    packet_struct::PacketStruct,
+    stream::{parse_stream, StreamRecord},
+    PacketDir,
+};
+
+fn record(dir: PacketDir, data: &[u8]) -> StreamRecord {
+    let strct = match dir {
+        PacketDir::Send => PacketStruct::new_send(0x1000),
+        PacketDir::Recv => PacketStruct::new_recv(),
+    };
+    StreamRecord {
+        dir,
+        strct,
+        data: Some(data.to_vec()),
+        seq: None,
+    }
+}
+
+/// Login, world and character selection and the migration to the channel
+fn session() -> Vec<(PacketDir, Vec<u8>)> {
+    use PacketDir::*;
+    vec![
+        (Recv, vec![0x11, 0x00]),
+        (Send, vec![0x01, 0x00, b'a', b'b']),
+        (Recv, vec![0x00, 0x00, 0x00]),
+        (Send, vec![0x0b, 0x00]),
+        (Recv, vec![0x0a, 0x00, 0x01]),
+        (Recv, vec![0x0a, 0x00, 0xff]),
+        (Send, vec![0x05, 0x00, 0x00, 0x01]),
+        (Recv, vec![0x0b, 0x00, 0x00, 127, 0, 0, 1, 0x89, 0x21]),
+        // Channel connection
+        (Send, vec![0x14, 0x00, 0x01, 0x00, 0x00, 0x00]),
+        (Recv, vec![0x7d, 0x00, 0x01]),
+    ]
+}
+
+#[test]
+fn replay_session() {
+    let session = session();
+    // Records round trip through the saved stream format
+    let jsonl = session
+        .iter()
+        .map(|(dir, data)| serde_json::to_string(&record(*dir, data)).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let script = Script::from_records(parse_stream(&jsonl).unwrap());
+    assert_eq!(script.greeting.len(), 1);
+    assert_eq!(script.steps.len(), 4);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut server = MockServer::new(script);
+        server.run(&listener).unwrap();
+        server.script().is_done()
+    });
+
+    // Every connection starts with a handshake, which sets up the ciphers
+    let connect = || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let handshake = Handshake::read(&mut stream).unwrap();
+        assert_eq!(handshake, Handshake::default());
+        (stream, CryptoCodec::client(&handshake, true))
+    };
+    let expect = |(stream, codec): &mut (TcpStream, CryptoCodec), data: &[u8]| {
+        assert_eq!(codec.read_packet(stream).unwrap(), data);
+    };
+    let send = |(stream, codec): &mut (TcpStream, CryptoCodec), data: &[u8]| {
+        codec.write_packet(stream, data).unwrap();
+    };
+
+    // Login server, which also receives a ping missing in the recording
+    let mut conn = connect();
+    expect(&mut conn, &session[0].1);
+    send(&mut conn, &session[1].1);
+    expect(&mut conn, &session[2].1);
+    send(&mut conn, &[0x18, 0x00]);
+    send(&mut conn, &session[3].1);
+    expect(&mut conn, &session[4].1);
+    expect(&mut conn, &session[5].1);
+    send(&mut conn, &session[6].1);
+    expect(&mut conn, &session[7].1);
+    drop(conn);
+
+    // The migration continues the script without the greeting
+    let mut conn = connect();
+    send(&mut conn, &session[8].1);
+    expect(&mut conn, &session[9].1);
+    drop(conn);
+
+    assert!(server.join().unwrap());
+}
@@ -0,0 +1,173 @@
+//! Character list of the select world result.
+//!
+//! Only the names are kept, the remaining fields are skipped with the layout of
+//! `GW_CharacterStat` and `AvatarLook`, which is only known for v95. Other versions are rejected
+//! instead of reading misaligned names. The names are in slot order, which is the index of
+//! `CUIAvatar::SelectCharacter`.
+
+use anyhow::Context;
+
+use crate::shroom_ffi::addr;
+
+/// Client version of the layout
+const LAYOUT_VERSION: u16 = 95;
+const NAME_LEN: usize = 13;
+/// Slot, which terminates an equip list of the avatar look
+const EQUIP_LIST_END: u8 = 0xff;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let data = self
+            .data
+            .get(self.offset..self.offset + n)
+            .with_context(|| format!("Unexpected end of packet at {:#x}", self.offset))?;
+        self.offset += n;
+        Ok(data)
+    }
+
+    fn skip(&mut self, n: usize) -> anyhow::Result<()> {
+        self.read(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.read(2)?.try_into().unwrap()))
+    }
+}
+
+/// Evan and Resistance jobs encode the skill points per job level
+fn is_extend_sp_job(job: u16) -> bool {
+    job / 1000 == 3 || job / 100 == 22 || job == 2001
+}
+
+fn read_stat(r: &mut Reader) -> anyhow::Result<String> {
+    // Character id
+    r.skip(4)?;
+    let name = r.read(NAME_LEN)?;
+    let name = name.split(|&b| b == 0).next().unwrap_or_default();
+    let name = String::from_utf8_lossy(name).into_owned();
+
+    // Gender, skin, face, hair, pet ids and level
+    r.skip(1 + 1 + 4 + 4 + 3 * 8 + 1)?;
+    let job = r.u16()?;
+    // Str, dex, int, luk, hp, max hp, mp, max mp and ap
+    r.skip(4 * 2 + 4 * 4 + 2)?;
+    if is_extend_sp_job(job) {
+        let n = r.u8()?;
+        r.skip(n as usize * 2)?;
+    } else {
+        r.skip(2)?;
+    }
+    // Exp, fame, temp exp, field, portal, play time and sub job
+    r.skip(4 + 2 + 4 + 4 + 1 + 4 + 2)?;
+    Ok(name)
+}
+
+fn skip_avatar_look(r: &mut Reader) -> anyhow::Result<()> {
+    // Gender, skin, face, mega and hair
+    r.skip(1 + 1 + 4 + 1 + 4)?;
+    // Visible and masked equips
+    for _ in 0..2 {
+        while r.u8()? != EQUIP_LIST_END {
+            r.skip(4)?;
+        }
+    }
+    // Weapon sticker and pet ids
+    r.skip(4 + 3 * 4)
+}
+
+fn read_char(r: &mut Reader) -> anyhow::Result<String> {
+    let name = read_stat(r)?;
+    skip_avatar_look(r)?;
+    // On family and the optional world and job ranking
+    r.skip(1)?;
+    if r.u8()? != 0 {
+        r.skip(4 * 4)?;
+    }
+    Ok(name)
+}
+
+pub fn is_char_list(data: &[u8]) -> bool {
+    data.get(..2).is_some_and(|op| {
+        u16::from_le_bytes([op[0], op[1]]) == addr::RECV_SELECT_WORLD_RESULT_OPCODE
+    })
+}
+
+/// Names of the characters in slot order, the data starts with the opcode
+pub fn parse_names(data: &[u8], version: u16) -> anyhow::Result<Vec<String>> {
+    anyhow::ensure!(
+        version == LAYOUT_VERSION,
+        "The character list layout of v{version} is unknown, only v{LAYOUT_VERSION} is supported"
+    );
+    let mut r = Reader { data, offset: 2 };
+    let result = r.u8()?;
+    anyhow::ensure!(result == 0, "Select world failed: {result}");
+
+    let count = r.u8()?;
+    (0..count)
+        .map(|i| read_char(&mut r).with_context(|| format!("Decoding character {i}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a character, `extend_sp` is passed to check the job detection
+    fn encode_char(data: &mut Vec<u8>, name: &str, job: u16, extend_sp: bool, ranked: bool) {
+        data.extend_from_slice(&1000u32.to_le_bytes());
+        let mut name_buf = [0; NAME_LEN];
+        name_buf[..name.len()].copy_from_slice(name.as_bytes());
+        data.extend_from_slice(&name_buf);
+        data.extend_from_slice(&[0; 1 + 1 + 4 + 4 + 3 * 8 + 1]);
+        data.extend_from_slice(&job.to_le_bytes());
+        data.extend_from_slice(&[0; 4 * 2 + 4 * 4 + 2]);
+        if extend_sp {
+            // Two job levels with skill points
+            data.extend_from_slice(&[2, 1, 3, 2, 5]);
+        } else {
+            data.extend_from_slice(&[0; 2]);
+        }
+        data.extend_from_slice(&[0; 4 + 2 + 4 + 4 + 1 + 4 + 2]);
+
+        // Avatar look with two equips and one masked equip
+        data.extend_from_slice(&[0; 1 + 1 + 4 + 1 + 4]);
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 5, 0, 0, 0, 0, EQUIP_LIST_END]);
+        data.extend_from_slice(&[1, 0, 0, 0, 0, EQUIP_LIST_END]);
+        data.extend_from_slice(&[0; 4 + 3 * 4]);
+
+        data.push(0);
+        data.push(ranked as u8);
+        if ranked {
+            data.extend_from_slice(&[0; 4 * 4]);
+        }
+    }
+
+    #[test]
+    fn names() {
+        let mut data = addr::RECV_SELECT_WORLD_RESULT_OPCODE.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0, 4]);
+        encode_char(&mut data, "Warrior", 110, false, true);
+        encode_char(&mut data, "Evan", 2210, true, false);
+        encode_char(&mut data, "BattleMage", 3212, true, false);
+        encode_char(&mut data, "LongestName12", 0, false, true);
+        // Slot counts after the list
+        data.extend_from_slice(&[0; 9]);
+
+        assert!(is_char_list(&data));
+        assert_eq!(
+            parse_names(&data, 95).unwrap(),
+            ["Warrior", "Evan", "BattleMage", "LongestName12"]
+        );
+        assert!(parse_names(&data[..data.len() / 2], 95).is_err());
+        assert!(parse_names(&data, 92).is_err());
+    }
+}
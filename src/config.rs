@@ -1,7 +1,20 @@
 use serde::{Deserialize, Serialize};
 use widestring::U16CString;
 use windows::core::{PCSTR, PCWSTR};
-use std::{ffi::CString, fmt::Write, sync::OnceLock};
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fmt::Write,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::OnceLock,
+};
+
+use shroom_trace::{
+    fuzz::FuzzCfg, packet_struct::CompositeHelper, redact::RedactRule, writer::WriterCfg,
+    PacketDir,
+};
+
+use crate::{shroom_ffi::addr, util::packet_rules::PacketRules};
 
 #[derive(Debug)]
 pub struct Str(pub CString);
@@ -76,13 +89,83 @@ pub enum LogBackend {
     Stdout
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct AutoLoginData {
     pub username: Str,
     pub password: Str,
     pub world: Option<u32>,
     pub channel: Option<u32>,
     pub char_index: Option<u32>,
+    /// Selects the character by name from the character list, `char_index` is the fallback
+    pub char_name: Option<String>,
+    #[serde(default)]
+    pub retry: LoginRetryCfg,
+}
+
+/// Retries of the auto login, delays are in milliseconds
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoginRetryCfg {
+    /// Attempts including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for every further one
+    pub base_delay: u64,
+    pub max_delay: u64,
+    /// An attempt without a result fails after It
+    pub timeout: u64,
+}
+
+impl Default for LoginRetryCfg {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: 2000,
+            max_delay: 30000,
+            timeout: 10000,
+        }
+    }
+}
+
+// The password must never end up in logs or bug reports
+impl std::fmt::Debug for AutoLoginData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AutoLoginData")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .field("world", &self.world)
+            .field("channel", &self.channel)
+            .field("char_index", &self.char_index)
+            .field("char_name", &self.char_name)
+            .field("retry", &self.retry)
+            .finish()
+    }
+}
+
+/// Addresses of optional trace hooks, which override the version defaults
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TraceHookAddrs {
+    pub encode8: Option<usize>,
+    pub encode_double: Option<usize>,
+    pub decode8: Option<usize>,
+    pub decode_double: Option<usize>,
+}
+
+impl TraceHookAddrs {
+    pub fn encode8(&self) -> Option<usize> {
+        self.encode8.or(addr::COUTPACKET_ENCODE8)
+    }
+
+    pub fn encode_double(&self) -> Option<usize> {
+        self.encode_double.or(addr::COUTPACKET_ENCODE_DOUBLE)
+    }
+
+    pub fn decode8(&self) -> Option<usize> {
+        self.decode8.or(addr::CINPACKET_DECODE8)
+    }
+
+    pub fn decode_double(&self) -> Option<usize> {
+        self.decode_double.or(addr::CINPACKET_DECODE_DOUBLE)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -90,6 +173,185 @@ pub struct PacketTracingData {
     pub send_file: String,
     pub recv_file: String,
     pub log_data: bool,
+    #[serde(default)]
+    pub hook_addrs: TraceHookAddrs,
+    /// Client helpers like FILETIME or position decoding, which are traced as one element
+    #[serde(default)]
+    pub composite_helpers: Vec<CompositeHelper>,
+    /// Records the callers of every element, to reconstruct nested structures.
+    /// Walks the frame pointer chain of the client, so It relies on frame pointers in the callers
+    #[serde(default)]
+    pub call_stack: bool,
+    /// Queue of the background writer, `Drop` skips records instead of stalling the game thread
+    #[serde(default)]
+    pub writer: WriterCfg,
+    /// Localhost port of the live JSONL packet stream
+    #[serde(default)]
+    pub stream_port: Option<u16>,
+    /// String fields, which are masked in the logged data
+    #[serde(default = "PacketTracingData::default_redact")]
+    pub redact: Vec<RedactRule>,
+    /// Opcode to handler table, which is written after the session
+    #[serde(default)]
+    pub handler_map: Option<HandlerMapData>,
+    /// Receive handler coverage, which is merged across sessions
+    #[serde(default)]
+    pub coverage: Option<CoverageData>,
+    /// Raw Winsock streams of the game socket, correlated with the traces by sequence
+    #[serde(default)]
+    pub raw_capture: Option<RawCaptureData>,
+}
+
+impl PacketTracingData {
+    fn default_redact() -> Vec<RedactRule> {
+        vec![RedactRule {
+            dir: PacketDir::Send,
+            opcode: addr::SEND_CHECK_PASSWORD_OPCODE,
+            offset: None,
+        }]
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HandlerMapData {
+    /// JSON file, the previous table is loaded and merged with the new session
+    pub file: String,
+    /// Optional CSV export of the merged table
+    #[serde(default)]
+    pub csv: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CoverageData {
+    /// JSON file, the previous coverage is loaded and merged with the new session
+    pub file: String,
+    /// List of the known receive opcodes, one `<hex opcode> [name]` per line
+    #[serde(default)]
+    pub opcodes: Option<String>,
+    /// JSON report of the seen, incomplete and never seen opcodes, requires `opcodes`
+    #[serde(default)]
+    pub report: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RawCaptureData {
+    /// Directory of the stream files and the index, the index of a previous session is replaced
+    pub dir: String,
+    /// Captures with the auto login, the redaction of the traces doesn't apply to the streams
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+/// Rewrites the server addresses, which the client connects to
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RedirectData {
+    /// Login server as `host:port`, replaces the connects to the original login server
+    #[serde(default)]
+    pub login: Option<String>,
+    /// Addresses of the original login server, by default every connect to `login_port` is
+    /// replaced
+    #[serde(default)]
+    pub login_orig: Vec<SocketAddrV4>,
+    /// Port of the original login server, 8484 If unset
+    #[serde(default)]
+    pub login_port: Option<u16>,
+    /// Channel and migration addresses sent by the server, mapped to `host:port`
+    #[serde(default)]
+    pub migrations: BTreeMap<SocketAddrV4, String>,
+    /// Hosts-style mapping, which keeps the port. Applied to addresses without another mapping
+    #[serde(default)]
+    pub hosts: BTreeMap<Ipv4Addr, Ipv4Addr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ProxyKind {
+    Socks5,
+    /// HTTP proxy, which supports the `CONNECT` method
+    Http,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Proxy for the game connections, the redirected address is the target
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProxyData {
+    pub kind: ProxyKind,
+    /// Proxy server as `host:port`
+    pub addr: String,
+    #[serde(default)]
+    pub auth: Option<ProxyAuth>,
+    /// Timeout of the connect and the handshake in milliseconds, It blocks the game thread
+    #[serde(default = "ProxyData::default_timeout")]
+    pub timeout: u64,
+}
+
+impl ProxyData {
+    fn default_timeout() -> u64 {
+        1000
+    }
+}
+
+/// Simulated conditions of one direction
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetCondition {
+    /// Added to every packet in milliseconds
+    pub latency: u64,
+    /// Maximum random latency on top in milliseconds, the packet order is kept
+    pub jitter: u64,
+    /// Bytes per second, unlimited without
+    pub bandwidth: Option<u64>,
+    /// Chance to hold a packet back by `reorder_delay`, so later packets overtake It
+    pub reorder: f64,
+    /// Milliseconds
+    pub reorder_delay: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetSimProfile {
+    pub send: NetCondition,
+    pub recv: NetCondition,
+}
+
+/// Network condition simulator, the delays are reproducible with the same seed
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NetSimData {
+    /// Name of the active profile
+    pub profile: String,
+    #[serde(default)]
+    pub seed: u64,
+    pub profiles: BTreeMap<String, NetSimProfile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TrafficStatsData {
+    /// JSON file, which is rewritten with every report
+    pub file: String,
+    /// Seconds between the periodic reports
+    pub interval: u64,
+    /// Number of opcodes in the logged table
+    #[serde(default = "TrafficStatsData::default_top")]
+    pub top: usize,
+}
+
+impl TrafficStatsData {
+    fn default_top() -> usize {
+        20
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -170,9 +432,48 @@ pub struct Config {
     pub handle_exceptions: bool,
     pub wz: WzData,
     pub lazy_tmpl_loading: bool,
+    #[serde(default)]
+    pub packet_rules: PacketRules,
+    #[serde(default)]
+    pub traffic_stats: Option<TrafficStatsData>,
+    /// Mutates received packets to find client crashes, never enable It on a real server
+    #[serde(default)]
+    pub fuzz: Option<FuzzCfg>,
+    #[serde(default)]
+    pub redirect: Option<RedirectData>,
+    #[serde(default)]
+    pub proxy: Option<ProxyData>,
+    #[serde(default)]
+    pub net_sim: Option<NetSimData>,
 }
 
 impl Config {
+    /// Whether the Winsock `connect` is hooked, which the raw capture requires to find the game
+    /// socket
+    pub fn hooks_connect(&self) -> bool {
+        self.redirect.is_some() || self.proxy.is_some() || self.captures_raw()
+    }
+
+    /// Raw capture, which is refused with the auto login unless It's allowed explicitly. The
+    /// captured login packet and handshake let `trace_decrypt` recover the password
+    pub fn raw_capture(&self) -> Option<&RawCaptureData> {
+        self.packet_tracing
+            .as_ref()?
+            .raw_capture
+            .as_ref()
+            .filter(|raw| raw.allow_credentials || self.auto_login_data.is_none())
+    }
+
+    /// Whether the Winsock IO is hooked for the raw capture
+    pub fn captures_raw(&self) -> bool {
+        self.raw_capture().is_some()
+    }
+
+    /// Whether session data is exported periodically and on exit
+    pub fn exports_session(&self) -> bool {
+        self.packet_tracing.is_some() || self.traffic_stats.is_some()
+    }
+
     pub fn window_title(&self) -> Option<CString> {
         let wnd = self.window_data.as_ref()?;
 
@@ -186,6 +487,16 @@ impl Config {
         }
         Some(CString::new(name).expect("cstr"))
     }
+
+    /// Whether the send and process hooks of the client socket are required
+    pub fn needs_socket_hooks(&self) -> bool {
+        self.packet_tracing.is_some()
+            || self.auto_login_data.is_some()
+            || !self.packet_rules.is_empty()
+            || self.traffic_stats.is_some()
+            || self.fuzz.is_some()
+            || self.net_sim.is_some()
+    }
 }
 
 impl Default for Config {
@@ -201,6 +512,8 @@ impl Default for Config {
                 world: Some(0),
                 channel: Some(0),
                 char_index: Some(0),
+                char_name: None,
+                retry: LoginRetryCfg::default(),
             }),
             window_data: Some(WindowData {
                 name: "DinputStory".to_string(),
@@ -211,6 +524,12 @@ impl Default for Config {
                 send_file: "send_packets.txt".to_string(),
                 recv_file: "recv_packets.txt".to_string(),
                 log_data: false,
+                hook_addrs: TraceHookAddrs::default(),
+                composite_helpers: Vec::default(),
+                call_stack: false,
+                writer: WriterCfg::default(),
+                stream_port: None,
+                redact: PacketTracingData::default_redact(),
             }),*/
             packet_tracing: None,
             multi_jump: Some(2),
@@ -221,7 +540,13 @@ impl Default for Config {
                 version: WString::new("95"),
                 path: Some("wz95".to_string())
             }),
-            lazy_tmpl_loading: true
+            lazy_tmpl_loading: true,
+            packet_rules: PacketRules::default(),
+            traffic_stats: None,
+            fuzz: None,
+            redirect: None,
+            proxy: None,
+            net_sim: None,
             /*wz: WzData::Image(
                 WzImageData {
                     path: "Data".to_string(),
@@ -250,4 +575,12 @@ mod tests {
         gen_default_config();
         toml::from_str::<Config>(&std::fs::read_to_string("config.toml").unwrap()).unwrap();        
     }
+
+    #[test]
+    fn auto_login_debug() {
+        let cfg = Config::default();
+        let dbg = format!("{cfg:?}");
+        assert!(dbg.contains("<redacted>"));
+        assert!(!dbg.contains("test1234"));
+    }
 }
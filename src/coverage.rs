@@ -0,0 +1,52 @@
+use shroom_trace::{
+    coverage::{Coverage, KnownOpcodes},
+    packet_struct::PacketStruct,
+    PacketDir,
+};
+
+use crate::{
+    config::{CoverageData, CONFIG},
+    session::Session,
+};
+
+static SESSION: Session<Coverage> = Session::new();
+
+fn coverage_data() -> Option<&'static CoverageData> {
+    CONFIG.get()?.packet_tracing.as_ref()?.coverage.as_ref()
+}
+
+/// Records a traced structure, only processed packets are covered
+pub fn record(dir: PacketDir, strct: &PacketStruct) {
+    if dir != PacketDir::Recv || coverage_data().is_none() {
+        return;
+    }
+
+    SESSION.record(|coverage| coverage.record(strct));
+}
+
+fn save(coverage: &mut Coverage, data: &CoverageData) -> anyhow::Result<()> {
+    coverage.merge(Coverage::load(&data.file)?);
+    coverage.save(&data.file)?;
+
+    if let (Some(opcodes), Some(report_file)) = (&data.opcodes, &data.report) {
+        let report = coverage.report(&KnownOpcodes::load(opcodes)?);
+        log::info!("Handler coverage: {:.1}%", report.ratio() * 100.0);
+        report.save(report_file)?;
+    }
+    Ok(())
+}
+
+/// Merges the coverage recorded since the last export with the saved coverage
+pub fn export() {
+    let Some(data) = coverage_data() else {
+        return;
+    };
+
+    let mut coverage = SESSION.take();
+    if coverage.is_empty() {
+        return;
+    }
+    if let Err(err) = save(&mut coverage, data) {
+        log::error!("Failed to write coverage: {err:?}");
+    }
+}
@@ -2,23 +2,28 @@ use std::{
     ffi::{c_int, c_uint, c_void, CStr},
     fs::OpenOptions,
     io::Write,
+    sync::OnceLock,
 };
 
 use chrono::Local;
 use windows::Win32::{
     Foundation::NTSTATUS,
     System::{
-        Diagnostics::Debug::{AddVectoredExceptionHandler, EXCEPTION_POINTERS, EXCEPTION_RECORD},
+        Diagnostics::Debug::{
+            AddVectoredExceptionHandler, CONTEXT, EXCEPTION_POINTERS, EXCEPTION_RECORD,
+        },
         Kernel::ExceptionContinueSearch,
     },
 };
 
 use crate::{
     config::CONFIG,
+    fuzz,
     shroom_ffi::{
         error_codes::ClientErrorCode,
         ztl::{ZException, ZEXCEPTION_MAGIC},
     },
+    socket::finish_incomplete_recv,
     util::stack_walker::StackWalker,
 };
 
@@ -145,6 +150,9 @@ fn write_trace(
 
     writeln!(f, "{:-<80}", "")?;
     writeln!(f, "Exception at {}", Local::now())?;
+    if let Some(case) = fuzz::current_case() {
+        writeln!(f, "{case}")?;
+    }
 
     if let Some(cxx_ex) = cxx_ex {
         if let Some(zex) = cxx_ex.as_zexception() {
@@ -206,26 +214,17 @@ fn write_trace(
 }
 
 #[derive(Debug, Default)]
-pub struct ExceptionHandler {
-    init_sym: bool
-}
+pub struct ExceptionHandler;
 
 impl ExceptionHandler {
     pub const fn new() -> Self {
-        ExceptionHandler {
-            init_sym: false
-        }
+        ExceptionHandler
     }
 
     pub unsafe fn handle_ex(&mut self, info: &EXCEPTION_POINTERS, record: &EXCEPTION_RECORD) {
         let walker = info.ContextRecord.as_ref().map(|ctx| {
             let walker = StackWalker::from_ctx(*ctx);
-            if !self.init_sym {
-                self.init_sym = true;
-                if let Err(err) = load_init_sym(&walker) {
-                    log::error!("Failed to init sym: {:?}", err);
-                };
-            }
+            init_symbols(&walker);
             walker
         });
     
@@ -240,6 +239,21 @@ impl ExceptionHandler {
 static EXCEPTION_HANDLER: std::sync::Mutex<ExceptionHandler> = std::sync::Mutex::new(ExceptionHandler::new());
 
 
+static SYM_INIT: OnceLock<bool> = OnceLock::new();
+/// Frames searched for `_CxxThrowException`
+const MAX_THROW_FRAMES: usize = 4;
+
+/// Initializes the symbol handler and loads the pdb once per process, returns false If It failed
+pub fn init_symbols(sw: &StackWalker) -> bool {
+    *SYM_INIT.get_or_init(|| match load_init_sym(sw) {
+        Ok(()) => true,
+        Err(err) => {
+            log::error!("Failed to init sym: {:?}", err);
+            false
+        }
+    })
+}
+
 fn load_init_sym(sw: &StackWalker) -> windows::core::Result<()> {
     sw.sym_init()?;
     let cfg = CONFIG.get().unwrap();
@@ -249,6 +263,31 @@ fn load_init_sym(sw: &StackWalker) -> windows::core::Result<()> {
     Ok(())
 }
 
+/// Return address into the code, which threw the C++ exception. The exception address is inside
+/// of `RaiseException`, which is called by `_CxxThrowException`
+fn throw_ret_addr(ctx: &CONTEXT) -> Option<usize> {
+    let mut walker = StackWalker::from_ctx(*ctx);
+    init_symbols(&walker);
+    let mut fallback = None;
+    let mut in_throw = false;
+    for i in 0..MAX_THROW_FRAMES {
+        let Ok(Some(frame)) = walker.get_next_frame() else {
+            break;
+        };
+        if in_throw {
+            return Some(frame.ret as usize);
+        }
+        // Without symbols the thrower is the caller of the second frame
+        if i == 1 {
+            fallback = Some(frame.ret as usize);
+        }
+        in_throw = frame
+            .sym
+            .is_some_and(|sym| sym.to_bytes() == b"_CxxThrowException");
+    }
+    fallback
+}
+
 unsafe extern "system" fn exception_handler(exception_info: *mut EXCEPTION_POINTERS) -> i32 {
     let Some(info) = exception_info.as_ref() else {
         return ExceptionContinueSearch.0;
@@ -265,6 +304,14 @@ unsafe extern "system" fn exception_handler(exception_info: *mut EXCEPTION_POINT
 
     log::error!("Exception at: {:p} - code: {:?}", record.ExceptionAddress, record.ExceptionCode);
 
+    // Handlers throw a ZException, when a packet is malformed
+    let is_zexception = CxxThrowException::try_from(record)
+        .is_ok_and(|cxx_ex| cxx_ex.as_zexception().is_some());
+    if is_zexception {
+        let ret_addr = info.ContextRecord.as_ref().and_then(throw_ret_addr);
+        finish_incomplete_recv(ret_addr.unwrap_or(record.ExceptionAddress as usize));
+    }
+
     if let Ok(mut handler) = EXCEPTION_HANDLER.try_lock() {
         handler.handle_ex(info, record);
     }
@@ -0,0 +1,74 @@
+use std::sync::{LazyLock, Mutex};
+
+use shroom_trace::{
+    fuzz::{FuzzCase, Fuzzer},
+    packet_struct::PacketStruct,
+    PacketDir,
+};
+
+use crate::{
+    config::CONFIG,
+    shroom_ffi::socket::{CInPacket, OwnedInPacket},
+    util::{packet_rules::HexBytes, packet_schema::ShroomPacket},
+};
+
+static FUZZER: LazyLock<Option<Mutex<Fuzzer>>> = LazyLock::new(|| {
+    let cfg = CONFIG.get()?.fuzz.clone()?;
+    log::warn!("Fuzzing received packets with seed: {:#x}", cfg.seed);
+    Some(Mutex::new(Fuzzer::new(cfg)))
+});
+
+/// Case of the packet, which is currently processed, with the original data
+static CURRENT_CASE: Mutex<Option<(FuzzCase, Vec<u8>)>> = Mutex::new(None);
+
+/// Learns the layout of a traced structure, which guides the mutations
+pub fn learn(dir: PacketDir, strct: &PacketStruct) {
+    if dir != PacketDir::Recv {
+        return;
+    }
+
+    if let Some(fuzzer) = FUZZER.as_ref() {
+        fuzzer.lock().expect("fuzzer").learn(strct);
+    }
+}
+
+/// Mutated copy of the packet, If It's selected for fuzzing
+pub fn mutate(pkt: &CInPacket) -> Option<OwnedInPacket> {
+    let fuzzer = FUZZER.as_ref()?;
+    // A case, which threw, is never finished
+    let mut current = CURRENT_CASE.lock().expect("fuzz case");
+    *current = None;
+
+    let original = pkt.data().to_vec();
+    let mut data = original.clone();
+    let case = fuzzer.lock().expect("fuzzer").fuzz(&mut data)?;
+
+    log::info!("Fuzz {case}");
+    *current = Some((case, original));
+    Some(OwnedInPacket::with_data(pkt, CInPacket::DATA_OFFSET, &data))
+}
+
+/// Called once the mutated packet was processed without an exception
+pub fn finish_case() {
+    *CURRENT_CASE.lock().expect("fuzz case") = None;
+}
+
+/// Whether a structure is traced from a mutated packet, which must not be learned or recorded.
+/// It's called from the exception handler, so the lock is only tried
+pub fn is_mutated(dir: PacketDir) -> bool {
+    dir == PacketDir::Recv
+        && CURRENT_CASE
+            .try_lock()
+            .is_ok_and(|current| current.is_some())
+}
+
+/// Description of the in-flight case for the exception log, the lock is only tried
+pub fn current_case() -> Option<String> {
+    let current = CURRENT_CASE.try_lock().ok()?;
+    let (case, original) = current.as_ref()?;
+    let json = serde_json::to_string(case).ok()?;
+    Some(format!(
+        "Fuzz {case}\nFuzz case: {json}\nOriginal data: {}",
+        HexBytes(original.clone())
+    ))
+}
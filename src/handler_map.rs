@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use shroom_trace::{handlers::HandlerMap, packet_struct::PacketStruct, PacketDir};
+use windows::Win32::System::Diagnostics::Debug::CONTEXT;
+
+use crate::{
+    config::{HandlerMapData, CONFIG},
+    exceptions::init_symbols,
+    session::Session,
+    util::stack_walker::StackWalker,
+};
+
+static SESSION: Session<HandlerMap> = Session::new();
+
+fn handler_map_data() -> Option<&'static HandlerMapData> {
+    CONFIG.get()?.packet_tracing.as_ref()?.handler_map.as_ref()
+}
+
+/// Records the handler of a traced structure
+pub fn record(dir: PacketDir, strct: &PacketStruct) {
+    if handler_map_data().is_none() {
+        return;
+    }
+
+    SESSION.record(|map| map.record(dir, strct));
+}
+
+fn save(map: &mut HandlerMap, data: &HandlerMapData) -> anyhow::Result<()> {
+    // The session is still saved, the unreadable map is kept next to It
+    match HandlerMap::load(&data.file) {
+        Ok(saved) => map.merge(saved),
+        Err(err) => {
+            let backup = Path::new(&data.file).with_extension("bak");
+            log::error!(
+                "Failed to load handler map, moving It to {}: {err:?}",
+                backup.display()
+            );
+            if let Err(err) = std::fs::rename(&data.file, &backup) {
+                log::error!("Failed to move handler map: {err:?}");
+            }
+        }
+    }
+
+    // Only the symbol handler is used, so the context is never walked
+    let walker = StackWalker::from_ctx(CONTEXT::default());
+    if init_symbols(&walker) {
+        map.symbolize(|addr| {
+            let (name, offset) = walker.sym_from_addr(addr as u64)?;
+            Some(format!("{name}+{offset:#x}"))
+        });
+    }
+
+    map.save_json(&data.file)?;
+    if let Some(ref csv) = data.csv {
+        map.save_csv(csv)?;
+    }
+    Ok(())
+}
+
+/// Merges the handlers recorded since the last export with the saved map and exports It
+pub fn export() {
+    let Some(data) = handler_map_data() else {
+        return;
+    };
+
+    let mut map = SESSION.take();
+    if map.is_empty() {
+        return;
+    }
+    match save(&mut map, data) {
+        Ok(()) => log::info!("Wrote handler map with {} entries", map.len()),
+        Err(err) => log::error!("Failed to write handler map: {err:?}"),
+    }
+}
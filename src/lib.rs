@@ -30,18 +30,37 @@ use windows::{
     },
 };
 
-use crate::{config::CONFIG, login::LoginHooks, socket::PacketHooks, wz::WzHooks};
+use crate::{
+    config::CONFIG,
+    login::LoginHooks,
+    raw_capture::RawCaptureHooks,
+    redirect::RedirectHooks,
+    session::SessionHooks,
+    socket::{PacketHooks, SocketHooks},
+    wz::WzHooks,
+};
 
-//pub mod net;
 pub mod app;
+pub mod char_list;
 pub mod config;
+pub mod coverage;
 pub mod exceptions;
+pub mod fuzz;
+pub mod handler_map;
 pub mod login;
+#[cfg(feature = "net")]
+pub mod net;
+pub mod net_sim;
 #[cfg(feature = "overlay")]
 pub mod overlay;
+pub mod proxy;
+pub mod raw_capture;
+pub mod redirect;
+pub mod session;
 pub mod shroom_ffi;
 pub mod shroom_hooks;
 pub mod socket;
+pub mod traffic_stats;
 pub mod util;
 pub mod win32_hooks;
 pub mod wz;
@@ -116,9 +135,26 @@ fn run() {
 
     log::info!("Running");
     let cfg = CONFIG.get().unwrap();
+    if cfg.fuzz.is_some() && cfg.packet_tracing.is_none() {
+        log::warn!("Fuzzing without packet_tracing, the mutations are not guided by the layouts");
+    }
+    let refuses_raw = cfg
+        .packet_tracing
+        .as_ref()
+        .is_some_and(|tracing| tracing.raw_capture.is_some() && !cfg.captures_raw());
+    if refuses_raw {
+        log::error!("Raw capture is disabled with auto_login, set allow_credentials to capture");
+    }
 
     unsafe { LoginHooks.enable_if(cfg.auto_login_data.is_some()) }.expect("Login hooks");
     unsafe { PacketHooks.enable_if(cfg.packet_tracing.is_some()) }.expect("Packet hooks");
+    unsafe { SocketHooks.enable_if(cfg.needs_socket_hooks()) }.expect("Socket hooks");
+    if cfg.hooks_connect() {
+        redirect::init();
+    }
+    unsafe { RedirectHooks.enable_if(cfg.hooks_connect()) }.expect("Redirect hooks");
+    unsafe { RawCaptureHooks.enable_if(cfg.captures_raw()) }.expect("Raw capture hooks");
+    unsafe { SessionHooks.enable_if(cfg.exports_session()) }.expect("Session hooks");
 
     for extra_dll in &cfg.extra_dlls {
         if let Err(err) = unsafe { LoadLibraryA(extra_dll.as_pcstr()) } {
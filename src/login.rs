@@ -1,11 +1,25 @@
+//! Auto login.
+//!
+//! The credentials are sent, when the login screen is initialized. The check password result is
+//! watched in the received packets: transient failures like a busy server are retried with an
+//! exponential backoff from the socket update, permanent ones stop the auto login.
+//! The character is selected by name from the received character list, If It's configured.
+
 use std::{
     ffi::{c_int, c_void},
-    sync::atomic::AtomicPtr,
+    fmt,
+    sync::{atomic::AtomicPtr, LazyLock, Mutex},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::{AutoLoginData, CONFIG},
-    hook_list, shroom_ffi::{self, CLogin, CloginInitRef, CloginOnRecommendWorldMessageRef, CuiavatarSelectCharacterRef},
+    char_list,
+    config::{AutoLoginData, LoginRetryCfg, CONFIG},
+    hook_list,
+    shroom_ffi::{
+        self, addr, CLogin, CloginInitRef, CloginOnRecommendWorldMessageRef,
+        CuiavatarSelectCharacterRef,
+    },
     static_lazy_hook,
 };
 
@@ -15,14 +29,186 @@ fn get_auto_login() -> &'static Option<AutoLoginData> {
     &CONFIG.get().unwrap().auto_login_data
 }
 
+/// Result of the check password result packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginResult {
+    Success,
+    Banned(u8),
+    WrongPassword,
+    NotRegistered,
+    AlreadyLoggedIn,
+    ServerBusy,
+    /// No result was received within the timeout
+    Timeout,
+    Unknown(u8),
+}
+
+impl LoginResult {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0 => Self::Success,
+            2 | 3 => Self::Banned(code),
+            4 => Self::WrongPassword,
+            5 => Self::NotRegistered,
+            7 => Self::AlreadyLoggedIn,
+            10 => Self::ServerBusy,
+            _ => Self::Unknown(code),
+        }
+    }
+
+    /// Result of a received packet, None for other packets
+    pub fn from_packet(data: &[u8]) -> Option<Self> {
+        let opcode = u16::from_le_bytes(data.get(..2)?.try_into().unwrap());
+        if opcode != addr::RECV_CHECK_PASSWORD_RESULT_OPCODE {
+            return None;
+        }
+        data.get(2).map(|&code| Self::from_code(code))
+    }
+
+    /// Whether the same credentials can succeed later
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            Self::AlreadyLoggedIn | Self::ServerBusy | Self::Timeout
+        )
+    }
+}
+
+impl fmt::Display for LoginResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Success => write!(f, "success"),
+            Self::Banned(code) => write!(f, "account is banned({code})"),
+            Self::WrongPassword => write!(f, "wrong password"),
+            Self::NotRegistered => write!(f, "account is not registered"),
+            Self::AlreadyLoggedIn => write!(f, "account is already logged in"),
+            Self::ServerBusy => write!(f, "server is busy"),
+            Self::Timeout => write!(f, "no result received"),
+            Self::Unknown(code) => write!(f, "unknown result code {code}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginState {
+    /// Waiting for the login screen
+    Idle,
+    /// Credentials were sent, waiting for the result
+    Pending {
+        attempt: u32,
+        sent_at: Instant,
+    },
+    /// Waiting before the next attempt
+    Backoff {
+        attempt: u32,
+        until: Instant,
+    },
+    LoggedIn,
+    /// Permanent failure, the credentials are not sent again
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct AutoLogin {
+    state: LoginState,
+    retry: LoginRetryCfg,
+}
+
+impl AutoLogin {
+    pub fn new(retry: LoginRetryCfg) -> Self {
+        Self {
+            state: LoginState::Idle,
+            retry,
+        }
+    }
+
+    pub fn state(&self) -> LoginState {
+        self.state
+    }
+
+    fn set_state(&mut self, state: LoginState) {
+        log::info!("Auto login: {:?} -> {:?}", self.state, state);
+        self.state = state;
+    }
+
+    /// Delay after the failed attempt
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        Duration::from_millis(
+            self.retry
+                .base_delay
+                .saturating_mul(factor)
+                .min(self.retry.max_delay),
+        )
+    }
+
+    /// The login screen was initialized, returns whether the credentials should be sent.
+    /// Returning to the login screen starts over, unless the login failed permanently
+    pub fn start(&mut self, now: Instant) -> bool {
+        if self.state == LoginState::Failed {
+            return false;
+        }
+
+        self.set_state(LoginState::Pending {
+            attempt: 1,
+            sent_at: now,
+        });
+        true
+    }
+
+    /// Handles a received result, results of manual logins are ignored
+    pub fn on_result(&mut self, result: LoginResult, now: Instant) {
+        let LoginState::Pending { attempt, .. } = self.state else {
+            return;
+        };
+
+        if result == LoginResult::Success {
+            self.set_state(LoginState::LoggedIn);
+        } else if !result.is_transient() {
+            log::error!("Auto login failed permanently: {result}");
+            self.set_state(LoginState::Failed);
+        } else if attempt >= self.retry.max_attempts {
+            log::error!("Auto login failed after {attempt} attempts: {result}");
+            self.set_state(LoginState::Failed);
+        } else {
+            let delay = self.backoff(attempt);
+            log::warn!("Auto login attempt {attempt} failed: {result}, retrying in {delay:?}");
+            self.set_state(LoginState::Backoff {
+                attempt,
+                until: now + delay,
+            });
+        }
+    }
+
+    /// Advances the timers, returns whether the credentials should be sent again
+    pub fn poll(&mut self, now: Instant) -> bool {
+        match self.state {
+            LoginState::Backoff { attempt, until } if now >= until => {
+                self.set_state(LoginState::Pending {
+                    attempt: attempt + 1,
+                    sent_at: now,
+                });
+                true
+            }
+            LoginState::Pending { sent_at, .. }
+                if now.duration_since(sent_at) >= Duration::from_millis(self.retry.timeout) =>
+            {
+                self.on_result(LoginResult::Timeout, now);
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+static AUTO_LOGIN: LazyLock<Option<Mutex<AutoLogin>>> = LazyLock::new(|| {
+    let data = get_auto_login().as_ref()?;
+    Some(Mutex::new(AutoLogin::new(data.retry.clone())))
+});
+
 static CLOGIN_INSTANCE: AtomicPtr<CLogin> = AtomicPtr::new(std::ptr::null_mut());
 
-static_lazy_hook!(INIT_HOOK, CloginInitRef, clogin_init_hook);
-unsafe extern "thiscall" fn clogin_init_hook(
-    this: *const shroom_ffi::CLogin,
-    param: *const c_void,
-) {
-    INIT_HOOK.call(this, param);
+unsafe fn send_credentials(this: *const CLogin) {
     if let Some(auto_login) = get_auto_login() {
         shroom_ffi::clogin_send_check_password_packet()(
             this,
@@ -32,19 +218,89 @@ unsafe extern "thiscall" fn clogin_init_hook(
     }
 }
 
+/// Names of the last received character list in slot order
+static CHAR_NAMES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Slot of the configured character, the name takes precedence over the index
+pub fn select_index(data: &AutoLoginData, names: &[String]) -> Option<u32> {
+    let Some(ref char_name) = data.char_name else {
+        return data.char_index;
+    };
+
+    match names
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(char_name))
+    {
+        Some(ix) => Some(ix as u32),
+        None => {
+            log::warn!(
+                "Character {char_name} not found in {names:?}, falling back to index {:?}",
+                data.char_index
+            );
+            data.char_index
+        }
+    }
+}
+
+/// Watches the received packets for the login result and the character list
+pub fn on_recv(data: &[u8]) {
+    let Some(auto_login) = AUTO_LOGIN.as_ref() else {
+        return;
+    };
+
+    if let Some(result) = LoginResult::from_packet(data) {
+        auto_login
+            .lock()
+            .expect("auto login")
+            .on_result(result, Instant::now());
+    } else if char_list::is_char_list(data) {
+        let names = char_list::parse_names(data, addr::CLIENT_VERSION).unwrap_or_else(|err| {
+            log::warn!("Failed to parse the character list: {err:?}");
+            Vec::new()
+        });
+        log::info!("Characters: {names:?}");
+        *CHAR_NAMES.lock().expect("char names") = names;
+    }
+}
+
+/// Sends the next attempt after the backoff, called once per socket update
+pub fn tick() {
+    let Some(auto_login) = AUTO_LOGIN.as_ref() else {
+        return;
+    };
+
+    // The lock must be released before sending, as the packet passes the socket hooks
+    let retry = auto_login.lock().expect("auto login").poll(Instant::now());
+    let this = CLOGIN_INSTANCE.load(std::sync::atomic::Ordering::SeqCst);
+    if retry && !this.is_null() {
+        unsafe { send_credentials(this) };
+    }
+}
+
+static_lazy_hook!(INIT_HOOK, CloginInitRef, clogin_init_hook);
+unsafe extern "thiscall" fn clogin_init_hook(
+    this: *const shroom_ffi::CLogin,
+    param: *const c_void,
+) {
+    INIT_HOOK.call(this, param);
+    CLOGIN_INSTANCE.store(this as *mut CLogin, std::sync::atomic::Ordering::SeqCst);
+    let start = AUTO_LOGIN
+        .as_ref()
+        .is_some_and(|auto_login| auto_login.lock().expect("auto login").start(Instant::now()));
+    if start {
+        send_credentials(this);
+    }
+}
+
 static_lazy_hook!(
     WORLD_MSG_HOOK,
     CloginOnRecommendWorldMessageRef,
     world_msg_hook
 );
 
-unsafe extern "thiscall" fn world_msg_hook(
-    this: *const shroom_ffi::CLogin,
-    pkt: *const c_void,
-) {
+unsafe extern "thiscall" fn world_msg_hook(this: *const shroom_ffi::CLogin, pkt: *const c_void) {
     log::info!("On recommended world");
     WORLD_MSG_HOOK.call(this, pkt);
-    CLOGIN_INSTANCE.store(this as *mut CLogin, std::sync::atomic::Ordering::SeqCst);
     if let Some((world, channel)) = get_auto_login()
         .as_ref()
         .and_then(|a| a.get_world_channel())
@@ -60,11 +316,12 @@ static_lazy_hook!(
     select_char_hook
 );
 
-unsafe extern "thiscall" fn select_char_hook(
-    this: *const shroom_ffi::CUIAvatar,
-    idx: c_int,
-) {
-    if let Some(char_index) = get_auto_login().as_ref().and_then(|a| a.char_index) {
+unsafe extern "thiscall" fn select_char_hook(this: *const shroom_ffi::CUIAvatar, idx: c_int) {
+    let char_index = get_auto_login()
+        .as_ref()
+        .and_then(|a| select_index(a, &CHAR_NAMES.lock().expect("char names")));
+    if let Some(char_index) = char_index {
+        log::info!("Selecting character: {char_index}");
         SELECT_CHAR_HOOK.call(this, char_index as i32);
         let login_instance = CLOGIN_INSTANCE.load(std::sync::atomic::Ordering::SeqCst);
         shroom_ffi::clogin_send_select_character_packet()(login_instance);
@@ -73,9 +330,119 @@ unsafe extern "thiscall" fn select_char_hook(
     }
 }
 
-hook_list!(
-    LoginHooks,
-    INIT_HOOK,
-    WORLD_MSG_HOOK,
-    SELECT_CHAR_HOOK,
-);
+hook_list!(LoginHooks, INIT_HOOK, WORLD_MSG_HOOK, SELECT_CHAR_HOOK,);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry() -> LoginRetryCfg {
+        LoginRetryCfg {
+            max_attempts: 3,
+            base_delay: 1000,
+            max_delay: 1500,
+            timeout: 5000,
+        }
+    }
+
+    #[test]
+    fn from_packet() {
+        assert_eq!(
+            LoginResult::from_packet(&[0, 0, 4, 0]),
+            Some(LoginResult::WrongPassword)
+        );
+        assert_eq!(LoginResult::from_packet(&[0, 0]), None);
+        assert_eq!(LoginResult::from_packet(&[1, 0, 0]), None);
+    }
+
+    #[test]
+    fn retries() {
+        let now = Instant::now();
+        let ms = |ms| now + Duration::from_millis(ms);
+        let mut login = AutoLogin::new(retry());
+        assert!(login.start(now));
+
+        login.on_result(LoginResult::ServerBusy, now);
+        assert_eq!(
+            login.state(),
+            LoginState::Backoff {
+                attempt: 1,
+                until: ms(1000)
+            }
+        );
+        assert!(!login.poll(ms(999)));
+        assert!(login.poll(ms(1000)));
+
+        // Second backoff is capped by the max delay
+        login.on_result(LoginResult::AlreadyLoggedIn, ms(1000));
+        assert_eq!(
+            login.state(),
+            LoginState::Backoff {
+                attempt: 2,
+                until: ms(2500)
+            }
+        );
+        assert!(login.poll(ms(2500)));
+        login.on_result(LoginResult::Success, ms(2600));
+        assert_eq!(login.state(), LoginState::LoggedIn);
+
+        // Results of manual logins are ignored
+        login.on_result(LoginResult::WrongPassword, ms(3000));
+        assert_eq!(login.state(), LoginState::LoggedIn);
+    }
+
+    #[test]
+    fn timeout() {
+        let now = Instant::now();
+        let ms = |ms| now + Duration::from_millis(ms);
+        let mut login = AutoLogin::new(retry());
+        login.start(now);
+
+        assert!(!login.poll(ms(4999)));
+        // A missing result is retried like a transient failure
+        assert!(!login.poll(ms(5000)));
+        assert!(login.poll(ms(6000)));
+        assert!(!login.poll(ms(11000)));
+        assert!(login.poll(ms(12500)));
+
+        // The third attempt is the last one
+        assert!(!login.poll(ms(17500)));
+        assert_eq!(login.state(), LoginState::Failed);
+    }
+
+    #[test]
+    fn select_by_name() {
+        let mut data: AutoLoginData = toml::from_str(
+            r#"
+            username = "admin"
+            password = "test1234"
+            char_index = 0
+            char_name = "evan"
+            "#,
+        )
+        .unwrap();
+        let names = ["Warrior", "Evan"].map(String::from);
+
+        assert_eq!(select_index(&data, &names), Some(1));
+        // Unknown names and missing lists fall back to the index
+        assert_eq!(select_index(&data, &names[..1]), Some(0));
+        data.char_name = Some("Mage".to_string());
+        assert_eq!(select_index(&data, &names), Some(0));
+        data.char_name = None;
+        data.char_index = None;
+        assert_eq!(select_index(&data, &names), None);
+    }
+
+    #[test]
+    fn permanent_failure() {
+        let now = Instant::now();
+        let mut login = AutoLogin::new(retry());
+        login.start(now);
+        login.on_result(LoginResult::Banned(2), now);
+        assert_eq!(login.state(), LoginState::Failed);
+
+        // Not retried on the login screen either
+        assert!(!login.start(now));
+        assert!(!login.poll(now + Duration::from_secs(60)));
+    }
+}
@@ -1,148 +1,504 @@
-use std::net::{SocketAddr};
+//! WebSocket transport, which tunnels the game traffic.
+//!
+//! The [`NetClient`] runs on its own tokio runtime, the game thread talks to It through the
+//! [`NetClientHandle`] without blocking. Every game packet is one binary message.
+
+use std::{net::SocketAddr, time::Duration};
 
 use anyhow::Context;
-use bytes::BytesMut;
-use futures::prelude::*;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use tokio::{net::TcpStream, runtime::Builder, sync::mpsc};
-use tokio_websockets::{MaybeTlsStream, WebSocketStream, Message};
+use tokio_websockets::{ClientBuilder, Message, WebSocketStream};
 
 type Conn = WebSocketStream<TcpStream>;
 
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NetCfg {
+    /// Attempts to reconnect after the connection was lost, 0 disables reconnecting
+    #[serde(default = "NetCfg::default_reconnect_attempts")]
+    pub reconnect_attempts: u32,
+    /// Delay before the first attempt in ms, It's doubled after every attempt
+    #[serde(default = "NetCfg::default_reconnect_delay")]
+    pub reconnect_delay: u64,
+    /// Timeout of the connect and the WebSocket handshake in ms
+    #[serde(default = "NetCfg::default_connect_timeout")]
+    pub connect_timeout: u64,
+}
+
+impl NetCfg {
+    fn default_reconnect_attempts() -> u32 {
+        3
+    }
+
+    fn default_reconnect_delay() -> u64 {
+        250
+    }
+
+    fn default_connect_timeout() -> u64 {
+        5000
+    }
+}
+
+impl Default for NetCfg {
+    fn default() -> Self {
+        Self {
+            reconnect_attempts: Self::default_reconnect_attempts(),
+            reconnect_delay: Self::default_reconnect_delay(),
+            connect_timeout: Self::default_connect_timeout(),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum NetCmdMessage {
     Connect(SocketAddr),
+    /// Replaces the current connection, like the client does when changing the channel
     MigrateConnect(SocketAddr),
     Disconnect,
-    SendMsg(bytes::Bytes),
+    SendMsg(Bytes),
+    /// Closes the connection and stops the client
+    Shutdown,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum NetRxMessage {
-    Connected,
+    Connected(SocketAddr),
+    Migrated(SocketAddr),
+    /// The connection was lost, the attempt starts at 1
+    Reconnecting(u32),
     Disconnected,
-    MsgReceived(tokio_websockets::Message),
+    /// A connect failed, the client stays disconnected
+    ConnectFailed(String),
+    MsgReceived(Bytes),
 }
 
+#[derive(Debug)]
 pub struct NetClientHandle {
     tx: mpsc::Sender<NetCmdMessage>,
     rx: mpsc::Receiver<NetRxMessage>,
-    buf: BytesMut,
 }
 
 impl NetClientHandle {
     pub fn new(tx: mpsc::Sender<NetCmdMessage>, rx: mpsc::Receiver<NetRxMessage>) -> Self {
-        Self {
-            tx,
-            rx,
-            buf: BytesMut::new(),
-        }
+        Self { tx, rx }
+    }
+
+    fn cmd(&self, cmd: NetCmdMessage) -> anyhow::Result<()> {
+        self.tx
+            .try_send(cmd)
+            .map_err(|err| anyhow::anyhow!("Net client unavailable: {err}"))
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        self.cmd(NetCmdMessage::Connect(addr))
     }
 
-    pub fn connect(&mut self, addr: SocketAddr) {
-        self.tx.try_send(NetCmdMessage::Connect(addr)).unwrap();
+    pub fn migrate_connect(&self, addr: SocketAddr) -> anyhow::Result<()> {
+        self.cmd(NetCmdMessage::MigrateConnect(addr))
     }
 
-    pub fn disconnect(&mut self) {
-        self.tx.try_send(NetCmdMessage::Disconnect).unwrap();
+    pub fn disconnect(&self) -> anyhow::Result<()> {
+        self.cmd(NetCmdMessage::Disconnect)
     }
 
-    pub fn send_msg(&mut self, msg: bytes::Bytes) {
-        self.tx.try_send(NetCmdMessage::SendMsg(msg)).unwrap();
+    pub fn send_msg(&self, msg: Bytes) -> anyhow::Result<()> {
+        self.cmd(NetCmdMessage::SendMsg(msg))
     }
 
+    pub fn shutdown(&self) -> anyhow::Result<()> {
+        self.cmd(NetCmdMessage::Shutdown)
+    }
+
+    /// Next event without blocking, called from the game thread
     pub fn poll(&mut self) -> Option<NetRxMessage> {
         self.rx.try_recv().ok()
     }
+
+    /// Waits for the next event, `None` once the client stopped
+    pub async fn recv(&mut self) -> Option<NetRxMessage> {
+        self.rx.recv().await
+    }
+}
+
+/// What ended the session of a connection
+enum ConnEnd {
+    Lost,
+    Disconnect,
+    /// Connect while connected, which replaces the session
+    Connect(SocketAddr),
+    Migrate(SocketAddr),
+    Shutdown,
+}
+
+enum Reconnect {
+    Connected(Conn),
+    Failed,
+    /// A command other than a send arrived, which is handled after the reconnect
+    Interrupted(NetCmdMessage),
 }
 
 pub struct NetClient {
+    cfg: NetCfg,
     rx: mpsc::Receiver<NetCmdMessage>,
     tx: mpsc::Sender<NetRxMessage>,
 }
 
 impl NetClient {
-    pub fn new() -> (Self, NetClientHandle) {
-        let (tx, rx) = mpsc::channel(32);
-        let (tx2, rx2) = mpsc::channel(32);
+    pub fn new(cfg: NetCfg) -> (Self, NetClientHandle) {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (tx2, rx2) = mpsc::channel(CHANNEL_CAPACITY);
         let handle = NetClientHandle::new(tx, rx2);
-        let client = Self { rx, tx: tx2 };
+        let client = Self { cfg, rx, tx: tx2 };
         (client, handle)
     }
 
-    async fn connect(&mut self, addr: SocketAddr) -> anyhow::Result<Conn> {
-        let stream = TcpStream::connect(addr).await?;
-        let (stream, resp) = tokio_websockets::ClientBuilder::new()
-            .uri("http://127.0.0.1").unwrap()
-            .connect_on(stream)
-            .await?;
+    /// Runs the client on a new thread with its own runtime
+    pub fn spawn(cfg: NetCfg) -> anyhow::Result<(NetClientHandle, std::thread::JoinHandle<()>)> {
+        let (client, handle) = Self::new(cfg);
+        let rt = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("Net runtime")?;
 
+        let thread = std::thread::Builder::new()
+            .name("net_client".to_string())
+            .spawn(move || {
+                if let Err(err) = rt.block_on(client.run()) {
+                    log::error!("Net client failed: {err:?}");
+                }
+            })?;
+        Ok((handle, thread))
+    }
 
-        Ok(stream)
+    async fn post(&self, msg: NetRxMessage) {
+        // The handle is gone, when the game shuts down, which is handled by the cmd channel
+        let _ = self.tx.send(msg).await;
     }
 
-    async fn handle_cmd(&mut self, cmd: NetCmdMessage) -> anyhow::Result<()> {
-        Ok(())
+    /// Connects and posts `on_connect` or the failure
+    async fn open(&self, addr: SocketAddr, on_connect: NetRxMessage) -> Option<(SocketAddr, Conn)> {
+        match connect(addr, self.connect_timeout()).await {
+            Ok(conn) => {
+                self.post(on_connect).await;
+                Some((addr, conn))
+            }
+            Err(err) => {
+                self.post(NetRxMessage::ConnectFailed(format!("{err:?}")))
+                    .await;
+                None
+            }
+        }
+    }
+
+    fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.cfg.connect_timeout)
+    }
+
+    /// Reconnects with a backoff, commands are still handled while waiting
+    async fn reconnect(&mut self, addr: SocketAddr) -> Reconnect {
+        let timeout = self.connect_timeout();
+        let mut delay = Duration::from_millis(self.cfg.reconnect_delay);
+        for attempt in 1..=self.cfg.reconnect_attempts {
+            self.post(NetRxMessage::Reconnecting(attempt)).await;
+            let next_conn = async move {
+                tokio::time::sleep(delay).await;
+                connect(addr, timeout).await
+            };
+            tokio::pin!(next_conn);
+
+            let res = loop {
+                tokio::select! {
+                    res = &mut next_conn => break res,
+                    cmd = self.rx.recv() => match cmd {
+                        Some(NetCmdMessage::SendMsg(_)) => {
+                            log::warn!("Net send while reconnecting")
+                        }
+                        Some(NetCmdMessage::Disconnect) => return Reconnect::Failed,
+                        Some(cmd) => return Reconnect::Interrupted(cmd),
+                        None => return Reconnect::Interrupted(NetCmdMessage::Shutdown),
+                    },
+                }
+            };
+            match res {
+                Ok(conn) => return Reconnect::Connected(conn),
+                Err(err) => log::warn!("Reconnect attempt {attempt} failed: {err:?}"),
+            }
+            delay *= 2;
+        }
+        Reconnect::Failed
     }
 
-    async fn run_socket(
-        &mut self,
-        mut conn: Conn,
-    ) -> anyhow::Result<()> {
+    async fn run_conn(&mut self, conn: &mut Conn) -> ConnEnd {
         loop {
             tokio::select! {
-                cmd = self.rx.recv() => {
-                    let cmd = cmd.context("closed")?;
-                    match cmd {
-                        NetCmdMessage::Connect(addr) => {
-                            panic!("already connected");
-                        }
-                        NetCmdMessage::MigrateConnect(addr) => {
-                            conn = self.connect(addr).await?;
-                        }
-                        NetCmdMessage::Disconnect => {
-                            conn.close().await?;
-                            break;
-                        }
-                        NetCmdMessage::SendMsg(msg) => {
-                            socket.send(Message::Binary(msg)).await?;
+                cmd = self.rx.recv() => match cmd {
+                    Some(NetCmdMessage::SendMsg(msg)) => {
+                        if let Err(err) = conn.send(Message::binary(msg)).await {
+                            log::warn!("Net send failed: {err:?}");
+                            return ConnEnd::Lost;
                         }
                     }
-                }
-                msg = socket.next() => {
-                    let msg = msg.context("closed")??;
-                    match msg {
-                        Message::
+                    Some(NetCmdMessage::Connect(addr)) => return ConnEnd::Connect(addr),
+                    Some(NetCmdMessage::MigrateConnect(addr)) => return ConnEnd::Migrate(addr),
+                    Some(NetCmdMessage::Disconnect) => return ConnEnd::Disconnect,
+                    Some(NetCmdMessage::Shutdown) | None => return ConnEnd::Shutdown,
+                },
+                msg = conn.next() => match msg {
+                    Some(Ok(msg)) if msg.is_binary() => {
+                        self.post(NetRxMessage::MsgReceived(msg.into_payload().into())).await;
                     }
-                }
+                    // Pings are answered by the stream, text messages are not part of the protocol
+                    Some(Ok(_)) => (),
+                    Some(Err(err)) => {
+                        log::warn!("Net receive failed: {err:?}");
+                        return ConnEnd::Lost;
+                    }
+                    None => return ConnEnd::Lost,
+                },
             }
         }
-        Ok(())
     }
 
+    /// Runs until the handle is dropped or shutdown is requested
     pub async fn run(mut self) -> anyhow::Result<()> {
-        let mut socket: Option<WebSocketStream<MaybeTlsStream<TcpStream>>> = None;
+        let mut cur: Option<(SocketAddr, Conn)> = None;
+        // Command, which interrupted a reconnect
+        let mut pending = None;
 
         loop {
-            if let Some(ref mut socket) = socket.take() {
-                loop {
-                    tokio::select! {
-                        // This one should be never closed
-                        cmd = self.rx.recv() => self.handle_cmd(cmd.expect("NetClient rx closed")).await?,
-                        msg = socket.next() => {
-                            if let Some(msg) = msg {
-                                self.handle_msg(msg?).await?;
-                            } else {
-                                // TODO post disconnected
-                                break;
-                            }
-                        }
+            let Some((addr, conn)) = cur.as_mut() else {
+                let cmd = match pending.take() {
+                    Some(cmd) => Some(cmd),
+                    None => self.rx.recv().await,
+                };
+                match cmd {
+                    Some(NetCmdMessage::Connect(addr) | NetCmdMessage::MigrateConnect(addr)) => {
+                        cur = self.open(addr, NetRxMessage::Connected(addr)).await;
                     }
+                    Some(NetCmdMessage::SendMsg(_)) => log::warn!("Net send while disconnected"),
+                    Some(NetCmdMessage::Disconnect) => (),
+                    Some(NetCmdMessage::Shutdown) | None => return Ok(()),
+                }
+                continue;
+            };
+            let addr = *addr;
+
+            let end = self.run_conn(conn).await;
+            let (_, mut conn) = cur.take().unwrap();
+            match end {
+                ConnEnd::Lost => match self.reconnect(addr).await {
+                    Reconnect::Connected(conn) => {
+                        cur = Some((addr, conn));
+                        self.post(NetRxMessage::Connected(addr)).await;
+                    }
+                    Reconnect::Failed => self.post(NetRxMessage::Disconnected).await,
+                    Reconnect::Interrupted(cmd) => pending = Some(cmd),
+                },
+                ConnEnd::Disconnect => {
+                    let _ = conn.close().await;
+                    self.post(NetRxMessage::Disconnected).await;
+                }
+                // A new session, unlike a migration
+                ConnEnd::Connect(new_addr) => {
+                    let _ = conn.close().await;
+                    cur = self.open(new_addr, NetRxMessage::Connected(new_addr)).await;
+                }
+                ConnEnd::Migrate(new_addr) => {
+                    let _ = conn.close().await;
+                    cur = self.open(new_addr, NetRxMessage::Migrated(new_addr)).await;
+                }
+                ConnEnd::Shutdown => {
+                    let _ = conn.close().await;
+                    return Ok(());
                 }
             }
         }
     }
 }
 
-fn net_executor() {
-    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+async fn connect(addr: SocketAddr, timeout: Duration) -> anyhow::Result<Conn> {
+    let connect = async {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (conn, _) = ClientBuilder::new()
+            .uri(&format!("ws://{addr}/"))?
+            .connect_on(stream)
+            .await?;
+        anyhow::Ok(conn)
+    };
+
+    tokio::time::timeout(timeout, connect)
+        .await
+        .with_context(|| format!("Connecting to {addr} timed out"))?
+        .with_context(|| format!("Connecting to {addr}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_websockets::ServerBuilder;
+
+    use super::*;
 
-    rt.block_on(async move {})
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Echoes binary messages, the connection is dropped after a message "drop"
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let Ok(mut conn) = ServerBuilder::new().accept(stream).await else {
+                        return;
+                    };
+                    while let Some(Ok(msg)) = conn.next().await {
+                        if msg.as_payload()[..] == b"drop"[..] {
+                            return;
+                        }
+                        if msg.is_binary() && conn.send(msg).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    async fn next(handle: &mut NetClientHandle) -> NetRxMessage {
+        tokio::time::timeout(TIMEOUT, handle.recv())
+            .await
+            .expect("net event")
+            .expect("client stopped")
+    }
+
+    fn cfg() -> NetCfg {
+        NetCfg {
+            reconnect_attempts: 2,
+            reconnect_delay: 10,
+            connect_timeout: 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn echo_and_migrate() {
+        let (a, b) = (echo_server().await, echo_server().await);
+        let (client, mut handle) = NetClient::new(cfg());
+        let client = tokio::spawn(client.run());
+
+        handle.connect(a).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Connected(a));
+        handle
+            .send_msg(Bytes::from_static(b"\x10\x00ping"))
+            .unwrap();
+        assert_eq!(
+            next(&mut handle).await,
+            NetRxMessage::MsgReceived(Bytes::from_static(b"\x10\x00ping"))
+        );
+
+        handle.migrate_connect(b).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Migrated(b));
+        handle.send_msg(Bytes::from_static(b"migrated")).unwrap();
+        assert_eq!(
+            next(&mut handle).await,
+            NetRxMessage::MsgReceived(Bytes::from_static(b"migrated"))
+        );
+
+        handle.disconnect().unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Disconnected);
+        handle.shutdown().unwrap();
+        tokio::time::timeout(TIMEOUT, client)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let addr = echo_server().await;
+        let (client, mut handle) = NetClient::new(cfg());
+        let client = tokio::spawn(client.run());
+
+        handle.connect(addr).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Connected(addr));
+        handle.send_msg(Bytes::from_static(b"drop")).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Reconnecting(1));
+        assert_eq!(next(&mut handle).await, NetRxMessage::Connected(addr));
+
+        handle.send_msg(Bytes::from_static(b"again")).unwrap();
+        assert_eq!(
+            next(&mut handle).await,
+            NetRxMessage::MsgReceived(Bytes::from_static(b"again"))
+        );
+
+        // Dropping the handle shuts the client down
+        drop(handle);
+        tokio::time::timeout(TIMEOUT, client)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands_while_reconnecting() {
+        let (a, b) = (echo_server().await, echo_server().await);
+        // The backoff outlasts the test, so only the commands end It
+        let (client, mut handle) = NetClient::new(NetCfg {
+            reconnect_delay: 60_000,
+            ..cfg()
+        });
+        let client = tokio::spawn(client.run());
+
+        handle.connect(a).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Connected(a));
+        handle.send_msg(Bytes::from_static(b"drop")).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Reconnecting(1));
+        handle.disconnect().unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Disconnected);
+
+        // Connect while connected starts a new session
+        handle.connect(b).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Connected(b));
+        handle.connect(a).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Connected(a));
+
+        handle.send_msg(Bytes::from_static(b"drop")).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Reconnecting(1));
+        handle.connect(b).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Connected(b));
+
+        handle.send_msg(Bytes::from_static(b"drop")).unwrap();
+        assert_eq!(next(&mut handle).await, NetRxMessage::Reconnecting(1));
+        handle.shutdown().unwrap();
+        tokio::time::timeout(TIMEOUT, client)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_failed() {
+        // Bind and drop a listener to get a port without a server
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (client, mut handle) = NetClient::new(cfg());
+        tokio::spawn(client.run());
+
+        handle.connect(addr).unwrap();
+        assert!(matches!(
+            next(&mut handle).await,
+            NetRxMessage::ConnectFailed(_)
+        ));
+    }
 }
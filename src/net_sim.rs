@@ -0,0 +1,202 @@
+//! Network condition simulator.
+//!
+//! Every packet gets a due time from the active profile, the socket queues It until then and
+//! flushes It from the socket update, so the game thread is never blocked. Received packets are
+//! queued before `ProcessPacket`, sent packets before they reach the raw socket.
+
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use shroom_trace::{fuzz::Rng, PacketDir};
+
+use crate::config::{NetCondition, NetSimProfile, CONFIG};
+
+/// Link state of one direction
+#[derive(Debug)]
+struct Link {
+    cond: NetCondition,
+    /// Time, when the previous packet was transmitted with the bandwidth cap
+    free_at: Option<Instant>,
+    /// Due time of the previous packet, which was not reordered
+    last_due: Option<Instant>,
+}
+
+impl Link {
+    fn new(cond: NetCondition) -> Self {
+        Self {
+            cond,
+            free_at: None,
+            last_due: None,
+        }
+    }
+
+    fn due(&mut self, rng: &mut Rng, now: Instant, len: usize) -> Instant {
+        let cond = &self.cond;
+        let mut sent = now;
+        if let Some(bandwidth) = cond.bandwidth.filter(|&bw| bw > 0) {
+            let start = self.free_at.map_or(now, |free_at| free_at.max(now));
+            sent = start + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+            self.free_at = Some(sent);
+        }
+
+        let jitter = rng.below(cond.jitter as usize + 1) as u64;
+        let mut due = sent + Duration::from_millis(cond.latency + jitter);
+        // Jitter alone never reorders, like on a TCP connection
+        if let Some(last_due) = self.last_due {
+            due = due.max(last_due);
+        }
+
+        if cond.reorder > 0. && rng.chance(cond.reorder) {
+            return due + Duration::from_millis(cond.reorder_delay);
+        }
+        self.last_due = Some(due);
+        due
+    }
+}
+
+#[derive(Debug)]
+pub struct NetSim {
+    rng: Rng,
+    send: Link,
+    recv: Link,
+}
+
+impl NetSim {
+    pub fn new(profile: NetSimProfile, seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            send: Link::new(profile.send),
+            recv: Link::new(profile.recv),
+        }
+    }
+
+    /// Time, when a packet with the length, which is passed at `now`, should be handled
+    pub fn due(&mut self, dir: PacketDir, now: Instant, len: usize) -> Instant {
+        let link = match dir {
+            PacketDir::Send => &mut self.send,
+            PacketDir::Recv => &mut self.recv,
+        };
+        link.due(&mut self.rng, now, len)
+    }
+}
+
+static SIM: LazyLock<Option<Mutex<NetSim>>> = LazyLock::new(|| {
+    let data = CONFIG.get()?.net_sim.as_ref()?;
+    let Some(profile) = data.profiles.get(&data.profile) else {
+        log::error!("Unknown net sim profile: {}", data.profile);
+        return None;
+    };
+
+    log::info!("Simulating network profile: {}", data.profile);
+    Some(Mutex::new(NetSim::new(profile.clone(), data.seed)))
+});
+
+/// Due time of a packet, None If the simulator is disabled
+pub fn due(dir: PacketDir, len: usize) -> Option<Instant> {
+    let sim = SIM.as_ref()?;
+    Some(sim.lock().expect("net sim").due(dir, Instant::now(), len))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::delay_queue::{flush_due, DelayQueue};
+
+    use super::*;
+
+    fn ms(start: Instant, t: Instant) -> u64 {
+        (t - start).as_millis() as u64
+    }
+
+    #[test]
+    fn due() {
+        let profile = NetSimProfile {
+            send: NetCondition {
+                latency: 50,
+                bandwidth: Some(1000),
+                ..Default::default()
+            },
+            recv: NetCondition {
+                latency: 100,
+                jitter: 40,
+                reorder: 0.3,
+                reorder_delay: 500,
+                ..Default::default()
+            },
+        };
+        let now = Instant::now();
+
+        // 500 bytes take 500ms with the cap, the second packet waits for the first
+        let mut sim = NetSim::new(profile.clone(), 7);
+        assert_eq!(ms(now, sim.due(PacketDir::Send, now, 500)), 550);
+        assert_eq!(ms(now, sim.due(PacketDir::Send, now, 100)), 650);
+
+        let recv = |seed| {
+            let mut sim = NetSim::new(profile.clone(), seed);
+            (0..20)
+                .map(|_| ms(now, sim.due(PacketDir::Recv, now, 10)))
+                .collect::<Vec<_>>()
+        };
+        let delays = recv(7);
+        // Reproducible with the same seed
+        assert_eq!(delays, recv(7));
+        assert!(delays.iter().all(|&d| (100..=640).contains(&d)));
+
+        // Packets, which are not held back, keep their order
+        let in_order = delays.iter().filter(|&&d| d <= 140).collect::<Vec<_>>();
+        assert!(in_order.windows(2).all(|w| w[0] <= w[1]));
+        assert!(delays.iter().any(|&d| d >= 600));
+    }
+
+    #[test]
+    fn queue_and_flush() {
+        let profile = NetSimProfile {
+            send: NetCondition {
+                latency: 50,
+                ..Default::default()
+            },
+            recv: NetCondition {
+                latency: 100,
+                bandwidth: Some(1000),
+                ..Default::default()
+            },
+        };
+        let mut sim = NetSim::new(profile, 7);
+        let queue = Mutex::new(DelayQueue::new());
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // The bandwidth cap delays the second packet by 100ms
+        for (id, len) in [(0, 100), (1, 100)] {
+            let due = sim.due(PacketDir::Recv, start, len);
+            queue.lock().unwrap().push(due, (PacketDir::Recv, id));
+        }
+
+        let mut handled = Vec::new();
+        // Received packets are answered, which queues the reply like a handler does
+        let mut flush = |now| {
+            flush_due(&queue, now, |(dir, id)| {
+                handled.push((ms(start, now), dir, id));
+                if dir == PacketDir::Recv {
+                    let due = sim.due(PacketDir::Send, now, 10);
+                    queue.lock().unwrap().push(due, (PacketDir::Send, id));
+                }
+            })
+        };
+        for now in [150, 200, 250, 300, 350] {
+            flush(at(now));
+        }
+
+        assert_eq!(
+            handled,
+            [
+                (200, PacketDir::Recv, 0),
+                (250, PacketDir::Send, 0),
+                (300, PacketDir::Recv, 1),
+                (350, PacketDir::Send, 1),
+            ]
+        );
+        assert!(queue.lock().unwrap().is_empty());
+    }
+}
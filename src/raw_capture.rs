@@ -0,0 +1,286 @@
+//! Raw capture of the game socket.
+//!
+//! The Winsock `send`/`recv`/`WSASend`/`WSARecv` are hooked, so the handshake and packets, which
+//! bypass `CClientSocket::SendPacket`, are recorded as well. Only sockets, which were connected
+//! by the game executable, are captured, the hooked `connect` of the redirect tracks them. Peeked
+//! data is recorded once It's read. The chunks share the sequence with the trace records, see
+//! `shroom_trace::capture` for the format and `trace_capture` to join them.
+
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    ffi::c_void,
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+};
+
+use retour::GenericDetour;
+use shroom_trace::{capture::CaptureWriter, PacketDir};
+use windows::{
+    core::{s, w},
+    Win32::{
+        Networking::WinSock::{
+            WSAGetLastError, MSG_PEEK, SOCKET, SOCKET_ERROR, WSABUF, WSA_IO_PENDING,
+        },
+        System::{
+            LibraryLoader::GetModuleHandleW,
+            ProcessStatus::{GetModuleInformation, MODULEINFO},
+            Threading::GetCurrentProcess,
+        },
+    },
+};
+
+use crate::{
+    config::{RawCaptureData, CONFIG},
+    hook_list, static_win32_fn_hook,
+};
+
+static CAPTURE: LazyLock<Option<Mutex<CaptureWriter>>> = LazyLock::new(|| {
+    let data = raw_capture_data()?;
+    match CaptureWriter::create(&data.dir) {
+        Ok(writer) => Some(Mutex::new(writer)),
+        Err(err) => {
+            log::error!("Failed to create raw capture: {err:?}");
+            None
+        }
+    }
+});
+static SEQ: AtomicU64 = AtomicU64::new(0);
+/// Sockets connected by the game, the launcher or other modules are not captured
+static GAME_SOCKETS: LazyLock<Mutex<HashSet<usize>>> = LazyLock::new(Mutex::default);
+/// Image of the game executable
+static GAME_MODULE: LazyLock<Option<Range<usize>>> = LazyLock::new(|| {
+    let module = unsafe { GetModuleHandleW(None) }.ok()?;
+    let mut info = MODULEINFO::default();
+    unsafe {
+        GetModuleInformation(
+            GetCurrentProcess(),
+            module,
+            &mut info,
+            std::mem::size_of::<MODULEINFO>() as u32,
+        )
+    }
+    .inspect_err(|err| log::error!("Failed to get the game module: {err:?}"))
+    .ok()?;
+    let base = info.lpBaseOfDll as usize;
+    Some(base..base + info.SizeOfImage as usize)
+});
+static WARNED_OVERLAPPED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn raw_capture_data() -> Option<&'static RawCaptureData> {
+    CONFIG.get()?.raw_capture()
+}
+
+/// Next sequence number for a trace record, None If the capture is disabled
+pub fn next_seq() -> Option<u64> {
+    CAPTURE.as_ref()?;
+    Some(SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Tracks the socket, If `connect` was called by the game executable
+pub fn on_connect(sock: SOCKET, caller: usize) {
+    let is_game = GAME_MODULE
+        .as_ref()
+        .is_some_and(|game| game.contains(&caller));
+    if CAPTURE.is_some() && is_game {
+        GAME_SOCKETS.lock().expect("game sockets").insert(sock.0);
+    }
+}
+
+fn is_game_socket(sock: SOCKET) -> bool {
+    GAME_SOCKETS.lock().expect("game sockets").contains(&sock.0)
+}
+
+/// Runs `f` without capturing the socket IO of this thread, like the proxy handshake
+pub fn without_capture<T>(f: impl FnOnce() -> T) -> T {
+    let prev = SUSPENDED.replace(true);
+    let res = f();
+    SUSPENDED.set(prev);
+    res
+}
+
+fn record(sock: SOCKET, dir: PacketDir, data: &[u8]) {
+    if data.is_empty() || SUSPENDED.get() || !is_game_socket(sock) {
+        return;
+    }
+    let Some(capture) = CAPTURE.as_ref() else {
+        return;
+    };
+
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+    if let Err(err) = capture
+        .lock()
+        .expect("raw capture")
+        .data(sock.0 as u64, dir, data, seq)
+    {
+        log::error!("Failed to write raw capture: {err:?}");
+    }
+}
+
+unsafe fn record_bufs(sock: SOCKET, dir: PacketDir, bufs: *const WSABUF, count: u32, len: usize) {
+    let mut left = len;
+    for buf in std::slice::from_raw_parts(bufs, count as usize) {
+        let n = (buf.len as usize).min(left);
+        record(sock, dir, std::slice::from_raw_parts(buf.buf.0, n));
+        left -= n;
+    }
+}
+
+/// Flushes the capture, called by the session export
+pub fn flush() {
+    let Some(capture) = CAPTURE.as_ref() else {
+        return;
+    };
+
+    if let Err(err) = capture.lock().expect("raw capture").flush() {
+        log::error!("Failed to flush raw capture: {err:?}");
+    }
+}
+
+static_win32_fn_hook!(
+    SEND_HOOK,
+    w!("ws2_32.dll"),
+    s!("send"),
+    send_detour,
+    type FnSend = extern "system" fn(SOCKET, *const u8, i32, i32) -> i32
+);
+
+extern "system" fn send_detour(sock: SOCKET, buf: *const u8, len: i32, flags: i32) -> i32 {
+    let n = SEND_HOOK.call(sock, buf, len, flags);
+    if n > 0 {
+        record(sock, PacketDir::Send, unsafe {
+            std::slice::from_raw_parts(buf, n as usize)
+        });
+    }
+    n
+}
+
+static_win32_fn_hook!(
+    RECV_HOOK,
+    w!("ws2_32.dll"),
+    s!("recv"),
+    recv_detour,
+    type FnRecv = extern "system" fn(SOCKET, *mut u8, i32, i32) -> i32
+);
+
+extern "system" fn recv_detour(sock: SOCKET, buf: *mut u8, len: i32, flags: i32) -> i32 {
+    let n = RECV_HOOK.call(sock, buf, len, flags);
+    if n > 0 && flags & MSG_PEEK.0 == 0 {
+        record(sock, PacketDir::Recv, unsafe {
+            std::slice::from_raw_parts(buf, n as usize)
+        });
+    }
+    n
+}
+
+static_win32_fn_hook!(
+    WSA_SEND_HOOK,
+    w!("ws2_32.dll"),
+    s!("WSASend"),
+    wsa_send_detour,
+    type FnWsaSend = extern "system" fn(
+        SOCKET,
+        *const WSABUF,
+        u32,
+        *mut u32,
+        u32,
+        *mut c_void,
+        *mut c_void,
+    ) -> i32
+);
+
+extern "system" fn wsa_send_detour(
+    sock: SOCKET,
+    bufs: *const WSABUF,
+    count: u32,
+    sent: *mut u32,
+    flags: u32,
+    overlapped: *mut c_void,
+    completion: *mut c_void,
+) -> i32 {
+    let res = WSA_SEND_HOOK.call(sock, bufs, count, sent, flags, overlapped, completion);
+    let len = match res {
+        0 if !sent.is_null() => unsafe { *sent as usize },
+        // An overlapped send is queued completely
+        0 => usize::MAX,
+        SOCKET_ERROR if unsafe { WSAGetLastError() } == WSA_IO_PENDING => usize::MAX,
+        _ => return res,
+    };
+    unsafe { record_bufs(sock, PacketDir::Send, bufs, count, len) };
+    res
+}
+
+static_win32_fn_hook!(
+    WSA_RECV_HOOK,
+    w!("ws2_32.dll"),
+    s!("WSARecv"),
+    wsa_recv_detour,
+    type FnWsaRecv = extern "system" fn(
+        SOCKET,
+        *const WSABUF,
+        u32,
+        *mut u32,
+        *mut u32,
+        *mut c_void,
+        *mut c_void,
+    ) -> i32
+);
+
+extern "system" fn wsa_recv_detour(
+    sock: SOCKET,
+    bufs: *const WSABUF,
+    count: u32,
+    recvd: *mut u32,
+    flags: *mut u32,
+    overlapped: *mut c_void,
+    completion: *mut c_void,
+) -> i32 {
+    let peek = unsafe { flags.as_ref() }.is_some_and(|flags| flags & MSG_PEEK.0 as u32 != 0);
+    let res = WSA_RECV_HOOK.call(sock, bufs, count, recvd, flags, overlapped, completion);
+    if peek || !is_game_socket(sock) {
+        return res;
+    }
+    if !overlapped.is_null() {
+        // The data arrives after the call returned
+        if !WARNED_OVERLAPPED.swap(true, Ordering::Relaxed) {
+            log::warn!("Overlapped WSARecv is not captured");
+        }
+    } else if res == 0 && !recvd.is_null() {
+        unsafe { record_bufs(sock, PacketDir::Recv, bufs, count, *recvd as usize) };
+    }
+    res
+}
+
+static_win32_fn_hook!(
+    CLOSE_SOCKET_HOOK,
+    w!("ws2_32.dll"),
+    s!("closesocket"),
+    close_socket_detour,
+    type FnCloseSocket = extern "system" fn(SOCKET) -> i32
+);
+
+extern "system" fn close_socket_detour(sock: SOCKET) -> i32 {
+    GAME_SOCKETS.lock().expect("game sockets").remove(&sock.0);
+    if let Some(capture) = CAPTURE.as_ref() {
+        if let Err(err) = capture.lock().expect("raw capture").close(sock.0 as u64) {
+            log::error!("Failed to close raw capture connection: {err:?}");
+        }
+    }
+    CLOSE_SOCKET_HOOK.call(sock)
+}
+
+hook_list!(
+    RawCaptureHooks,
+    SEND_HOOK,
+    RECV_HOOK,
+    WSA_SEND_HOOK,
+    WSA_RECV_HOOK,
+    CLOSE_SOCKET_HOOK,
+);
@@ -0,0 +1,235 @@
+//! Redirection of the server addresses.
+//!
+//! The login server replaces the connects to the original login server, which are matched by
+//! address or by the login port. Migration addresses are mapped exactly, the remaining ones go
+//! through the hosts mapping. The Winsock `connect` is hooked, so It works with unpatched client binaries.
+//! A configured proxy is connected instead of the redirected address. The raw capture uses the
+//! hook to track the game sockets.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    sync::LazyLock,
+};
+
+use anyhow::Context;
+use retour::GenericDetour;
+use windows::{
+    core::{s, w},
+    Win32::Networking::WinSock::{AF_INET, IN_ADDR, IN_ADDR_0, SOCKADDR, SOCKADDR_IN, SOCKET},
+};
+
+use crate::{
+    config::{RedirectData, CONFIG},
+    hook_list, proxy, raw_capture, ret_addr, static_win32_fn_hook,
+};
+
+/// Resolves a `host:port` to the first IPv4 address, as the client only supports IPv4
+pub fn resolve(addr: &str) -> anyhow::Result<SocketAddrV4> {
+    addr.to_socket_addrs()
+        .with_context(|| format!("Resolving {addr}"))?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .with_context(|| format!("No IPv4 address for {addr}"))
+}
+
+const DEFAULT_LOGIN_PORT: u16 = 8484;
+
+#[derive(Debug)]
+pub struct Redirector {
+    login: Option<SocketAddrV4>,
+    login_orig: Vec<SocketAddrV4>,
+    login_port: u16,
+    migrations: HashMap<SocketAddrV4, SocketAddrV4>,
+    hosts: BTreeMap<Ipv4Addr, Ipv4Addr>,
+}
+
+impl Redirector {
+    pub fn new(data: &RedirectData) -> anyhow::Result<Self> {
+        Ok(Self {
+            login: data.login.as_deref().map(resolve).transpose()?,
+            login_orig: data.login_orig.clone(),
+            login_port: data.login_port.unwrap_or(DEFAULT_LOGIN_PORT),
+            migrations: data
+                .migrations
+                .iter()
+                .map(|(&from, to)| Ok((from, resolve(to)?)))
+                .collect::<anyhow::Result<_>>()?,
+            hosts: data.hosts.clone(),
+        })
+    }
+
+    fn is_login(&self, addr: SocketAddrV4) -> bool {
+        if self.login_orig.is_empty() {
+            addr.port() == self.login_port
+        } else {
+            self.login_orig.contains(&addr)
+        }
+    }
+
+    /// Address, which should be connected to instead of `addr`
+    pub fn map(&self, addr: SocketAddrV4) -> SocketAddrV4 {
+        if let Some(&to) = self.migrations.get(&addr) {
+            return to;
+        }
+
+        if let Some(login) = self.login.filter(|_| self.is_login(addr)) {
+            return login;
+        }
+
+        match self.hosts.get(addr.ip()) {
+            Some(&ip) => SocketAddrV4::new(ip, addr.port()),
+            None => addr,
+        }
+    }
+}
+
+static REDIRECTOR: LazyLock<Option<Redirector>> = LazyLock::new(|| {
+    let data = CONFIG.get()?.redirect.as_ref()?;
+    match Redirector::new(data) {
+        Ok(redirector) => Some(redirector),
+        Err(err) => {
+            log::error!("Invalid redirect config: {err:?}");
+            None
+        }
+    }
+});
+
+/// Resolves the configured addresses once, as the connect runs on the game thread
+pub fn init() {
+    LazyLock::force(&REDIRECTOR);
+    proxy::init();
+}
+
+/// Applies the configured redirection, addresses are kept without a config
+pub fn redirect(addr: SocketAddrV4) -> SocketAddrV4 {
+    let Some(redirector) = REDIRECTOR.as_ref() else {
+        return addr;
+    };
+
+    let to = redirector.map(addr);
+    if to != addr {
+        log::info!("Redirecting {addr} to {to}");
+    }
+    to
+}
+
+pub fn sockaddr_to_addr(sa: &SOCKADDR_IN) -> SocketAddrV4 {
+    let ip = Ipv4Addr::from(u32::from_be(unsafe { sa.sin_addr.S_un.S_addr }));
+    SocketAddrV4::new(ip, u16::from_be(sa.sin_port))
+}
+
+pub fn addr_to_sockaddr(addr: SocketAddrV4) -> SOCKADDR_IN {
+    SOCKADDR_IN {
+        sin_family: AF_INET,
+        sin_port: addr.port().to_be(),
+        sin_addr: IN_ADDR {
+            S_un: IN_ADDR_0 {
+                S_addr: u32::from(*addr.ip()).to_be(),
+            },
+        },
+        ..Default::default()
+    }
+}
+
+static_win32_fn_hook!(
+    CONNECT_HOOK,
+    w!("ws2_32.dll"),
+    s!("connect"),
+    connect_detour,
+    type FnConnect = extern "system" fn(SOCKET, *const SOCKADDR, i32) -> i32
+);
+
+extern "system" fn connect_detour(sock: SOCKET, name: *const SOCKADDR, name_len: i32) -> i32 {
+    raw_capture::on_connect(sock, ret_addr!());
+
+    // Only IPv4 addresses are redirected
+    let is_v4 = unsafe { name.as_ref() }.is_some_and(|sa| sa.sa_family == AF_INET);
+    if !is_v4 || (name_len as usize) < std::mem::size_of::<SOCKADDR_IN>() {
+        return CONNECT_HOOK.call(sock, name, name_len);
+    }
+
+    let addr = sockaddr_to_addr(unsafe { &*(name as *const SOCKADDR_IN) });
+    let target = redirect(addr);
+    let connect = |sa: &SOCKADDR_IN| {
+        CONNECT_HOOK.call(
+            sock,
+            sa as *const SOCKADDR_IN as *const SOCKADDR,
+            std::mem::size_of::<SOCKADDR_IN>() as i32,
+        )
+    };
+
+    proxy::connect(sock, target, connect)
+}
+
+hook_list!(RedirectHooks, CONNECT_HOOK,);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map() {
+        let data: RedirectData = toml::from_str(
+            r#"
+            login = "127.0.0.1:8484"
+            migrations = { "8.31.99.141:8585" = "127.0.0.1:8585" }
+            hosts = { "8.31.99.142" = "192.168.0.2" }
+            "#,
+        )
+        .unwrap();
+        let redirector = Redirector::new(&data).unwrap();
+
+        let addr = |s: &str| s.parse::<SocketAddrV4>().unwrap();
+        assert_eq!(
+            redirector.map(addr("8.31.99.140:8484")),
+            addr("127.0.0.1:8484")
+        );
+        assert_eq!(
+            redirector.map(addr("8.31.99.141:8585")),
+            addr("127.0.0.1:8585")
+        );
+        assert_eq!(
+            redirector.map(addr("8.31.99.142:8586")),
+            addr("192.168.0.2:8586")
+        );
+        assert_eq!(
+            redirector.map(addr("8.31.99.143:8587")),
+            addr("8.31.99.143:8587")
+        );
+        // Another login server of the client
+        assert_eq!(
+            redirector.map(addr("8.31.99.139:8484")),
+            addr("127.0.0.1:8484")
+        );
+    }
+
+    #[test]
+    fn login_orig() {
+        let data: RedirectData = toml::from_str(
+            r#"
+            login = "127.0.0.1:8484"
+            login_orig = ["8.31.99.140:8484"]
+            "#,
+        )
+        .unwrap();
+        let redirector = Redirector::new(&data).unwrap();
+
+        let addr = |s: &str| s.parse::<SocketAddrV4>().unwrap();
+        // A channel connect first doesn't become the login server
+        assert_eq!(
+            redirector.map(addr("8.31.99.141:8585")),
+            addr("8.31.99.141:8585")
+        );
+        assert_eq!(
+            redirector.map(addr("8.31.99.139:8484")),
+            addr("8.31.99.139:8484")
+        );
+        assert_eq!(
+            redirector.map(addr("8.31.99.140:8484")),
+            addr("127.0.0.1:8484")
+        );
+    }
+}
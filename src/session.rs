@@ -0,0 +1,81 @@
+//! Export of the session data like the handler map and coverage.
+//!
+//! Exports write files and load the symbols, so they must not run under the loader lock of
+//! `DllMain`. They run periodically from the socket update and a last time, when the process exits
+//! cleanly through `ExitProcess`.
+
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use retour::GenericDetour;
+use windows::core::{s, w};
+
+use crate::{
+    coverage, handler_map, hook_list, raw_capture, socket, static_win32_fn_hook, traffic_stats,
+};
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Data recorded by the hooks, which is taken by the next export
+pub struct Session<T>(LazyLock<Mutex<T>>);
+
+impl<T: Default> Session<T> {
+    pub const fn new() -> Self {
+        Self(LazyLock::new(Mutex::default))
+    }
+
+    pub fn record(&self, f: impl FnOnce(&mut T)) {
+        f(&mut self.0.lock().expect("session"))
+    }
+
+    /// Takes the data, which was recorded since the last export
+    pub fn take(&self) -> T {
+        std::mem::take(&mut *self.0.lock().expect("session"))
+    }
+}
+
+impl<T: Default> Default for Session<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static LAST_EXPORT: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
+
+fn export() {
+    handler_map::export();
+    coverage::export();
+    raw_capture::flush();
+}
+
+/// Exports periodically, called once per socket update
+pub fn tick() {
+    let mut last = LAST_EXPORT.lock().expect("last export");
+    if last.elapsed() < EXPORT_INTERVAL {
+        return;
+    }
+    *last = Instant::now();
+    drop(last);
+    export();
+}
+
+static_win32_fn_hook!(
+    EXIT_PROCESS_HOOK,
+    w!("kernel32.dll"),
+    s!("ExitProcess"),
+    exit_process_detour,
+    type FnExitProcess = extern "system" fn(u32)
+);
+
+// The other threads are still running here, unlike in the detach of `DllMain`
+extern "system" fn exit_process_detour(code: u32) {
+    log::info!("Exporting the session on exit");
+    export();
+    socket::flush_traces();
+    traffic_stats::finish_session();
+    EXIT_PROCESS_HOOK.call(code)
+}
+
+hook_list!(SessionHooks, EXIT_PROCESS_HOOK,);
@@ -117,12 +117,17 @@ pub struct CWvsApp {
 static_assertions::assert_eq_size!(CWvsApp, [u8; 0x8c]);
 
 pub mod addr {
+    pub const CLIENT_VERSION: u16 = 95;
+
     pub const CLOGO_INIT: usize = 0x60e240;
     pub const CLOGO_END: usize = 0x60bd00;
     pub const CMSGBOX_INIT: usize = 0x669370;
 
     pub const CLOGIN_INIT: usize = 0x5d8010;
     pub const CLOGIN_SEND_CHECK_PASSWORD_PACKET: usize = 0x5db9d0;
+    pub const SEND_CHECK_PASSWORD_OPCODE: u16 = 0x1;
+    pub const RECV_CHECK_PASSWORD_RESULT_OPCODE: u16 = 0x0;
+    pub const RECV_SELECT_WORLD_RESULT_OPCODE: u16 = 0xb;
     pub const CLOGIN_SEND_LOGIN_PACKET: usize = 0x5dbef0;
     pub const CLOGIN_SEND_SELECT_CHAR_PACKET: usize = 0x5da2a0;
     pub const CLOGIN_ON_RECOMMEND_WORLD_MESSAGE: usize = 0x5d7280;
@@ -133,6 +138,7 @@ pub mod addr {
 
     pub const CCLIENTSOCKET_SEND_PACKET: usize = 0x004af9f0;
     pub const CCLIENTSOCKET_PROCESS_PACKET: usize = 0x004b00f0;
+    pub const CCLIENTSOCKET_MANIPULATE_PACKET: usize = 0x004b0220;
     // Only required if the Send Packet function checks the return address(+95?)
     pub const SOCKET_SINGLETON_SEND_PACKET_RET: usize = 0x00429b8b + 5;
     pub const SEND_PACKET_RET_SPOOF: bool = true;
@@ -150,6 +156,12 @@ pub mod addr {
     pub const CINPACKET_DECODE_STR: usize = 0x484140;
     pub const CINPACKET_DECODE_BUF: usize = 0x4336a0;
 
+    // Not located yet for this version, can be set with `packet_tracing.hook_addrs`
+    pub const COUTPACKET_ENCODE8: Option<usize> = None;
+    pub const COUTPACKET_ENCODE_DOUBLE: Option<usize> = None;
+    pub const CINPACKET_DECODE8: Option<usize> = None;
+    pub const CINPACKET_DECODE_DOUBLE: Option<usize> = None;
+
     pub const CIOBUFFER_MANIPULATOR_EN: usize = 0x68c8e0;
     pub const CIOBUFFER_MANIPULATOR_DE: usize = 0x68cab0;
 
@@ -163,12 +175,17 @@ pub mod addr {
 }
 
 pub mod addr92 {
+    pub const CLIENT_VERSION: u16 = 92;
+
     pub const CLOGO_INIT: usize = 0x602730;
     pub const CLOGO_END: usize = 0x600da0;
     pub const CMSGBOX_INIT: usize = 0x65c7b0;
 
     pub const CLOGIN_INIT: usize = 0x5ce780;
     pub const CLOGIN_SEND_CHECK_PASSWORD_PACKET: usize = 0x5d2190;
+    pub const SEND_CHECK_PASSWORD_OPCODE: u16 = 0x1;
+    pub const RECV_CHECK_PASSWORD_RESULT_OPCODE: u16 = 0x0;
+    pub const RECV_SELECT_WORLD_RESULT_OPCODE: u16 = 0xb;
     pub const CLOGIN_SEND_LOGIN_PACKET: usize = 0x5d26b0;
     pub const CLOGIN_SEND_SELECT_CHAR_PACKET: usize = 0x5d0a60;
     pub const CLOGIN_ON_RECOMMEND_WORLD_MESSAGE: usize = 0x5cd030;
@@ -195,6 +212,12 @@ pub mod addr92 {
     pub const CINPACKET_DECODE_STR: usize = 0x480b60;
     pub const CINPACKET_DECODE_BUF: usize = 0x4347a0;
 
+    // Not located yet for this version, can be set with `packet_tracing.hook_addrs`
+    pub const COUTPACKET_ENCODE8: Option<usize> = None;
+    pub const COUTPACKET_ENCODE_DOUBLE: Option<usize> = None;
+    pub const CINPACKET_DECODE8: Option<usize> = None;
+    pub const CINPACKET_DECODE_DOUBLE: Option<usize> = None;
+
     pub const USE_SEND_PACKET_TRAMPOLINE: bool = false;
 }
 
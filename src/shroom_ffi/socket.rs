@@ -2,7 +2,7 @@ use std::ffi::{c_int, c_uchar, c_uint, c_ushort, c_void};
 
 use crate::fn_ref;
 
-use super::{addr, ztl::{zxarr::ZArray, zxstr::ZXString8}};
+use super::{addr, ztl::{zxarr::{ZArray, ZArrayBuf}, zxstr::ZXString8}};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -24,6 +24,84 @@ pub struct CInPacket {
     pub offset: c_uint,
 }
 
+/// Copy of an outgoing packet, which can be sent at a later time
+#[derive(Debug)]
+pub struct OwnedOutPacket {
+    pkt: COutPacket,
+    _buf: ZArrayBuf,
+}
+
+impl OwnedOutPacket {
+    pub fn copy_from(pkt: &COutPacket) -> Self {
+        let mut buf = ZArrayBuf::from_slice(pkt.send_buf.data());
+        Self {
+            pkt: COutPacket {
+                is_loopback: pkt.is_loopback,
+                send_buf: buf.as_zarray(),
+                offset: pkt.offset,
+                is_encrypted_by_shanda: pkt.is_encrypted_by_shanda,
+            },
+            _buf: buf,
+        }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut COutPacket {
+        &mut self.pkt as *mut _
+    }
+}
+
+/// Copy of an incoming packet, which can be processed at a later time
+#[derive(Debug)]
+pub struct OwnedInPacket {
+    pkt: CInPacket,
+    _buf: ZArrayBuf,
+}
+
+impl OwnedInPacket {
+    pub fn copy_from(pkt: &CInPacket) -> Self {
+        let mut buf = ZArrayBuf::from_slice(pkt.recv_buf.data());
+        Self {
+            pkt: CInPacket {
+                is_loopback: pkt.is_loopback,
+                state: pkt.state,
+                recv_buf: buf.as_zarray(),
+                len: pkt.len,
+                raw_seq: pkt.raw_seq,
+                data_len: pkt.data_len,
+                offset: pkt.offset,
+            },
+            _buf: buf,
+        }
+    }
+
+    /// Copy of the packet with replaced data(including the opcode), the lengths are adjusted
+    pub fn with_data(pkt: &CInPacket, header_len: usize, data: &[u8]) -> Self {
+        let mut raw = pkt.recv_buf.data()[..header_len].to_vec();
+        raw.extend_from_slice(data);
+        let mut buf = ZArrayBuf::from_slice(&raw);
+
+        let old_len = pkt.recv_buf.len() as isize;
+        let delta = raw.len() as isize - old_len;
+        let adjust = |len: c_ushort| (len as isize + delta).clamp(0, c_ushort::MAX as isize) as c_ushort;
+        Self {
+            pkt: CInPacket {
+                is_loopback: pkt.is_loopback,
+                state: pkt.state,
+                recv_buf: buf.as_zarray(),
+                len: adjust(pkt.len),
+                raw_seq: pkt.raw_seq,
+                data_len: adjust(pkt.data_len),
+                offset: pkt.offset,
+            },
+            _buf: buf,
+        }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut CInPacket {
+        &mut self.pkt as *mut _
+    }
+}
+
 fn_ref!(
     coutpacket_encode1,
     addr::COUTPACKET_ENCODE1,
@@ -81,6 +159,11 @@ fn_ref!(
     unsafe extern "thiscall" fn(*mut CInPacket, *mut c_void, c_uint)
 );
 
+// The 64 bit functions are not available for every version, so only the types are defined
+pub type CoutpacketEncode8 = unsafe extern "thiscall" fn(*mut COutPacket, u64);
+pub type CoutpacketEncodeDouble = unsafe extern "thiscall" fn(*mut COutPacket, f64);
+pub type CinpacketDecode8 = unsafe extern "thiscall" fn(*mut CInPacket) -> u64;
+pub type CinpacketDecodeDouble = unsafe extern "thiscall" fn(*mut CInPacket) -> f64;
 
 pub type CClientSocket = c_void;
 
@@ -98,7 +181,7 @@ fn_ref!(
 
 fn_ref!(
     cclientsocket_manipulate_packet,
-    0x4b0220,
+    addr::CCLIENTSOCKET_MANIPULATE_PACKET,
     unsafe extern "thiscall" fn(*mut CClientSocket)
 );
 
@@ -63,4 +63,43 @@ impl<T> ZArray<T> {
             &[]
         }
     }
+
+    pub unsafe fn data_mut(&mut self) -> &mut [T] {
+        let ln = self.len();
+        if ln > 0 {
+            std::slice::from_raw_parts_mut(self.0, ln)
+        } else {
+            &mut []
+        }
+    }
+}
+
+/// Byte array with a ZArray layout, which is owned by rust,
+/// so It must never be freed by the client
+#[derive(Debug)]
+pub struct ZArrayBuf {
+    raw: Vec<c_int>,
+}
+
+impl ZArrayBuf {
+    const HEADER_WORDS: usize = std::mem::size_of::<ZArrayHeader>() / std::mem::size_of::<c_int>();
+
+    pub fn from_slice(data: &[u8]) -> Self {
+        let mut raw = vec![0; Self::HEADER_WORDS + data.len().div_ceil(4)];
+        raw[0] = 1;
+        raw[1] = data.len() as c_int;
+        raw[2] = data.len() as c_int;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                raw.as_mut_ptr().add(Self::HEADER_WORDS) as *mut u8,
+                data.len(),
+            );
+        }
+        Self { raw }
+    }
+
+    pub fn as_zarray(&mut self) -> ZArray<u8> {
+        unsafe { ZArray::from_ptr(self.raw.as_mut_ptr().add(Self::HEADER_WORDS) as *mut u8) }
+    }
 }
\ No newline at end of file
@@ -1,28 +1,38 @@
 use std::{
-    ffi::{c_uchar, c_uint, c_ushort, c_void},
-    sync::{LazyLock, Mutex},
+    ffi::{c_uchar, c_uint, c_ushort, c_void, CStr},
+    sync::{Arc, LazyLock, Mutex},
+    time::Instant,
 };
 
+use shroom_trace::{redact::Redactor, stream::StreamServer};
+use windows::Win32::System::Diagnostics::Debug::CONTEXT;
+
 use crate::{
     config::{PacketTracingData, CONFIG},
-    hook_list, lazy_hook, ret_addr,
+    exceptions::init_symbols,
+    fuzz, hook_list, lazy_hook, login, net_sim, opt_lazy_hook, ret_addr, session, traffic_stats,
     shroom_ffi::{
         addr,
         socket::{
-            cclientsocket_process_packet, cclientsocket_send_packet, cinpacket_decode1,
-            cinpacket_decode2, cinpacket_decode4, cinpacket_decode_buf, cinpacket_decode_str,
-            coutpacket_encode1, coutpacket_encode2, coutpacket_encode4, coutpacket_encode_buf,
-            coutpacket_encode_str, send_packet_trampoline, CClientSocket, CInPacket, COutPacket,
-            CclientsocketProcessPacket, CclientsocketSendPacket, CinpacketDecode1,
-            CinpacketDecode2, CinpacketDecode4, CinpacketDecodeBuf, CinpacketDecodeStr,
-            CoutpacketEncode1, CoutpacketEncode2, CoutpacketEncode4, CoutpacketEncodeBuf,
-            CoutpacketEncodeStr,
+            cclientsocket_manipulate_packet, cclientsocket_process_packet,
+            cclientsocket_send_packet, cinpacket_decode1, cinpacket_decode2, cinpacket_decode4,
+            cinpacket_decode_buf, cinpacket_decode_str, coutpacket_encode1, coutpacket_encode2,
+            coutpacket_encode4, coutpacket_encode_buf, coutpacket_encode_str,
+            send_packet_trampoline, CClientSocket, CInPacket, COutPacket,
+            CclientsocketManipulatePacket, CclientsocketProcessPacket, CclientsocketSendPacket,
+            CinpacketDecode1, CinpacketDecode2, CinpacketDecode4, CinpacketDecodeBuf,
+            CinpacketDecodeStr, CoutpacketEncode1, CoutpacketEncode2, CoutpacketEncode4,
+            CinpacketDecode8, CinpacketDecodeDouble, CoutpacketEncode8, CoutpacketEncodeBuf,
+            CoutpacketEncodeDouble, CoutpacketEncodeStr, OwnedInPacket, OwnedOutPacket,
         },
         ztl::zxstr::ZXString8,
     },
     util::{
-        hooks::LazyHook,
+        delay_queue::{flush_due, DelayQueue},
+        hooks::{HookModule, LazyHook, OptLazyHook},
+        packet_rules::{PacketDir, PacketRules, RuleOutcome},
         packet_schema::{PacketStructElem, PacketStructLogger, ShroomPacket},
+        stack_walker::StackWalker,
     },
 };
 
@@ -30,39 +40,101 @@ pub fn tracing_data() -> &'static PacketTracingData {
     CONFIG.get().unwrap().packet_tracing.as_ref().unwrap()
 }
 
+fn is_tracing() -> bool {
+    CONFIG.get().unwrap().packet_tracing.is_some()
+}
+
+fn packet_rules() -> &'static PacketRules {
+    &CONFIG.get().unwrap().packet_rules
+}
+
+enum DelayedPacket {
+    Send(OwnedOutPacket),
+    Recv(OwnedInPacket),
+}
+
+static DELAYED_PACKETS: Mutex<DelayQueue<DelayedPacket>> = Mutex::new(DelayQueue::new());
+
+// Shared by both directions, so viewers get the packets in order
+static PACKET_STREAM: LazyLock<Option<Arc<StreamServer>>> = LazyLock::new(|| {
+    let port = tracing_data().stream_port?;
+    match StreamServer::bind(port, STREAM_CLIENT_CAPACITY) {
+        Ok(server) => {
+            log::info!("Packet stream listening on: {}", server.local_addr());
+            Some(Arc::new(server))
+        }
+        Err(err) => {
+            log::error!("Failed to start packet stream: {err:?}");
+            None
+        }
+    }
+});
+
+const STREAM_CLIENT_CAPACITY: usize = 1024;
+
+// The auto login password is masked in every packet
+fn redactor() -> Redactor {
+    let redactor = Redactor::new(tracing_data().redact.clone());
+    match CONFIG.get().unwrap().auto_login_data {
+        Some(ref auto_login) => redactor.with_secret(auto_login.password.0.as_bytes()),
+        None => redactor,
+    }
+}
+
 static SEND_CTX: LazyLock<Mutex<PacketStructLogger<COutPacket>>> = LazyLock::new(|| {
-    Mutex::new(PacketStructLogger::new(
-        tracing_data().send_file.clone(),
-        tracing_data().log_data,
-    ))
+    Mutex::new(
+        PacketStructLogger::new(
+            tracing_data().send_file.clone(),
+            tracing_data().log_data,
+            &tracing_data().composite_helpers,
+            &tracing_data().writer,
+        )
+        .with_stream(PACKET_STREAM.clone())
+        .with_redactor(redactor()),
+    )
 });
 
 static RECV_CTX: LazyLock<Mutex<PacketStructLogger<CInPacket>>> = LazyLock::new(|| {
-    Mutex::new(PacketStructLogger::new(
-        tracing_data().recv_file.clone(),
-        tracing_data().log_data,
-    ))
+    Mutex::new(
+        PacketStructLogger::new(
+            tracing_data().recv_file.clone(),
+            tracing_data().log_data,
+            &tracing_data().composite_helpers,
+            &tracing_data().writer,
+        )
+        .with_stream(PACKET_STREAM.clone())
+        .with_redactor(redactor()),
+    )
 });
 
+// Callers of the hooked function, has to be expanded inside of the hook
+macro_rules! call_stack {
+    () => {
+        if tracing_data().call_stack {
+            vec![ret_addr!(1), ret_addr!(2), ret_addr!(3)]
+        } else {
+            Vec::new()
+        }
+    };
+}
+
 macro_rules! add_send_elem {
     ($pkt:ident, $v:ident) => {
         let ret_addr = ret_addr!();
         let pkt = $pkt.as_ref().unwrap();
-        SEND_CTX
-            .lock()
-            .unwrap()
-            .add_elem(PacketStructElem::new(pkt.offset(), ret_addr, $v));
+        SEND_CTX.lock().unwrap().add_elem(
+            PacketStructElem::new(pkt.offset(), ret_addr, $v).with_call_stack(call_stack!()),
+        );
     };
 }
 
+// The offset must be taken before decoding the value
 macro_rules! add_recv_elem {
-    ($pkt:ident, $v:ident) => {
+    ($offset:ident, $v:ident) => {
         let ret_addr = ret_addr!();
-        let pkt = $pkt.as_ref().unwrap();
-        RECV_CTX
-            .lock()
-            .unwrap()
-            .add_elem(PacketStructElem::new(pkt.offset(), ret_addr, $v));
+        RECV_CTX.lock().unwrap().add_elem(
+            PacketStructElem::new($offset, ret_addr, $v).with_call_stack(call_stack!()),
+        );
     };
 }
 
@@ -107,6 +179,51 @@ unsafe extern "thiscall" fn coutpacket_encode_buf_hook(
     COUTPACKET_ENCODE_BUF_HOOK.call(this, p, len)
 }
 
+/// Falls back to the symbol of the pdb, if neither the config nor the version sets the address
+fn locate_hook(addr: Option<usize>, symbol: &CStr, key: &str) -> Option<usize> {
+    if addr.is_some() {
+        return addr;
+    }
+    let walker = StackWalker::from_ctx(CONTEXT::default());
+    let addr = init_symbols(&walker)
+        .then(|| walker.sym_from_name(symbol))
+        .flatten()
+        .map(|addr| addr as usize);
+    match addr {
+        Some(addr) => log::info!("Located {symbol:?} at {addr:#x} in the pdb"),
+        None => {
+            log::warn!("{symbol:?} is not located, set packet_tracing.hook_addrs.{key} to trace It")
+        }
+    }
+    addr
+}
+
+static COUTPACKET_ENCODE8_HOOK: OptLazyHook<CoutpacketEncode8> = opt_lazy_hook!(
+    locate_hook(
+        tracing_data().hook_addrs.encode8(),
+        c"COutPacket::Encode8",
+        "encode8"
+    ),
+    coutpacket_encode8_hook
+);
+unsafe extern "thiscall" fn coutpacket_encode8_hook(this: *mut COutPacket, v: u64) {
+    add_send_elem!(this, v);
+    COUTPACKET_ENCODE8_HOOK.as_ref().unwrap().call(this, v)
+}
+
+static COUTPACKET_ENCODE_DOUBLE_HOOK: OptLazyHook<CoutpacketEncodeDouble> = opt_lazy_hook!(
+    locate_hook(
+        tracing_data().hook_addrs.encode_double(),
+        c"COutPacket::EncodeDouble",
+        "encode_double"
+    ),
+    coutpacket_encode_double_hook
+);
+unsafe extern "thiscall" fn coutpacket_encode_double_hook(this: *mut COutPacket, v: f64) {
+    add_send_elem!(this, v);
+    COUTPACKET_ENCODE_DOUBLE_HOOK.as_ref().unwrap().call(this, v)
+}
+
 static CCLIENTSOCKET_SEND_PACKET_HOOK: LazyHook<CclientsocketSendPacket> =
     lazy_hook!(cclientsocket_send_packet, cclientsocket_send_packet_hook);
 
@@ -115,11 +232,42 @@ unsafe extern "thiscall" fn cclientsocket_send_packet_hook(
     pkt: *mut COutPacket,
 ) {
     let ret = ret_addr!();
-    SEND_CTX
-        .lock()
-        .expect("send")
-        .finish_send(ret, pkt.as_ref().unwrap());
+    let pkt_ref = pkt.as_mut().unwrap();
+    let outcome = packet_rules().apply(PacketDir::Send, pkt_ref.data_mut());
+    // Traced after the rules like received packets, dropped packets are not traced
+    if is_tracing() {
+        let mut ctx = SEND_CTX.lock().expect("send");
+        if matches!(outcome, RuleOutcome::Drop) {
+            ctx.clear();
+        } else {
+            ctx.finish_send(ret, pkt_ref);
+        }
+    }
+
+    match outcome {
+        RuleOutcome::Pass => send_simulated(this, pkt),
+        RuleOutcome::Drop => (),
+        RuleOutcome::Delay(delay) => DELAYED_PACKETS.lock().expect("delayed").push(
+            Instant::now() + delay,
+            DelayedPacket::Send(OwnedOutPacket::copy_from(pkt_ref)),
+        ),
+    }
+}
+
+// Simulated packets are queued with the delayed ones, which are flushed by the socket update
+unsafe fn send_simulated(this: *mut CClientSocket, pkt: *mut COutPacket) {
+    let pkt_ref = pkt.as_ref().unwrap();
+    match net_sim::due(PacketDir::Send, pkt_ref.data().len()) {
+        Some(due) => DELAYED_PACKETS
+            .lock()
+            .expect("delayed")
+            .push(due, DelayedPacket::Send(OwnedOutPacket::copy_from(pkt_ref))),
+        None => send_packet(this, pkt),
+    }
+}
 
+unsafe fn send_packet(this: *mut CClientSocket, pkt: *mut COutPacket) {
+    traffic_stats::record(PacketDir::Send, pkt.as_ref().unwrap().data());
     if addr::SEND_PACKET_RET_SPOOF {
         send_packet_trampoline(this, pkt);
     } else {
@@ -130,24 +278,27 @@ unsafe extern "thiscall" fn cclientsocket_send_packet_hook(
 static CINPACKET_DECODE1_HOOK: LazyHook<CinpacketDecode1> =
     lazy_hook!(cinpacket_decode1, cinpacket_decode1_hook);
 unsafe extern "thiscall" fn cinpacket_decode1_hook(this: *mut CInPacket) -> c_uchar {
+    let offset = this.as_ref().unwrap().offset();
     let v = CINPACKET_DECODE1_HOOK.call(this);
-    add_recv_elem!(this, v);
+    add_recv_elem!(offset, v);
     v
 }
 
 static CINPACKET_DECODE2_HOOK: LazyHook<CinpacketDecode2> =
     lazy_hook!(cinpacket_decode2, cinpacket_decode2_hook);
 unsafe extern "thiscall" fn cinpacket_decode2_hook(this: *mut CInPacket) -> c_ushort {
+    let offset = this.as_ref().unwrap().offset();
     let v = CINPACKET_DECODE2_HOOK.call(this);
-    add_recv_elem!(this, v);
+    add_recv_elem!(offset, v);
     v
 }
 
 static CINPACKET_DECODE4_HOOK: LazyHook<CinpacketDecode4> =
     lazy_hook!(cinpacket_decode4, cinpacket_decode4_hook);
 unsafe extern "thiscall" fn cinpacket_decode4_hook(this: *mut CInPacket) -> c_uint {
+    let offset = this.as_ref().unwrap().offset();
     let v = CINPACKET_DECODE4_HOOK.call(this);
-    add_recv_elem!(this, v);
+    add_recv_elem!(offset, v);
     v
 }
 
@@ -157,9 +308,10 @@ unsafe extern "thiscall" fn cinpacket_decode_str_hook(
     this: *mut CInPacket,
     out: *mut ZXString8,
 ) -> ZXString8 {
+    let offset = this.as_ref().unwrap().offset();
     let v = CINPACKET_DECODE_STR_HOOK.call(this, out);
     let v_ref = &v;
-    add_recv_elem!(this, v_ref);
+    add_recv_elem!(offset, v_ref);
     v
 }
 
@@ -170,9 +322,40 @@ unsafe extern "thiscall" fn cinpacket_decode_buf_hook(
     p: *mut c_void,
     len: c_uint,
 ) {
+    let offset = this.as_ref().unwrap().offset();
     CINPACKET_DECODE_BUF_HOOK.call(this, p, len);
     let slice = std::slice::from_raw_parts(p as *const u8, len as usize);
-    add_recv_elem!(this, slice);
+    add_recv_elem!(offset, slice);
+}
+
+static CINPACKET_DECODE8_HOOK: OptLazyHook<CinpacketDecode8> = opt_lazy_hook!(
+    locate_hook(
+        tracing_data().hook_addrs.decode8(),
+        c"CInPacket::Decode8",
+        "decode8"
+    ),
+    cinpacket_decode8_hook
+);
+unsafe extern "thiscall" fn cinpacket_decode8_hook(this: *mut CInPacket) -> u64 {
+    let offset = this.as_ref().unwrap().offset();
+    let v = CINPACKET_DECODE8_HOOK.as_ref().unwrap().call(this);
+    add_recv_elem!(offset, v);
+    v
+}
+
+static CINPACKET_DECODE_DOUBLE_HOOK: OptLazyHook<CinpacketDecodeDouble> = opt_lazy_hook!(
+    locate_hook(
+        tracing_data().hook_addrs.decode_double(),
+        c"CInPacket::DecodeDouble",
+        "decode_double"
+    ),
+    cinpacket_decode_double_hook
+);
+unsafe extern "thiscall" fn cinpacket_decode_double_hook(this: *mut CInPacket) -> f64 {
+    let offset = this.as_ref().unwrap().offset();
+    let v = CINPACKET_DECODE_DOUBLE_HOOK.as_ref().unwrap().call(this);
+    add_recv_elem!(offset, v);
+    v
 }
 
 static CCLIENTSOCKET_PROCESS_PACKET_HOOK: LazyHook<CclientsocketProcessPacket> = lazy_hook!(
@@ -184,10 +367,51 @@ unsafe extern "thiscall" fn cclientsocket_process_packet_hook(
     this: *mut CClientSocket,
     pkt: *mut CInPacket,
 ) {
+    let pkt_ref = pkt.as_mut().unwrap();
+    match packet_rules().apply(PacketDir::Recv, pkt_ref.data_mut()) {
+        RuleOutcome::Pass => process_simulated(this, pkt),
+        RuleOutcome::Drop => (),
+        RuleOutcome::Delay(delay) => DELAYED_PACKETS.lock().expect("delayed").push(
+            Instant::now() + delay,
+            DelayedPacket::Recv(OwnedInPacket::copy_from(pkt_ref)),
+        ),
+    }
+}
+
+unsafe fn process_simulated(this: *mut CClientSocket, pkt: *mut CInPacket) {
+    let pkt_ref = pkt.as_ref().unwrap();
+    match net_sim::due(PacketDir::Recv, pkt_ref.data().len()) {
+        Some(due) => DELAYED_PACKETS
+            .lock()
+            .expect("delayed")
+            .push(due, DelayedPacket::Recv(OwnedInPacket::copy_from(pkt_ref))),
+        None => process_packet(this, pkt),
+    }
+}
+
+unsafe fn process_packet(this: *mut CClientSocket, pkt: *mut CInPacket) {
+    // The mutated copy is processed instead, the case stays logged If the handler throws
+    if let Some(mut fuzzed) = fuzz::mutate(pkt.as_ref().unwrap()) {
+        process_packet_inner(this, fuzzed.as_mut_ptr());
+        fuzz::finish_case();
+        return;
+    }
+
+    process_packet_inner(this, pkt);
+}
+
+unsafe fn process_packet_inner(this: *mut CClientSocket, pkt: *mut CInPacket) {
+    traffic_stats::record(PacketDir::Recv, pkt.as_ref().unwrap().data());
+    login::on_recv(pkt.as_ref().unwrap().data());
+    if !is_tracing() {
+        CCLIENTSOCKET_PROCESS_PACKET_HOOK.call(this, pkt);
+        return;
+    }
+
     RECV_CTX
         .lock()
         .expect("recv")
-        .set_packet_data(pkt.as_ref().unwrap());
+        .begin_process(pkt.as_ref().unwrap());
     CCLIENTSOCKET_PROCESS_PACKET_HOOK.call(this, pkt);
     RECV_CTX
         .lock()
@@ -195,6 +419,55 @@ unsafe extern "thiscall" fn cclientsocket_process_packet_hook(
         .finish_process(pkt.as_ref().unwrap());
 }
 
+/// Writes the partially decoded receive structure, when a handler throws.
+/// Called from the exception handler, so the context is only try locked
+pub fn finish_incomplete_recv(throw_ret_addr: usize) {
+    let tracing = CONFIG
+        .get()
+        .is_some_and(|cfg| cfg.packet_tracing.is_some());
+    if !tracing {
+        return;
+    }
+
+    let Ok(mut ctx) = RECV_CTX.try_lock() else {
+        return;
+    };
+    if ctx.finish_incomplete(throw_ret_addr) {
+        log::warn!("Packet handler threw at: {throw_ret_addr:#x}, wrote incomplete structure");
+        // The exception may end the process, before the writer thread gets to It
+        ctx.flush();
+    }
+}
+
+/// Writes the queued trace records, the static loggers are never dropped
+pub fn flush_traces() {
+    if !is_tracing() {
+        return;
+    }
+
+    SEND_CTX.lock().expect("send").flush();
+    RECV_CTX.lock().expect("recv").flush();
+}
+
+// Delayed packets are flushed from the game thread, once per socket update
+static CCLIENTSOCKET_MANIPULATE_PACKET_HOOK: LazyHook<CclientsocketManipulatePacket> = lazy_hook!(
+    cclientsocket_manipulate_packet,
+    cclientsocket_manipulate_packet_hook
+);
+
+unsafe extern "thiscall" fn cclientsocket_manipulate_packet_hook(this: *mut CClientSocket) {
+    CCLIENTSOCKET_MANIPULATE_PACKET_HOOK.call(this);
+    traffic_stats::tick();
+    session::tick();
+    login::tick();
+
+    // Handlers of delayed packets can send packets, which are delayed again
+    flush_due(&DELAYED_PACKETS, Instant::now(), |delayed| match delayed {
+        DelayedPacket::Send(mut pkt) => send_packet(this, pkt.as_mut_ptr()),
+        DelayedPacket::Recv(mut pkt) => process_packet(this, pkt.as_mut_ptr()),
+    });
+}
+
 hook_list!(
     PacketHooks,
     CINPACKET_DECODE1_HOOK,
@@ -202,11 +475,20 @@ hook_list!(
     CINPACKET_DECODE4_HOOK,
     CINPACKET_DECODE_STR_HOOK,
     CINPACKET_DECODE_BUF_HOOK,
-    CCLIENTSOCKET_PROCESS_PACKET_HOOK,
+    CINPACKET_DECODE8_HOOK,
+    CINPACKET_DECODE_DOUBLE_HOOK,
     COUTPACKET_ENCODE1_HOOK,
     COUTPACKET_ENCODE2_HOOK,
     COUTPACKET_ENCODE4_HOOK,
     COUTPACKET_ENCODE_STR_HOOK,
     COUTPACKET_ENCODE_BUF_HOOK,
+    COUTPACKET_ENCODE8_HOOK,
+    COUTPACKET_ENCODE_DOUBLE_HOOK,
+);
+
+hook_list!(
+    SocketHooks,
+    CCLIENTSOCKET_PROCESS_PACKET_HOOK,
     CCLIENTSOCKET_SEND_PACKET_HOOK,
+    CCLIENTSOCKET_MANIPULATE_PACKET_HOOK,
 );
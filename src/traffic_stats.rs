@@ -0,0 +1,76 @@
+use std::{
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use shroom_trace::{stats::TrafficStats, PacketDir};
+
+use crate::config::{TrafficStatsData, CONFIG};
+
+/// Totals since the injection, which are reported periodically and on exit
+struct TrafficTotals {
+    stats: TrafficStats,
+    started: Instant,
+    last_report: Instant,
+}
+
+static TOTALS: LazyLock<Mutex<TrafficTotals>> = LazyLock::new(|| {
+    let now = Instant::now();
+    Mutex::new(TrafficTotals {
+        stats: TrafficStats::new(),
+        started: now,
+        last_report: now,
+    })
+});
+
+fn stats_data() -> Option<&'static TrafficStatsData> {
+    CONFIG.get()?.traffic_stats.as_ref()
+}
+
+/// Records a packet, which was sent or processed
+pub fn record(dir: PacketDir, data: &[u8]) {
+    if stats_data().is_none() || data.len() < 2 {
+        return;
+    }
+
+    let opcode = u16::from_le_bytes([data[0], data[1]]);
+    TOTALS
+        .lock()
+        .expect("traffic stats")
+        .stats
+        .record(dir, opcode, data.len());
+}
+
+fn report(totals: &TrafficTotals, stats_data: &TrafficStatsData, title: &str) {
+    let report = totals.stats.report(totals.started.elapsed());
+    log::info!("{title}\n{}", report.table(stats_data.top));
+    if let Err(err) = report.save(&stats_data.file) {
+        log::error!("Failed to write traffic stats: {err:?}");
+    }
+}
+
+/// Writes the periodic report, called once per socket update
+pub fn tick() {
+    let Some(stats_data) = stats_data() else {
+        return;
+    };
+
+    let mut totals = TOTALS.lock().expect("traffic stats");
+    if totals.last_report.elapsed() < Duration::from_secs(stats_data.interval) {
+        return;
+    }
+    totals.last_report = Instant::now();
+    report(&totals, stats_data, "Traffic stats");
+}
+
+/// Writes the session total, called once on exit
+pub fn finish_session() {
+    let Some(stats_data) = stats_data() else {
+        return;
+    };
+
+    let totals = TOTALS.lock().expect("traffic stats");
+    if !totals.stats.is_empty() {
+        report(&totals, stats_data, "Session traffic total");
+    }
+}
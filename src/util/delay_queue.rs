@@ -0,0 +1,84 @@
+use std::{sync::Mutex, time::Instant};
+
+/// Queue of items, which become available after their due time.
+/// Items with the same due time keep their insertion order.
+#[derive(Debug)]
+pub struct DelayQueue<T> {
+    items: Vec<(Instant, T)>,
+}
+
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> DelayQueue<T> {
+    pub const fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn push(&mut self, due: Instant, item: T) {
+        let ix = self.items.partition_point(|(t, _)| *t <= due);
+        self.items.insert(ix, (due, item));
+    }
+
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        match self.items.first() {
+            Some((due, _)) if *due <= now => Some(self.items.remove(0).1),
+            _ => None,
+        }
+    }
+}
+
+/// Handles the due items of a shared queue. The lock is released before an item is handled,
+/// so the handler can push to the queue again
+pub fn flush_due<T>(queue: &Mutex<DelayQueue<T>>, now: Instant, mut handle: impl FnMut(T)) {
+    loop {
+        // Popping in the condition of a `while let` would keep the guard alive in the body
+        let next = queue.lock().expect("delay queue").pop_due(now);
+        let Some(item) = next else {
+            break;
+        };
+        handle(item);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn push_from_flush() {
+        let now = Instant::now();
+        let queue = Mutex::new(DelayQueue::new());
+        queue.lock().unwrap().push(now, 1);
+        queue.lock().unwrap().push(now, 2);
+
+        let mut handled = Vec::new();
+        flush_due(&queue, now, |item| {
+            handled.push(item);
+            // Like a delayed receive, which sends a delayed reply
+            let mut queue = queue.try_lock().expect("queue is unlocked while handling");
+            queue.push(now + Duration::from_millis(10), item * 10);
+        });
+        assert_eq!(handled, [1, 2]);
+
+        handled.clear();
+        flush_due(&queue, now + Duration::from_millis(10), |item| {
+            handled.push(item)
+        });
+        assert_eq!(handled, [10, 20]);
+        assert!(queue.lock().unwrap().is_empty());
+    }
+}
@@ -75,6 +75,18 @@ macro_rules! lazy_hook {
     };
 }
 
+/// Hook for a function, which address is not known for every version
+pub type OptLazyHook<T> = std::sync::LazyLock<Option<GenericDetour<T>>>;
+
+#[macro_export]
+macro_rules! opt_lazy_hook {
+    ($addr:expr, $hook:path) => {
+        std::sync::LazyLock::new(move || {
+            $addr.map(|addr| unsafe { $crate::util::hooks::ms_fn_hook(addr, $hook) })
+        })
+    };
+}
+
 #[macro_export]
 macro_rules! static_lazy_hook {
     ($name:ident, $target_ty:ty, $hook:path) => {
@@ -149,6 +161,22 @@ pub trait HookModule {
     }
 }
 
+impl<T: Function> HookModule for Option<GenericDetour<T>> {
+    unsafe fn enable(&self) -> anyhow::Result<()> {
+        if let Some(hook) = self {
+            hook.enable()?;
+        }
+        Ok(())
+    }
+
+    unsafe fn disable(&self) -> anyhow::Result<()> {
+        if let Some(hook) = self {
+            hook.disable()?;
+        }
+        Ok(())
+    }
+}
+
 impl<T: Function> HookModule for GenericDetour<T> {
     unsafe fn enable(&self) -> anyhow::Result<()> {
         self.enable()?;
@@ -6,7 +6,9 @@ use region::Protection;
 
 use self::hooks::HookModule;
 
+pub mod delay_queue;
 pub mod hooks;
+pub mod packet_rules;
 pub mod packet_schema;
 pub mod profiler;
 pub mod ref_time;
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+pub use shroom_trace::PacketDir;
+
+/// Byte string, which is written as hex in the config(e.g. "01 00 FF")
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let hex = s
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        if hex.len() % 2 != 0 {
+            return Err(serde::de::Error::custom("odd number of hex digits"));
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for HexBytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl std::fmt::Display for HexBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes which must be present at the offset, the offset includes the opcode
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BytePattern {
+    pub offset: usize,
+    pub bytes: HexBytes,
+}
+
+impl BytePattern {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.get(self.offset..self.offset + self.bytes.0.len()) == Some(&self.bytes.0[..])
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum RuleAction {
+    /// Overwrites the bytes at the offset, the packet length stays the same
+    Replace { offset: usize, bytes: HexBytes },
+    Drop,
+    Delay { ms: u64 },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PacketRule {
+    pub name: String,
+    pub dir: PacketDir,
+    pub opcode: u16,
+    #[serde(default)]
+    pub patterns: Vec<BytePattern>,
+    pub action: RuleAction,
+}
+
+impl PacketRule {
+    pub fn matches(&self, dir: PacketDir, data: &[u8]) -> bool {
+        self.dir == dir
+            && data.get(..2) == Some(&self.opcode.to_le_bytes()[..])
+            && self.patterns.iter().all(|p| p.matches(data))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleOutcome {
+    Pass,
+    Drop,
+    Delay(Duration),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct PacketRules(pub Vec<PacketRule>);
+
+impl PacketRules {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Applies all matching rules in order, replacements are done in place.
+    /// The first rule which drops or delays the packet stops the evaluation.
+    pub fn apply(&self, dir: PacketDir, data: &mut [u8]) -> RuleOutcome {
+        for rule in self.0.iter() {
+            if !rule.matches(dir, data) {
+                continue;
+            }
+
+            log::info!(
+                "Packet rule '{}' hit: {:?} opcode: {:#x} len: {}",
+                rule.name,
+                dir,
+                rule.opcode,
+                data.len()
+            );
+
+            match rule.action {
+                RuleAction::Replace {
+                    offset,
+                    ref bytes,
+                } => match data.get_mut(offset..offset + bytes.0.len()) {
+                    Some(dst) => dst.copy_from_slice(&bytes.0),
+                    None => log::warn!(
+                        "Packet rule '{}' replace at {offset} exceeds packet length {}",
+                        rule.name,
+                        data.len()
+                    ),
+                },
+                RuleAction::Drop => return RuleOutcome::Drop,
+                RuleAction::Delay { ms } => return RuleOutcome::Delay(Duration::from_millis(ms)),
+            }
+        }
+
+        RuleOutcome::Pass
+    }
+}
@@ -1,29 +1,44 @@
 use std::{
-    ffi::{c_uchar, c_uint, c_ushort},
-    fs::File,
-    io::{BufWriter, Write},
+    ffi::c_uchar,
     marker::PhantomData,
     path::Path,
-    ptr::null_mut,
+    ptr::{null, null_mut},
+    sync::Arc,
 };
 
-use serde::{Deserialize, Serialize};
+use shroom_trace::{
+    packet_struct::PacketTraceRecord,
+    redact::Redactor,
+    stream::{StreamRecord, StreamServer},
+    writer::{TraceWriter, WriterCfg, WriterStats},
+    PacketDir,
+};
 
-use crate::shroom_ffi::{
-    socket::{CInPacket, COutPacket},
-    ztl::{zxarr::ZArray, zxstr::ZXString8},
+use crate::{
+    coverage, fuzz, handler_map, raw_capture,
+    shroom_ffi::{
+        socket::{CInPacket, COutPacket},
+        ztl::{zxarr::ZArray, zxstr::ZXString8},
+    },
 };
 
 pub trait ShroomPacket {
     const DATA_OFFSET: usize;
+    const DIR: PacketDir;
 
     fn raw_data(&self) -> &ZArray<u8>;
+    fn raw_data_mut(&mut self) -> &mut ZArray<u8>;
     fn len(&self) -> usize;
 
     fn data(&self) -> &[u8] {
         &self.raw_data().data()[Self::DATA_OFFSET..Self::DATA_OFFSET + self.len()]
     }
 
+    fn data_mut(&mut self) -> &mut [u8] {
+        let len = self.len();
+        unsafe { &mut self.raw_data_mut().data_mut()[Self::DATA_OFFSET..Self::DATA_OFFSET + len] }
+    }
+
     fn opcode(&self) -> u16 {
         let data = self.data();
         u16::from_le_bytes(data[..2].try_into().unwrap())
@@ -34,11 +49,16 @@ pub trait ShroomPacket {
 
 impl ShroomPacket for COutPacket {
     const DATA_OFFSET: usize = 0;
+    const DIR: PacketDir = PacketDir::Send;
 
     fn raw_data(&self) -> &ZArray<u8> {
         &self.send_buf
     }
 
+    fn raw_data_mut(&mut self) -> &mut ZArray<u8> {
+        &mut self.send_buf
+    }
+
     fn len(&self) -> usize {
         self.offset as usize
     }
@@ -50,11 +70,16 @@ impl ShroomPacket for COutPacket {
 
 impl ShroomPacket for CInPacket {
     const DATA_OFFSET: usize = 4;
+    const DIR: PacketDir = PacketDir::Recv;
 
     fn raw_data(&self) -> &ZArray<u8> {
         &self.recv_buf
     }
 
+    fn raw_data_mut(&mut self) -> &mut ZArray<u8> {
+        &mut self.recv_buf
+    }
+
     fn len(&self) -> usize {
         self.recv_buf.len().checked_sub(Self::DATA_OFFSET).unwrap_or(0)
     }
@@ -64,38 +89,9 @@ impl ShroomPacket for CInPacket {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum PacketStructTy {
-    I8,
-    I16,
-    I32,
-    Buf(u32),
-    Str(u32),
-}
-
-impl From<c_uchar> for PacketStructTy {
-    fn from(_value: c_uchar) -> Self {
-        Self::I8
-    }
-}
-
-impl From<c_ushort> for PacketStructTy {
-    fn from(_value: c_ushort) -> Self {
-        Self::I16
-    }
-}
-
-impl From<c_uint> for PacketStructTy {
-    fn from(_value: c_uint) -> Self {
-        Self::I32
-    }
-}
-
-impl From<&[u8]> for PacketStructTy {
-    fn from(value: &[u8]) -> Self {
-        Self::Buf(value.len() as u32)
-    }
-}
+pub use shroom_trace::packet_struct::{
+    CompositeHelper, PacketStruct, PacketStructElem, PacketStructTy,
+};
 
 impl From<&ZXString8> for PacketStructTy {
     fn from(value: &ZXString8) -> Self {
@@ -103,87 +99,20 @@ impl From<&ZXString8> for PacketStructTy {
     }
 }
 
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct PacketStructElem {
-    ret_address: usize,
-    ty: PacketStructTy,
-    offset: usize,
-}
-
-impl PacketStructElem {
-    pub fn new<T: Into<PacketStructTy>>(offset: usize, ret_addr: usize, ty: T) -> Self {
-        Self {
-            ret_address: ret_addr,
-            ty: ty.into(),
-            offset,
-        }
-    }
-
-    pub fn byte_len(&self) -> usize {
-        match self.ty {
-            PacketStructTy::I8 => 1,
-            PacketStructTy::I16 => 2,
-            PacketStructTy::I32 => 4,
-            PacketStructTy::Buf(ln) | PacketStructTy::Str(ln) => ln as usize + 2,
-        }
-    }
-}
-
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct PacketStruct {
-    elements: Vec<PacketStructElem>,
-    send_ret_addr: Option<usize>,
-    exception_ret_addr: Option<usize>,
-    last_known_offset: usize,
-}
-
-impl PacketStruct {
-    pub fn new_send(send_ret_addr: usize) -> Self {
-        Self {
-            send_ret_addr: Some(send_ret_addr),
-            ..Default::default()
-        }
-    }
-
-    pub fn new_recv() -> Self {
-        Self {
-            ..Default::default()
-        }
-    }
-
-    fn handle_gap(&mut self, offset: usize) {
-        if offset > self.last_known_offset {
-            let gap = offset - self.last_known_offset;
-            self.elements.push(PacketStructElem::new(
-                self.last_known_offset,
-                usize::MAX,
-                PacketStructTy::Buf(gap as u32),
-            ));
-            self.last_known_offset = offset;
-        }
-    }
-
-    pub fn add_elem(&mut self, elem: PacketStructElem) {
-        self.handle_gap(elem.offset);
-        self.last_known_offset = elem.offset + elem.byte_len();
-        self.elements.push(elem);
-    }
-}
-
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct PacketStructLog<'a> {
-    strct: PacketStruct,
-    data: Option<&'a [u8]>,
-}
-
 #[derive(Debug)]
 pub struct PacketStructLogger<P> {
     data_ptr: *mut u8,
     data_size_hint: Option<usize>,
-    out_file: BufWriter<File>,
+    writer: TraceWriter,
+    stream: Option<Arc<StreamServer>>,
+    redactor: Redactor,
     cur: PacketStruct,
+    /// Packet, which is currently processed
+    in_flight: *const P,
+    /// Elements are ignored until the next packet, after an incomplete structure was written
+    discard: bool,
     with_data: bool,
+    composite_helpers: &'static [CompositeHelper],
     _p: PhantomData<P>,
 }
 
@@ -193,22 +122,45 @@ unsafe impl<P> Sync for PacketStructLogger<P> {}
 
 
 impl<P: ShroomPacket> PacketStructLogger<P> {
-    pub fn new(path: impl AsRef<Path>, with_data: bool) -> Self {
-        let out_file = BufWriter::new(File::create(path).unwrap());
+    pub fn new(
+        path: impl AsRef<Path>,
+        with_data: bool,
+        composite_helpers: &'static [CompositeHelper],
+        writer_cfg: &WriterCfg,
+    ) -> Self {
+        let writer = TraceWriter::create(path, writer_cfg).unwrap();
 
         Self {
             cur: Default::default(),
+            in_flight: null(),
+            discard: false,
             data_ptr: null_mut(),
             data_size_hint: None,
-            out_file,
+            writer,
+            stream: None,
+            redactor: Redactor::default(),
             with_data,
+            composite_helpers,
             _p: PhantomData,
         }
     }
 
+    /// Masks strings in the logged data, before It's written or streamed
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Publishes every record to the live stream
+    pub fn with_stream(mut self, stream: Option<Arc<StreamServer>>) -> Self {
+        self.stream = stream;
+        self
+    }
+
     pub fn clear(&mut self) {
         self.data_ptr = null_mut();
         self.data_size_hint = None;
+        self.in_flight = null();
         self.cur = Default::default();
     }
 
@@ -218,7 +170,19 @@ impl<P: ShroomPacket> PacketStructLogger<P> {
     }
 
     pub fn add_elem(&mut self, elem: PacketStructElem) {
-        self.cur.add_elem(elem);
+        if self.discard {
+            return;
+        }
+
+        let helper = self
+            .composite_helpers
+            .iter()
+            .find(|helper| helper.contains(elem.ret_address()));
+
+        match helper {
+            Some(helper) => self.cur.add_composite_elem(elem, helper),
+            None => self.cur.add_elem(elem),
+        }
     }
 
     fn finish_inner(&mut self) {
@@ -227,19 +191,49 @@ impl<P: ShroomPacket> PacketStructLogger<P> {
         self.clear();
     }
 
+    fn set_opcode(&mut self, p: &P) {
+        if p.len() >= 2 {
+            self.cur.set_opcode(p.opcode());
+        }
+    }
+
+    pub fn begin_process(&mut self, p: &P) {
+        self.set_packet_data(p);
+        self.in_flight = p;
+        self.discard = false;
+    }
+
     pub fn finish_process(&mut self, p: &P) {
+        if std::mem::take(&mut self.discard) {
+            self.clear();
+            return;
+        }
+
         self.set_packet_data(p);
+        self.set_opcode(p);
         self.finish_inner();
     }
 
-    pub fn finish_incomplete(&mut self, exception_ret_addr: usize) {
-        self.cur.exception_ret_addr = Some(exception_ret_addr);
+    /// Writes the in-flight structure with the offset of the failed element,
+    /// returns false If no packet is processed
+    pub fn finish_incomplete(&mut self, exception_ret_addr: usize) -> bool {
+        // Safety: the packet stays alive until `finish_process` is called
+        let Some(p) = (unsafe { self.in_flight.as_ref() }) else {
+            return false;
+        };
+
+        self.set_opcode(p);
+        self.cur.set_exception_ret_addr(exception_ret_addr);
+        self.cur.set_exception_offset(p.offset());
         self.finish_inner();
+        self.discard = true;
+        true
     }
 
     pub fn finish_send(&mut self, send_ret_addr: usize, p: &P) {
         self.set_packet_data(p);
-        self.cur.send_ret_addr = Some(send_ret_addr);
+        self.set_opcode(p);
+        self.cur.set_send_ret_addr(send_ret_addr);
         self.finish_inner();
     }
 
@@ -258,10 +252,37 @@ impl<P: ShroomPacket> PacketStructLogger<P> {
             None
         };
 
-        serde_json::to_writer(&mut self.out_file, &PacketStructLog { strct, data })?;
-        writeln!(&mut self.out_file, ",")?;
-        self.out_file.flush()?;
+        // Serialization and IO happen on the writer thread
+        let mut record = PacketTraceRecord {
+            strct,
+            data: data.map(<[u8]>::to_vec),
+            seq: raw_capture::next_seq(),
+        };
+        // Mutated packets would teach the fuzzer and the maps the malformed layouts
+        if !fuzz::is_mutated(P::DIR) {
+            handler_map::record(P::DIR, &record.strct);
+            coverage::record(P::DIR, &record.strct);
+            fuzz::learn(P::DIR, &record.strct);
+        }
+        self.redactor.redact(P::DIR, &mut record);
+        if let Some(stream) = self.stream.as_ref().filter(|s| s.client_count() > 0) {
+            stream.publish(StreamRecord::new(P::DIR, record.clone()));
+        }
+        if let Some(dropped) = self.writer.send(record) {
+            if dropped.is_power_of_two() {
+                log::warn!("Trace writer is behind, dropped {dropped} records");
+            }
+        }
 
         Ok(())
     }
+
+    /// Waits until the written records are in the trace file
+    pub fn flush(&self) {
+        self.writer.flush();
+    }
+
+    pub fn writer_stats(&self) -> WriterStats {
+        self.writer.stats()
+    }
 }
@@ -3,7 +3,7 @@ use std::ffi::CStr;
 use windows::core::PCSTR;
 use windows::Win32::Foundation::{HMODULE, MAX_PATH};
 use windows::Win32::System::Diagnostics::Debug::{
-    SymCleanup, SymInitialize, SymLoadModuleEx, SYM_LOAD_FLAGS,
+    SymCleanup, SymFromName, SymInitialize, SymLoadModuleEx, SYMBOL_INFO, SYM_LOAD_FLAGS,
 };
 use windows::Win32::System::Environment::GetCurrentDirectoryA;
 use windows::Win32::System::LibraryLoader::GetModuleHandleW;
@@ -295,6 +295,26 @@ impl StackWalker {
         }
     }
 
+    /// Resolves the symbol and the offset into It for an address, requires the symbols to be loaded
+    pub fn sym_from_addr(&self, addr: u64) -> Option<(String, u64)> {
+        let mut sym = ImgHlpSym64::default();
+        sym.base.Address = addr;
+        let mut sym_offset = 0;
+        unsafe { SymGetSymFromAddr64(self.proc, addr, Some(&mut sym_offset), &mut sym.base) }.ok()?;
+        let name = sym.name()?.to_string_lossy().into_owned();
+        Some((name, sym_offset))
+    }
+
+    /// Address of a symbol like `CInPacket::Decode8`, the name must be nul terminated
+    pub fn sym_from_name(&self, name: &CStr) -> Option<u64> {
+        let mut sym = SYMBOL_INFO {
+            SizeOfStruct: std::mem::size_of::<SYMBOL_INFO>() as u32,
+            ..Default::default()
+        };
+        unsafe { SymFromName(self.proc, PCSTR(name.as_ptr() as *const u8), &mut sym) }.ok()?;
+        Some(sym.Address)
+    }
+
     fn load_symbol(&mut self) -> windows::core::Result<()> {
         self.has_sym = false;
         let pc = self.last_frame.AddrReturn.Offset as u64;